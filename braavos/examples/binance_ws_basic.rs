@@ -1,34 +1,31 @@
-use braavos::binance::bn_models::WsMethod::Time;
-use braavos::binance::bn_ws_commands::WsRequest;
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Message;
+use braavos::binance::bn_commands::DEFAULT_CONTEXT;
+use braavos::binance::bn_ws_api::{WsApiClient, WsApiConfig, WsApiCredentials, WS_API_URL};
+use braavos::settings::BRAAVOS_SETTING;
 
 #[tokio::main]
 async fn main() {
-    let url = "wss://ws-api.binance.com:443/ws-api/v3";
-    // 创建一个 reqwest 客户端并设置代理
-    let ping_request = WsRequest::empty_new(Time);
-
-    let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
+    let account = &BRAAVOS_SETTING.accounts[0];
+    let credentials = WsApiCredentials::hmac(account);
+    let client = match WsApiClient::connect(WsApiConfig::new(WS_API_URL), credentials, DEFAULT_CONTEXT.clone()).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to connect: {}", e);
+            return;
+        }
+    };
     println!("WebSocket handshake has been successfully completed");
-    let (mut write, mut read) = ws_stream.split();
-    //
-    // let subscribe_message = r#"{ "method": "SUBSCRIBE", "params": ["btcusdt@aggTrade"], "id": 1 }"#;
 
-    let request_body = ping_request.to_json();
-    println!("request body is {}", request_body);
-    let msg = Message::Text(request_body);
-    if let Err(e) = write.send(msg).await {
-        eprintln!("Error while sending message: {}", e);
+    match client.server_time().await {
+        Ok(time) => println!("server time is {}", time),
+        Err(e) => eprintln!("Error: {}", e),
     }
-    //
-    while let Some(message) = read.next().await {
-        match message {
-            Ok(msg) => println!("Received: {}", msg),
-            Err(e) => eprintln!("Error: {}", e),
+
+    match client.account_status().await {
+        Ok(account) => {
+            for balance in account.balances.iter().filter(|b| !b.free.is_zero() || !b.locked.is_zero()) {
+                println!("{} free:{} locked:{}", balance.asset, balance.free, balance.locked);
+            }
         }
+        Err(e) => eprintln!("Error: {}", e),
     }
 }
-
-
@@ -1,32 +1,22 @@
-use braavos::binance::bn_models::WsMethod::SUBSCRIBE;
-use braavos::binance::bn_ws_commands::WsRequest;
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Message;
+use braavos::binance::bn_models::MarketEvent;
+use braavos::binance::bn_ws_stream::{MarketStreamClient, StreamConfig, StreamEvent, SPOT_STREAM_URL};
 
 #[tokio::main]
 async fn main() {
-    let url = "wss://stream.binance.com:9443/ws/bbb";
-    let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
-    println!("WebSocket handshake has been successfully completed");
-    let (mut write, mut read) = ws_stream.split();
-    //
-    // let subscribe_message = r#"{ "method": "SUBSCRIBE", "params": ["btcusdt@aggTrade"], "id": 1 }"#;
-    let params = Some(vec!["btcusdt@aggTrade".to_string(), "btcusdt@depth".to_string()]);
-    let ping_request = WsRequest::new(SUBSCRIBE, params);
-    let request_body = ping_request.to_json();
-    println!("request body is {}", request_body);
-    let msg = Message::Text(request_body);
-    if let Err(e) = write.send(msg).await {
-        eprintln!("Error while sending message: {}", e);
+    let (client, mut events) = MarketStreamClient::start(StreamConfig::new(SPOT_STREAM_URL));
+
+    if let Err(e) = client.subscribe(&["btcusdt@aggTrade", "btcusdt@depth"]).await {
+        eprintln!("Error while subscribing: {}", e);
+        return;
     }
-    //
-    while let Some(message) = read.next().await {
-        match message {
-            Ok(msg) => println!("Received: {}", msg),
-            Err(e) => eprintln!("Error: {}", e),
+    println!("subscriptions: {:?}", client.list_subscriptions().await);
+
+    while let Some(event) = events.recv().await {
+        match event {
+            StreamEvent::Data { event: MarketEvent::AggTrade(trade), .. } => println!("trade {} {}@{}", trade.symbol, trade.qty, trade.price),
+            StreamEvent::Data { event: MarketEvent::DepthUpdate(depth), .. } => println!("depth {} {}..{}", depth.symbol, depth.first_update_id, depth.final_update_id),
+            StreamEvent::Data { stream, event } => println!("Received {}: {:?}", stream, event),
+            other => println!("{:?}", other),
         }
     }
 }
-
-
@@ -1,4 +1,5 @@
 use crate::errors::BraavosError;
+use async_trait::async_trait;
 use crate::models::AccountSummary;
 use crate::settings::Account;
 
@@ -10,6 +11,9 @@ pub(crate) trait RawDataQuery<X> {
 }
 
 
-pub trait AccountReader {
-    fn account_balance(&self) -> Result<AccountSummary, BraavosError>;
+/** 读取账户的汇总数据。直接用调用方的runtime，多个账户可以并发的读
+ */
+#[async_trait]
+pub trait AccountReader: Send + Sync {
+    async fn account_balance(&self) -> Result<AccountSummary, BraavosError>;
 }
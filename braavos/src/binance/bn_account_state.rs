@@ -0,0 +1,443 @@
+use crate::accounts::RawDataQuery;
+use crate::binance::bn_commands::{fra_symbols, PMAccountReader, PMRawDataQuery};
+use crate::binance::bn_models::{AccountUpdateEvent, BinanceContext, MarkPriceEvent, MarketEvent, OutboundAccountPositionEvent, PMRawAccountData, PositionSide, UMSwapPosition, UserDataEvent};
+use crate::binance::bn_user_stream::UserStreamEvent;
+use crate::binance::bn_ws_stream::{MarketStreamClient, StreamEvent};
+use crate::errors::BraavosError;
+use crate::models::{AccountSummary, Decimal};
+use crate::settings::Account;
+use log::{debug, info, warn};
+use rust_decimal_macros::dec;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, Instant};
+
+/** U本位合约的保证金币种，未实现盈亏算在对应的资产上
+ */
+const UM_MARGIN_ASSETS: [&str; 2] = ["USDT", "USDC"];
+
+/** 本地状态和REST重新查出来的差别，正数说明本地算少了
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDrift {
+    pub account_equity: Decimal,
+    pub usdt_equity: Decimal,
+    pub negative_balance: Decimal,
+    pub um_swap_balance: Decimal,
+    pub cm_swap_balance: Decimal,
+}
+
+impl AccountDrift {
+    fn between(local: &AccountSummary, remote: &AccountSummary) -> AccountDrift {
+        AccountDrift {
+            account_equity: remote.account_equity - local.account_equity,
+            usdt_equity: remote.usdt_equity - local.usdt_equity,
+            negative_balance: remote.negative_balance - local.negative_balance,
+            um_swap_balance: remote.um_swap_summary.balance - local.um_swap_summary.balance,
+            cm_swap_balance: remote.cm_swap_summary.balance - local.cm_swap_summary.balance,
+        }
+    }
+
+    pub fn max_abs(&self) -> Decimal {
+        [self.account_equity, self.usdt_equity, self.negative_balance, self.um_swap_balance, self.cm_swap_balance]
+            .iter()
+            .map(|d| d.abs())
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/** 统一账户的本地状态。用PMRawDataQuery查一次作为初始值，之后：
+* 1. ACCOUNT_UPDATE更新合约钱包余额和持仓
+* 2. outboundAccountPosition更新杠杆账户余额
+* 3. 标记价格更新持仓的名义价值和未实现盈亏，套利币种的资金费率
+*
+* snapshot只是在本地算一遍，不发请求。推送可能会丢，所以定时用REST重新查一次，差别大的话打日志。
+* 新开的仓位要订阅标记价格，apply_user_event会返回还没订阅过的stream
+*/
+pub struct AccountState {
+    reader: PMAccountReader,
+    data: Mutex<PMRawAccountData>,
+    drift_tolerance: Decimal,
+    subscribed: Mutex<HashSet<String>>, //已经订阅的标记价格stream
+}
+
+impl AccountState {
+    pub async fn seed(account: Account, context: BinanceContext) -> Result<AccountState, BraavosError> {
+        let data = PMRawDataQuery::new(context.clone()).query_raw_data(&account).await?;
+        info!("{} account state seeded with {} positions", account.name, data.um_swap_position.len());
+        Ok(AccountState::from_raw_data(PMAccountReader::with_context(account, context), data))
+    }
+
+    fn from_raw_data(reader: PMAccountReader, data: PMRawAccountData) -> AccountState {
+        AccountState {
+            reader,
+            data: Mutex::new(data),
+            drift_tolerance: dec!(1),
+            subscribed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /** reconcile的时候差别超过这个值（USDT）打warn，默认是1
+     */
+    pub fn with_drift_tolerance(mut self, drift_tolerance: Decimal) -> AccountState {
+        self.drift_tolerance = drift_tolerance;
+        self
+    }
+
+    pub fn account(&self) -> &Account {
+        &self.reader.account
+    }
+
+    pub fn snapshot(&self) -> AccountSummary {
+        let data = self.data.lock().unwrap();
+        self.reader.summary_of(&data)
+    }
+
+    /** 需要订阅的标记价格，持仓和套利的币种，比如btcusdt@markPrice@1s。用标记价格估值的话，余额的币种也要订阅。
+     * 返回的stream记为已订阅，之后新开仓位的stream从apply_user_event拿
+     */
+    pub fn mark_price_streams(&self) -> Vec<String> {
+        let data = self.data.lock().unwrap();
+        let mut symbols: Vec<String> = data.um_swap_position.iter().map(|p| p.symbol.clone()).collect();
+        symbols.extend(fra_symbols(&self.reader.account));
+        let valuation = &self.reader.context.valuation;
+        if valuation.mark_price {
+            symbols.extend(data.account_balance.iter()
+                .flat_map(|b| valuation.pairs_for(&b.asset))
+                .filter(|s| data.premium_index.iter().any(|p| &p.symbol == s)));
+        }
+        symbols.sort();
+        symbols.dedup();
+        let streams: Vec<String> = symbols.iter().map(|s| mark_price_stream(s)).collect();
+        self.subscribed.lock().unwrap().extend(streams.iter().cloned());
+        streams
+    }
+
+    /** 更新本地状态，返回新开仓位还没订阅的标记价格stream，调用方负责订阅
+     */
+    pub fn apply_user_event(&self, event: &UserDataEvent) -> Vec<String> {
+        let opened: Vec<&str> = match event {
+            UserDataEvent::AccountUpdate(update) => {
+                self.apply_account_update(update);
+                if update.business_unit == "UM" {
+                    update.update.positions.iter().filter(|p| !p.position_amt.is_zero()).map(|p| p.symbol.as_str()).collect()
+                } else {
+                    vec![]
+                }
+            }
+            UserDataEvent::OutboundAccountPosition(position) => {
+                self.apply_margin_balance(position);
+                vec![]
+            }
+            // 杠杆账户的余额变化之后会再推一个outboundAccountPosition，这里不重复算
+            UserDataEvent::BalanceUpdate(update) => {
+                debug!("{} balance update {} {}", self.account().name, update.asset, update.delta);
+                vec![]
+            }
+            _ => vec![],
+        };
+        let mut subscribed = self.subscribed.lock().unwrap();
+        opened.into_iter().map(mark_price_stream).filter(|s| subscribed.insert(s.clone())).collect()
+    }
+
+    pub fn apply_market_event(&self, event: &MarketEvent) {
+        match event {
+            MarketEvent::MarkPrice(mark) => self.apply_mark_price(mark),
+            MarketEvent::Batch(events) => events.iter().for_each(|e| self.apply_market_event(e)),
+            _ => {}
+        }
+    }
+
+    fn apply_account_update(&self, event: &AccountUpdateEvent) {
+        let mut data = self.data.lock().unwrap();
+        let um = event.business_unit == "UM";
+        for balance in &event.update.balances {
+            match data.account_balance.iter_mut().find(|b| b.asset == balance.asset) {
+                Some(b) => {
+                    let wallet = if um { &mut b.um_wallet_balance } else { &mut b.cm_wallet_balance };
+                    let delta = balance.wallet_balance - *wallet;
+                    *wallet = balance.wallet_balance;
+                    b.total_wallet_balance += delta;
+                }
+                None => warn!("{} balance of {} not exists, wait for reconcile", self.account().name, balance.asset),
+            }
+        }
+
+        if !um {
+            debug!("{} skip {} cm positions", self.account().name, event.update.positions.len());
+            return;
+        }
+        for position in &event.update.positions {
+            let side = side_name(position.position_side);
+            let index = data.um_swap_position.iter().position(|p| p.symbol == position.symbol && p.position_side == side);
+            let old_pnl = match index {
+                Some(i) => {
+                    let p = &mut data.um_swap_position[i];
+                    let old_pnl = p.unrealized_profit;
+                    p.position_amt = position.position_amt;
+                    p.entry_price = position.entry_price;
+                    p.unrealized_profit = position.unrealized_pnl;
+                    p.notional = position.position_amt * p.mark_price;
+                    p.break_even_price = position.break_even_price.unwrap_or(p.break_even_price);
+                    p.update_time = event.transaction_time;
+                    old_pnl
+                }
+                None => {
+                    // 新开的仓位，标记价格先用开仓价，等标记价格推送过来再更新
+                    data.um_swap_position.push(UMSwapPosition {
+                        entry_price: position.entry_price,
+                        leverage: 0,
+                        mark_price: position.entry_price,
+                        max_notional_value: dec!(0),
+                        position_amt: position.position_amt,
+                        notional: position.position_amt * position.entry_price,
+                        symbol: position.symbol.clone(),
+                        unrealized_profit: position.unrealized_pnl,
+                        liquidation_price: dec!(0),
+                        position_side: String::from(side),
+                        update_time: event.transaction_time,
+                        break_even_price: position.break_even_price.unwrap_or(position.entry_price),
+                    });
+                    dec!(0)
+                }
+            };
+            add_um_pnl(&mut data, &position.symbol, position.unrealized_pnl - old_pnl);
+        }
+        data.um_swap_position.retain(|p| !p.position_amt.is_zero());
+    }
+
+    fn apply_margin_balance(&self, event: &OutboundAccountPositionEvent) {
+        let mut data = self.data.lock().unwrap();
+        for balance in &event.balances {
+            match data.account_balance.iter_mut().find(|b| b.asset == balance.asset) {
+                Some(b) => {
+                    let asset = balance.free + balance.locked;
+                    b.total_wallet_balance += asset - b.cross_margin_asset;
+                    b.cross_margin_free = balance.free;
+                    b.cross_margin_locked = balance.locked;
+                    b.cross_margin_asset = asset;
+                }
+                None => warn!("{} balance of {} not exists, wait for reconcile", self.account().name, balance.asset),
+            }
+        }
+    }
+
+    fn apply_mark_price(&self, event: &MarkPriceEvent) {
+        let mut data = self.data.lock().unwrap();
+        let mut pnl_delta = dec!(0);
+        for p in data.um_swap_position.iter_mut().filter(|p| p.symbol == event.symbol) {
+            let pnl = p.position_amt * (event.mark_price - p.entry_price);
+            pnl_delta += pnl - p.unrealized_profit;
+            p.mark_price = event.mark_price;
+            p.notional = p.position_amt * event.mark_price;
+            p.unrealized_profit = pnl;
+        }
+        add_um_pnl(&mut data, &event.symbol, pnl_delta);
+
+        if let Some(index) = data.premium_index.iter_mut().find(|p| p.symbol == event.symbol) {
+            index.mark_price = event.mark_price;
+            index.index_price = event.index_price;
+            index.last_funding_rate = event.funding_rate;
+            index.next_funding_time = event.next_funding_time;
+            index.time = event.event_time;
+        }
+    }
+
+    /** 用REST重新查一遍，替换掉本地的状态。查询期间收到的推送会被覆盖掉，下一次推送或者reconcile会再纠正
+     */
+    pub async fn reconcile(&self) -> Result<AccountDrift, BraavosError> {
+        let fresh = PMRawDataQuery::new(self.reader.context.clone()).query_raw_data(&self.reader.account).await?;
+        let remote = self.reader.summary_of(&fresh);
+        let local = {
+            let mut data = self.data.lock().unwrap();
+            let local = self.reader.summary_of(&data);
+            *data = fresh;
+            local
+        };
+
+        let drift = AccountDrift::between(&local, &remote);
+        if drift.max_abs() > self.drift_tolerance {
+            warn!("{} account state drift: {:?}", self.account().name, drift);
+        } else {
+            debug!("{} account state drift: {:?}", self.account().name, drift);
+        }
+        Ok(drift)
+    }
+
+    /** 一直处理推送，直到两个channel都关闭。用户数据流重连之后中间的推送可能丢了，马上reconcile一次。
+     * 传了market的话，新开仓位的标记价格在这里订阅
+     */
+    pub async fn run(self: Arc<Self>,
+                     mut user_events: mpsc::Receiver<UserStreamEvent>,
+                     mut market_events: mpsc::Receiver<StreamEvent>,
+                     market: Option<MarketStreamClient>,
+                     reconcile_interval: Duration) {
+        let mut reconcile = interval_at(Instant::now() + reconcile_interval, reconcile_interval);
+        let mut connected_before = false;
+        loop {
+            tokio::select! {
+                Some(event) = user_events.recv() => match event {
+                    UserStreamEvent::Data(event) => {
+                        let streams = self.apply_user_event(&event);
+                        if let (Some(market), false) = (&market, streams.is_empty()) {
+                            self.clone().subscribe(market.clone(), streams);
+                        }
+                    }
+                    UserStreamEvent::Connected => {
+                        if connected_before {
+                            self.try_reconcile().await;
+                        }
+                        connected_before = true;
+                    }
+                    UserStreamEvent::Disconnected(reason) => warn!("{} user stream disconnected: {}", self.account().name, reason),
+                },
+                Some(event) = market_events.recv() => {
+                    if let StreamEvent::Data { event, .. } = event {
+                        self.apply_market_event(&event);
+                    }
+                },
+                _ = reconcile.tick() => self.try_reconcile().await,
+                else => return,
+            }
+        }
+    }
+
+    /** 订阅要等响应，行情推送的channel满了会卡住，所以放到单独的task里面。失败的话下次开仓再订阅
+     */
+    fn subscribe(self: Arc<Self>, market: MarketStreamClient, streams: Vec<String>) {
+        tokio::spawn(async move {
+            let params: Vec<&str> = streams.iter().map(|s| s.as_str()).collect();
+            match market.subscribe(&params).await {
+                Ok(()) => info!("{} subscribed {:?}", self.account().name, streams),
+                Err(e) => {
+                    warn!("{} subscribe {:?} failed: {}", self.account().name, streams, e);
+                    let mut subscribed = self.subscribed.lock().unwrap();
+                    streams.iter().for_each(|s| { subscribed.remove(s); });
+                }
+            }
+        });
+    }
+
+    async fn try_reconcile(&self) {
+        if let Err(e) = self.reconcile().await {
+            warn!("{} reconcile failed: {}", self.account().name, e);
+        }
+    }
+}
+
+fn mark_price_stream(symbol: &str) -> String {
+    format!("{}@markPrice@1s", symbol.to_lowercase())
+}
+
+fn side_name(side: PositionSide) -> &'static str {
+    match side {
+        PositionSide::Both => "BOTH",
+        PositionSide::Long => "LONG",
+        PositionSide::Short => "SHORT",
+    }
+}
+
+fn add_um_pnl(data: &mut PMRawAccountData, symbol: &str, delta: Decimal) {
+    if delta.is_zero() {
+        return;
+    }
+    let asset = UM_MARGIN_ASSETS.iter().find(|a| symbol.ends_with(*a));
+    if let Some(b) = asset.and_then(|a| data.account_balance.iter_mut().find(|b| b.asset == *a)) {
+        b.um_unrealized_pnl += delta;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::binance::bn_models::{Endpoints, PMAccountInfo, PMBalance, Ticker};
+    use crate::settings::test_account;
+    use crate::utils::parse_test_json;
+
+    fn test_state() -> AccountState {
+        let data = PMRawAccountData {
+            account_info: parse_test_json::<PMAccountInfo>("tests/data/binance_papi_account.json"),
+            account_balance: parse_test_json::<Vec<PMBalance>>("tests/data/binance_papi_get_balance.json"),
+            spot_ticker: parse_test_json::<Vec<Ticker>>("tests/data/binance_spot_ticker.json"),
+            um_swap_position: parse_test_json::<Vec<UMSwapPosition>>("tests/data/binance_papi_um_position_risk.json"),
+            cm_swap_position: vec![],
+            premium_index: vec![],
+            funding_income: vec![],
+        };
+        let context = BinanceContext::new(reqwest::Client::new(), Endpoints::production());
+        AccountState::from_raw_data(PMAccountReader::with_context(test_account(), context), data)
+    }
+
+    fn user_event(json: &str) -> UserDataEvent {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_seed_snapshot_same_as_reader() {
+        let state = test_state();
+        let actual = state.snapshot();
+        assert_eq!(dec!(107.15440471), actual.usdt_equity);
+        assert_eq!(dec!(1016.5653078520000000), actual.account_equity);
+        assert_eq!(dec!(2990.06808746), actual.um_swap_summary.balance);
+        assert!(state.mark_price_streams().contains(&String::from("solusdt@markPrice@1s")));
+    }
+
+    #[test]
+    fn test_apply_mark_price() {
+        let state = test_state();
+        let before = state.snapshot();
+        // SOLUSDT空6个，开仓价185.0996666667，标记价格从154.461涨到160
+        state.apply_market_event(&MarketEvent::from_value(serde_json::json!(
+            {"e":"markPriceUpdate","E":1723960451595u64,"s":"SOLUSDT","p":"160","i":"160.1","P":"160","r":"0.0001","T":1723968000000u64}
+        )).unwrap());
+
+        let after = state.snapshot();
+        let sol = after.um_swap_summary.positions.iter().find(|p| p.symbol == "SOLUSDT").unwrap();
+        assert_eq!(dec!(160), sol.cur_price);
+        assert_eq!(dec!(-960), sol.pos_u);
+        assert_eq!(dec!(150.5980000002), sol.pnl_u);
+        assert_eq!(before.account_equity + dec!(150.5980000002) - dec!(183.832), after.account_equity, "未实现盈亏的变化算到权益里面");
+    }
+
+    #[test]
+    fn test_apply_user_events() {
+        let state = test_state();
+        let before = state.snapshot();
+        assert!(!state.mark_price_streams().contains(&String::from("btcusdt@markPrice@1s")));
+
+        let open_btc = r#"{"e":"ACCOUNT_UPDATE","fs":"UM","E":1723960451595,"T":1723960451590,"a":{"m":"ORDER","B":[{"a":"USDT","wb":"-500","cw":"0","bc":"0"}],"P":[{"s":"SOLUSDT","pa":"0","ep":"0","cr":"0","up":"0","ps":"BOTH","bep":"0"},{"s":"BTCUSDT","pa":"0.01","ep":"58000","cr":"0","up":"1","ps":"BOTH","bep":"58000"}]}}"#;
+        assert_eq!(vec![String::from("btcusdt@markPrice@1s")], state.apply_user_event(&user_event(open_btc)), "新开的仓位要订阅标记价格");
+        assert!(state.apply_user_event(&user_event(open_btc)).is_empty(), "订阅过的不再返回");
+        let after = state.snapshot();
+        let symbols: Vec<&str> = after.um_swap_summary.positions.iter().map(|p| p.symbol.as_str()).collect();
+        assert!(!symbols.contains(&"SOLUSDT"), "平掉的仓位删除");
+        assert!(symbols.contains(&"BTCUSDT"), "新开的仓位加上");
+        let wallet_delta = dec!(-500) - dec!(-513.5367502);
+        assert_eq!(before.account_equity + wallet_delta - dec!(183.832) + dec!(1), after.account_equity);
+
+        state.apply_user_event(&user_event(r#"{"e":"outboundAccountPosition","E":1723960451595,"u":1723960451590,"U":1,"B":[{"a":"USDT","f":"200","l":"0"}]}"#));
+        assert_eq!(dec!(200), state.snapshot().usdt_equity, "um钱包是负的，usdt权益就是杠杆账户的余额");
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::GET, "/papi/v1/balance", vec![MockResponse::fixture("tests/data/binance_papi_get_balance.json")])
+            .route(hyper::Method::GET, "/papi/v1/account", vec![MockResponse::fixture("tests/data/binance_papi_account.json")])
+            .route(hyper::Method::GET, "/papi/v1/um/positionRisk", vec![MockResponse::fixture("tests/data/binance_papi_um_position_risk.json")])
+            .route(hyper::Method::GET, "/papi/v1/cm/positionRisk", vec![MockResponse::fixture("tests/data/binance_papi_cm_position_risk.json")])
+            .route(hyper::Method::GET, "/api/v3/ticker/price", vec![MockResponse::fixture("tests/data/binance_spot_ticker.json")]);
+        let state = AccountState::seed(test_account(), server.context()).await.unwrap();
+        let seeded = state.snapshot().account_equity;
+
+        state.apply_user_event(&user_event(r#"{"e":"outboundAccountPosition","E":1723960451595,"u":1723960451590,"U":1,"B":[{"a":"USDT","f":"100","l":"0"}]}"#));
+        let drift = state.reconcile().await.unwrap();
+        assert_eq!(dec!(7.15440471), drift.account_equity, "REST查出来的比本地的多");
+        assert_eq!(seeded, state.snapshot().account_equity, "reconcile之后用REST的数据");
+    }
+}
@@ -0,0 +1,377 @@
+use crate::accounts::{AccountReader, RawDataQuery};
+use crate::binance::bn_commands::{cm_swap_balance, includes_asset, spot_price, um_swap_balance, BNCommand, GetCommand, DEFAULT_CONTEXT};
+use crate::binance::bn_models::{BinanceBase, BinanceContext, BinancePath, CMAccountInfo, CMRawAccountData, CMSwapPosition, CmAPI, NormalAPI, SpotAccountStatus, SpotRawAccountData, Ticker, TimeStampRequest, UMAccountInfo, UMRawAccountData, UMSwapPosition, UmAPI};
+use crate::errors::BraavosError;
+use crate::models::{AccountSummary, EmptyObject, SwapSummary};
+use crate::settings::Account;
+use async_trait::async_trait;
+use log::{error, trace};
+use rust_decimal_macros::dec;
+use tokio::join;
+
+/** 普通账户（不是统一账户）的原始数据，现货，U本位合约和币本位合约各是一个账户
+ */
+pub struct ClassicRawDataQuery {
+    context: BinanceContext,
+}
+
+impl ClassicRawDataQuery {
+    pub fn new(context: BinanceContext) -> ClassicRawDataQuery {
+        ClassicRawDataQuery { context }
+    }
+
+    async fn spot_ticker(&self) -> Result<Vec<Ticker>, BraavosError> {
+        let ticker_info = || self.context.command_info(BinanceBase::Normal, BinancePath::Normal(NormalAPI::SpotTickerAPI));
+        let ticker_command = GetCommand::<EmptyObject, Vec<Ticker>> { phantom: Default::default() };
+        ticker_command.execute_with_retry(|| (ticker_info(), None)).await
+    }
+}
+
+impl RawDataQuery<SpotRawAccountData> for ClassicRawDataQuery {
+    async fn query_raw_data(&self, account: &Account) -> Result<SpotRawAccountData, BraavosError> {
+        self.context.ensure_time_synced().await;
+
+        let account_info = || self.context.command_info_with_security(BinanceBase::Normal,
+                                                                      BinancePath::Normal(NormalAPI::AccountAPI),
+                                                                      &account.api_key,
+                                                                      &account.secret);
+        let account_command = GetCommand::<TimeStampRequest, SpotAccountStatus> { phantom: Default::default() };
+
+        let (account_res, ticker_res) = join!(
+            account_command.execute_with_retry(|| (account_info(), Some(self.context.timestamp_request(account)))),
+            self.spot_ticker()
+        );
+
+        Ok(SpotRawAccountData {
+            account: account_res?,
+            spot_ticker: ticker_res?,
+        })
+    }
+}
+
+impl RawDataQuery<UMRawAccountData> for ClassicRawDataQuery {
+    async fn query_raw_data(&self, account: &Account) -> Result<UMRawAccountData, BraavosError> {
+        self.context.ensure_time_synced().await;
+
+        let account_info = || self.context.command_info_with_security(BinanceBase::UsdMFutures,
+                                                                      BinancePath::FAPI(UmAPI::AccountAPI),
+                                                                      &account.api_key,
+                                                                      &account.secret);
+        let position_info = || self.context.command_info_with_security(BinanceBase::UsdMFutures,
+                                                                       BinancePath::FAPI(UmAPI::PositionRiskAPI),
+                                                                       &account.api_key,
+                                                                       &account.secret);
+        let account_command = GetCommand::<TimeStampRequest, UMAccountInfo> { phantom: Default::default() };
+        let position_command = GetCommand::<TimeStampRequest, Vec<UMSwapPosition>> { phantom: Default::default() };
+
+        let (account_res, position_res, ticker_res) = join!(
+            account_command.execute_with_retry(|| (account_info(), Some(self.context.timestamp_request(account)))),
+            position_command.execute_with_retry(|| (position_info(), Some(self.context.timestamp_request(account)))),
+            self.spot_ticker()
+        );
+
+        Ok(UMRawAccountData {
+            account: account_res?,
+            um_swap_position: position_res?,
+            spot_ticker: ticker_res?,
+        })
+    }
+}
+
+impl RawDataQuery<CMRawAccountData> for ClassicRawDataQuery {
+    async fn query_raw_data(&self, account: &Account) -> Result<CMRawAccountData, BraavosError> {
+        self.context.ensure_time_synced().await;
+
+        let account_info = || self.context.command_info_with_security(BinanceBase::CoinMFutures,
+                                                                      BinancePath::DAPI(CmAPI::AccountAPI),
+                                                                      &account.api_key,
+                                                                      &account.secret);
+        let position_info = || self.context.command_info_with_security(BinanceBase::CoinMFutures,
+                                                                       BinancePath::DAPI(CmAPI::PositionRiskAPI),
+                                                                       &account.api_key,
+                                                                       &account.secret);
+        let account_command = GetCommand::<TimeStampRequest, CMAccountInfo> { phantom: Default::default() };
+        let position_command = GetCommand::<TimeStampRequest, Vec<CMSwapPosition>> { phantom: Default::default() };
+
+        let (account_res, position_res, ticker_res) = join!(
+            account_command.execute_with_retry(|| (account_info(), Some(self.context.timestamp_request(account)))),
+            position_command.execute_with_retry(|| (position_info(), Some(self.context.timestamp_request(account)))),
+            self.spot_ticker()
+        );
+
+        Ok(CMRawAccountData {
+            account: account_res?,
+            cm_swap_position: position_res?,
+            spot_ticker: ticker_res?,
+        })
+    }
+}
+
+/** 现货账户，没有借币和合约，权益就是所有币按现货价格折算成USDT
+ */
+pub struct SpotAccountReader {
+    pub account: Account,
+    pub context: BinanceContext,
+}
+
+impl SpotAccountReader {
+    pub fn new(account: Account) -> SpotAccountReader {
+        SpotAccountReader::with_context(account, DEFAULT_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: BinanceContext) -> SpotAccountReader {
+        SpotAccountReader { account, context }
+    }
+
+    pub(crate) fn summary_of(&self, data: &SpotRawAccountData) -> AccountSummary {
+        let mut account_equity = dec!(0);
+        let mut usdt_equity = dec!(0);
+        let valuation = &self.context.valuation;
+        for b in &data.account.balances {
+            let amount = b.free + b.locked;
+            if amount == dec!(0) || !includes_asset(&self.account, valuation, &b.asset) {
+                continue;
+            }
+            if valuation.is_quote(&b.asset) {
+                usdt_equity = b.free;
+            }
+            match spot_price(&b.asset, &data.spot_ticker, valuation) {
+                Some(price) if !valuation.is_dust(amount * price) => account_equity += amount * price,
+                _ => trace!("skip {} {}", b.asset, amount),
+            }
+        }
+
+        AccountSummary {
+            usdt_equity,
+            negative_balance: dec!(0),
+            account_pnl: dec!(0),
+            account_equity,
+            um_swap_summary: SwapSummary::default(),
+            cm_swap_summary: SwapSummary::default(),
+            margin_summary: None,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountReader for SpotAccountReader {
+    async fn account_balance(&self) -> Result<AccountSummary, BraavosError> {
+        let query = ClassicRawDataQuery::new(self.context.clone());
+        match RawDataQuery::<SpotRawAccountData>::query_raw_data(&query, &self.account).await {
+            Ok(data) => Ok(self.summary_of(&data)),
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/** U本位合约账户，total开头的汇总已经是USDT了，持仓和统一账户的算法一样
+ */
+pub struct UMAccountReader {
+    pub account: Account,
+    pub context: BinanceContext,
+}
+
+impl UMAccountReader {
+    pub fn new(account: Account) -> UMAccountReader {
+        UMAccountReader::with_context(account, DEFAULT_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: BinanceContext) -> UMAccountReader {
+        UMAccountReader { account, context }
+    }
+
+    pub(crate) fn summary_of(&self, data: &UMRawAccountData) -> AccountSummary {
+        let info = &data.account;
+        let usdt_equity = info.assets.iter()
+            .find(|a| self.context.valuation.is_quote(&a.asset))
+            .map(|a| a.wallet_balance.max(dec!(0)))
+            .unwrap_or(dec!(0));
+        let negative_balance = info.assets.iter()
+            .filter(|a| a.wallet_balance < dec!(0))
+            .filter_map(|a| spot_price(&a.asset, &data.spot_ticker, &self.context.valuation).map(|p| -a.wallet_balance * p))
+            .sum();
+
+        AccountSummary {
+            usdt_equity,
+            negative_balance,
+            account_pnl: info.total_unrealized_profit,
+            account_equity: info.total_margin_balance,
+            um_swap_summary: um_swap_balance(&self.account, &data.um_swap_position),
+            cm_swap_summary: SwapSummary::default(),
+            margin_summary: None,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountReader for UMAccountReader {
+    async fn account_balance(&self) -> Result<AccountSummary, BraavosError> {
+        let query = ClassicRawDataQuery::new(self.context.clone());
+        match RawDataQuery::<UMRawAccountData>::query_raw_data(&query, &self.account).await {
+            Ok(data) => Ok(self.summary_of(&data)),
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/** 币本位合约账户，资产都是币，用现货价格折算成USDT
+ */
+pub struct CMAccountReader {
+    pub account: Account,
+    pub context: BinanceContext,
+}
+
+impl CMAccountReader {
+    pub fn new(account: Account) -> CMAccountReader {
+        CMAccountReader::with_context(account, DEFAULT_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: BinanceContext) -> CMAccountReader {
+        CMAccountReader { account, context }
+    }
+
+    pub(crate) fn summary_of(&self, data: &CMRawAccountData) -> AccountSummary {
+        let mut account_equity = dec!(0);
+        let mut account_pnl = dec!(0);
+        let mut negative_balance = dec!(0);
+        for a in &data.account.assets {
+            if let Some(price) = spot_price(&a.asset, &data.spot_ticker, &self.context.valuation) {
+                account_equity += a.margin_balance * price;
+                account_pnl += a.unrealized_profit * price;
+                if a.wallet_balance < dec!(0) {
+                    negative_balance += -a.wallet_balance * price;
+                }
+            }
+        }
+
+        AccountSummary {
+            usdt_equity: dec!(0),
+            negative_balance,
+            account_pnl,
+            account_equity,
+            um_swap_summary: SwapSummary::default(),
+            cm_swap_summary: cm_swap_balance(&data.cm_swap_position, &data.spot_ticker, &self.context.valuation),
+            margin_summary: None,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountReader for CMAccountReader {
+    async fn account_balance(&self) -> Result<AccountSummary, BraavosError> {
+        let query = ClassicRawDataQuery::new(self.context.clone());
+        match RawDataQuery::<CMRawAccountData>::query_raw_data(&query, &self.account).await {
+            Ok(data) => Ok(self.summary_of(&data)),
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::binance::bn_models::Endpoints;
+    use crate::utils::{parse_test_json, unix_time};
+
+    fn new_account(burning_free: bool) -> Account {
+        Account {
+            name: "".to_string(),
+            api_key: "classic-key".to_string(),
+            secret: "classic-secret".to_string(),
+            funding_rate_arbitrage: None,
+            burning_free,
+            recv_window: 5000,
+            funding_since: None,
+            exchange: Default::default(),
+            passphrase: None,
+            account_type: Default::default(),
+        }
+    }
+
+    fn spot_ticker() -> Vec<Ticker> {
+        parse_test_json::<Vec<Ticker>>("tests/data/binance_spot_ticker.json")
+    }
+
+    #[test]
+    fn test_spot_summary() {
+        let data = SpotRawAccountData {
+            account: parse_test_json::<SpotAccountStatus>("tests/data/binance_spot_account.json"),
+            spot_ticker: spot_ticker(),
+        };
+        let context = BinanceContext::new(reqwest::Client::new(), Endpoints::production());
+
+        let actual = SpotAccountReader::with_context(new_account(false), context.clone()).summary_of(&data);
+        assert_eq!(dec!(12423.717), actual.account_equity);
+        assert_eq!(dec!(1000), actual.usdt_equity);
+        assert_eq!(dec!(0), actual.negative_balance);
+        assert_eq!(dec!(0), actual.account_pnl);
+
+        let actual = SpotAccountReader::with_context(new_account(true), context).summary_of(&data);
+        assert_eq!(dec!(11345.917), actual.account_equity, "燃烧BNB的话BNB不算");
+    }
+
+    #[test]
+    fn test_um_summary() {
+        let data = UMRawAccountData {
+            account: parse_test_json::<UMAccountInfo>("tests/data/binance_fapi_account.json"),
+            um_swap_position: parse_test_json::<Vec<UMSwapPosition>>("tests/data/binance_fapi_position_risk.json"),
+            spot_ticker: spot_ticker(),
+        };
+        let context = BinanceContext::new(reqwest::Client::new(), Endpoints::production());
+
+        let actual = UMAccountReader::with_context(new_account(false), context).summary_of(&data);
+        assert_eq!(dec!(5127.87145935), actual.account_equity);
+        assert_eq!(dec!(281.76145935), actual.account_pnl);
+        assert_eq!(dec!(4900), actual.usdt_equity);
+        assert_eq!(dec!(53.89), actual.negative_balance);
+        assert_eq!(dec!(2990.06808746), actual.um_swap_summary.balance);
+        assert_eq!(8, actual.um_swap_summary.positions.len(), "没有持仓的交易对不算");
+    }
+
+    #[test]
+    fn test_cm_summary() {
+        let data = CMRawAccountData {
+            account: parse_test_json::<CMAccountInfo>("tests/data/binance_dapi_account.json"),
+            cm_swap_position: parse_test_json::<Vec<CMSwapPosition>>("tests/data/binance_dapi_position_risk.json"),
+            spot_ticker: spot_ticker(),
+        };
+        let context = BinanceContext::new(reqwest::Client::new(), Endpoints::production());
+
+        let actual = CMAccountReader::with_context(new_account(false), context).summary_of(&data);
+        assert_eq!(dec!(35018.99398), actual.account_equity);
+        assert_eq!(dec!(33.44398), actual.account_pnl);
+        assert_eq!(dec!(0), actual.usdt_equity);
+        assert_eq!(dec!(0), actual.negative_balance);
+        assert_eq!(2, actual.cm_swap_summary.positions.len(), "没有持仓的合约不算");
+    }
+
+    #[tokio::test]
+    async fn test_um_account_reader_with_mock() {
+        let server = MockServer::start();
+        let server_time = format!("{{\"serverTime\":{}}}", unix_time() + 60_000);
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, &server_time)])
+            .route(hyper::Method::GET, "/fapi/v2/account", vec![MockResponse::fixture("tests/data/binance_fapi_account.json")])
+            .route(hyper::Method::GET, "/fapi/v2/positionRisk", vec![MockResponse::fixture("tests/data/binance_fapi_position_risk.json")])
+            .route(hyper::Method::GET, "/api/v3/ticker/price", vec![MockResponse::fixture("tests/data/binance_spot_ticker.json")]);
+        let account = new_account(false);
+        let reader = UMAccountReader::with_context(account.clone(), server.context());
+
+        let actual = reader.account_balance().await.unwrap();
+        assert_eq!(dec!(5127.87145935), actual.account_equity);
+        assert_eq!(8, actual.um_swap_summary.positions.len());
+
+        let account_request = server.requests().into_iter().find(|r| r.path == "/fapi/v2/account").unwrap();
+        assert!(account_request.query.unwrap().contains("&signature="), "fapi的账户请求需要签名");
+        assert_eq!(Some(account.api_key), account_request.api_key);
+    }
+}
@@ -0,0 +1,401 @@
+use crate::binance::bn_commands::{BNCommand, GetCommand, DEFAULT_CONTEXT};
+use crate::binance::bn_models::{BinanceBase, BinanceContext, BinancePath, CMOrderResponse, CancelOrderRequest, CmAPI, MarginCancelResponse, MarginOrder, MarginOrderRequest, MarginOrderResponse, NormalAPI, OrderSide, OrderStatus, OrderType, PMOrderRequest, PremiumIndex, PriceLevel, Ticker, TimeInForce, UMOrderResponse, UmAPI};
+use crate::binance::bn_order_book::{fetch_depth_snapshot, BookMarket};
+use crate::binance::bn_order_commands::{new_client_order_id, PMOrderExecutor, PMOrderReader};
+use crate::errors::BraavosError;
+use crate::market::MarketDataReader;
+use crate::models::{BookLevel, BookSnapshot, Decimal, EmptyObject, Instrument, InstrumentKind, OrderInfo, OrderKind, OrderRequest, OrderState, Side};
+use crate::trading::OrderExecutor;
+use async_trait::async_trait;
+use rust_decimal_macros::dec;
+use std::collections::HashMap;
+use tokio::try_join;
+
+/** 币安的公开行情。现货和杠杆用现货的接口，U本位和币本位用各自合约的接口
+ */
+pub struct BinanceMarketData {
+    pub context: BinanceContext,
+}
+
+impl BinanceMarketData {
+    pub fn new() -> BinanceMarketData {
+        BinanceMarketData::with_context(DEFAULT_CONTEXT.clone())
+    }
+
+    pub fn with_context(context: BinanceContext) -> BinanceMarketData {
+        BinanceMarketData { context }
+    }
+
+    /** 不传symbol的话永续和交割合约都会返回，交割合约的lastFundingRate和interestRate是空字符串。
+     * 币本位的symbol是BTCUSD_PERP，BTCUSD_241227这种，还多了pair
+     */
+    async fn mark_prices(&self, coin_m: bool) -> Result<HashMap<String, Decimal>, BraavosError> {
+        let info = || match coin_m {
+            true => self.context.command_info(BinanceBase::CoinMFutures, BinancePath::DAPI(CmAPI::PremiumIndexAPI)),
+            false => self.context.command_info(BinanceBase::UsdMFutures, BinancePath::FAPI(UmAPI::PremiumIndexAPI)),
+        };
+        let get = GetCommand::<EmptyObject, Vec<PremiumIndex>> { phantom: Default::default() };
+        let premium = get.execute_with_retry(|| (info(), None)).await?;
+        Ok(premium.into_iter().map(|p| (p.symbol, p.mark_price)).collect())
+    }
+}
+
+impl Default for BinanceMarketData {
+    fn default() -> Self {
+        BinanceMarketData::new()
+    }
+}
+
+#[async_trait]
+impl MarketDataReader for BinanceMarketData {
+    async fn ping(&self) -> Result<(), BraavosError> {
+        let get = GetCommand::<EmptyObject, EmptyObject> { phantom: Default::default() };
+        get.execute_with_retry(|| (self.context.command_info(BinanceBase::Normal, BinancePath::Normal(NormalAPI::PingAPI)), None)).await?;
+        Ok(())
+    }
+
+    async fn order_book(&self, instrument: &Instrument, depth: u16) -> Result<BookSnapshot, BraavosError> {
+        let market = match instrument.kind {
+            InstrumentKind::Spot | InstrumentKind::Margin => BookMarket::Spot,
+            InstrumentKind::LinearSwap => BookMarket::UsdM,
+            InstrumentKind::InverseSwap => {
+                return Err(BraavosError::Unsupported(format!("binance coin-m order book {}", instrument.symbol)));
+            }
+        };
+        let snapshot = fetch_depth_snapshot(&self.context, market, &instrument.symbol, depth).await?;
+        Ok(BookSnapshot {
+            instrument: instrument.clone(),
+            bids: snapshot.bids.into_iter().map(BookLevel::from).collect(),
+            asks: snapshot.asks.into_iter().map(BookLevel::from).collect(),
+        })
+    }
+
+    async fn prices(&self, kind: InstrumentKind) -> Result<HashMap<String, Decimal>, BraavosError> {
+        match kind {
+            InstrumentKind::Spot | InstrumentKind::Margin => {
+                let get = GetCommand::<EmptyObject, Vec<Ticker>> { phantom: Default::default() };
+                let tickers = get.execute_with_retry(|| (self.context.command_info(BinanceBase::Normal, BinancePath::Normal(NormalAPI::SpotTickerAPI)), None)).await?;
+                Ok(tickers.into_iter().map(|t| (t.symbol, t.price)).collect())
+            }
+            InstrumentKind::LinearSwap => self.mark_prices(false).await,
+            InstrumentKind::InverseSwap => self.mark_prices(true).await,
+        }
+    }
+}
+
+/** 统一账户下单：U本位永续走UM，币本位永续走CM，现货和杠杆都走杠杆账户
+ */
+#[async_trait]
+impl OrderExecutor for PMOrderExecutor {
+    async fn place_order(&self, request: &OrderRequest) -> Result<OrderInfo, BraavosError> {
+        let client_order_id = request.client_order_id.clone().unwrap_or_else(new_client_order_id);
+        match request.instrument.kind {
+            InstrumentKind::LinearSwap => {
+                let order = pm_order_request(request, client_order_id)?;
+                Ok(OrderInfo::from(self.place_um_order(&order).await?))
+            }
+            InstrumentKind::InverseSwap => {
+                let order = pm_order_request(request, client_order_id)?;
+                Ok(OrderInfo::from(self.place_cm_order(&order).await?))
+            }
+            InstrumentKind::Spot | InstrumentKind::Margin => {
+                let order = margin_order_request(request, client_order_id)?;
+                Ok(margin_order_info(self.place_margin_order(&order).await?, &order))
+            }
+        }
+    }
+
+    async fn cancel_order(&self, instrument: &Instrument, order_id: &str) -> Result<OrderInfo, BraavosError> {
+        let order_id = order_id.parse::<u64>()
+            .map_err(|_| BraavosError::Custom(format!("invalid binance order id: {}", order_id)))?;
+        let request = CancelOrderRequest { symbol: instrument.symbol.clone(), order_id: Some(order_id), orig_client_order_id: None };
+        match instrument.kind {
+            InstrumentKind::LinearSwap => Ok(OrderInfo::from(self.cancel_um_order(&request).await?)),
+            InstrumentKind::InverseSwap => Ok(OrderInfo::from(self.cancel_cm_order(&request).await?)),
+            InstrumentKind::Spot | InstrumentKind::Margin => Ok(OrderInfo::from(self.cancel_margin_order(&request).await?)),
+        }
+    }
+
+    async fn open_orders(&self, instrument: Option<&Instrument>) -> Result<Vec<OrderInfo>, BraavosError> {
+        let reader = PMOrderReader::with_context(self.account.clone(), self.context.clone());
+        let symbol = instrument.map(|i| i.symbol.as_str());
+        match instrument.map(|i| i.kind) {
+            Some(InstrumentKind::LinearSwap) => Ok(to_order_infos(reader.um_open_orders(symbol).await?)),
+            Some(InstrumentKind::InverseSwap) => Ok(to_order_infos(reader.cm_open_orders(symbol).await?)),
+            Some(InstrumentKind::Spot) | Some(InstrumentKind::Margin) => Ok(to_order_infos(reader.margin_open_orders(symbol).await?)),
+            None => {
+                let (um, cm, margin) = try_join!(reader.um_open_orders(None), reader.cm_open_orders(None), reader.margin_open_orders(None))?;
+                let mut res = to_order_infos(um);
+                res.extend(to_order_infos(cm));
+                res.extend(to_order_infos(margin));
+                Ok(res)
+            }
+        }
+    }
+}
+
+fn to_order_infos<T: Into<OrderInfo>>(orders: Vec<T>) -> Vec<OrderInfo> {
+    orders.into_iter().map(Into::into).collect()
+}
+
+fn limit_price(request: &OrderRequest) -> Result<Decimal, BraavosError> {
+    request.price.ok_or_else(|| BraavosError::Custom(format!("limit order of {} without price", request.instrument.symbol)))
+}
+
+fn pm_order_request(request: &OrderRequest, client_order_id: String) -> Result<PMOrderRequest, BraavosError> {
+    let symbol = &request.instrument.symbol;
+    let side = OrderSide::from(request.side);
+    let time_in_force = match request.kind {
+        OrderKind::Market => None,
+        OrderKind::Limit => Some(TimeInForce::GTC),
+        OrderKind::PostOnly => Some(TimeInForce::GTX),
+        OrderKind::Ioc => Some(TimeInForce::IOC),
+        OrderKind::Fok => Some(TimeInForce::FOK),
+    };
+    let mut order = match time_in_force {
+        None => PMOrderRequest::market(symbol, side, request.quantity),
+        Some(tif) => PMOrderRequest::limit(symbol, side, request.quantity, limit_price(request)?, tif),
+    };
+    if request.reduce_only {
+        order = order.reduce_only();
+    }
+    order.new_client_order_id = client_order_id;
+    Ok(order)
+}
+
+/** 杠杆没有GTX，只做maker用LIMIT_MAKER，不带timeInForce。reduce_only在杠杆里面没有意义，忽略
+ */
+fn margin_order_request(request: &OrderRequest, client_order_id: String) -> Result<MarginOrderRequest, BraavosError> {
+    let symbol = &request.instrument.symbol;
+    let side = OrderSide::from(request.side);
+    let mut order = match request.kind {
+        OrderKind::Market => MarginOrderRequest::market(symbol, side, request.quantity),
+        OrderKind::Limit => MarginOrderRequest::limit(symbol, side, request.quantity, limit_price(request)?, TimeInForce::GTC),
+        OrderKind::Ioc => MarginOrderRequest::limit(symbol, side, request.quantity, limit_price(request)?, TimeInForce::IOC),
+        OrderKind::Fok => MarginOrderRequest::limit(symbol, side, request.quantity, limit_price(request)?, TimeInForce::FOK),
+        OrderKind::PostOnly => {
+            let mut order = MarginOrderRequest::limit(symbol, side, request.quantity, limit_price(request)?, TimeInForce::GTC);
+            order.order_type = OrderType::LimitMaker;
+            order.time_in_force = None;
+            order
+        }
+    };
+    order.new_client_order_id = client_order_id;
+    Ok(order)
+}
+
+/** 杠杆下单的返回有些字段可能没有，用请求里面的补上
+ */
+fn margin_order_info(response: MarginOrderResponse, request: &MarginOrderRequest) -> OrderInfo {
+    let filled_qty = response.executed_qty.unwrap_or_default();
+    let avg_price = average_price(response.cummulative_quote_qty.unwrap_or_default(), filled_qty);
+    OrderInfo {
+        instrument: Instrument::new(InstrumentKind::Margin, &response.symbol),
+        order_id: response.order_id.to_string(),
+        client_order_id: response.client_order_id,
+        side: Side::from(response.side.unwrap_or(request.side)),
+        price: response.price.or(request.price).unwrap_or_default(),
+        quantity: response.orig_qty.or(request.quantity).unwrap_or_default(),
+        filled_qty,
+        avg_price,
+        state: response.status.map(OrderState::from).unwrap_or(OrderState::Unknown),
+        update_time: Some(response.transact_time),
+    }
+}
+
+/** 杠杆的订单没有成交均价，用成交金额除以成交数量
+ */
+fn average_price(quote_qty: Decimal, filled_qty: Decimal) -> Decimal {
+    if filled_qty.is_zero() { dec!(0) } else { quote_qty / filled_qty }
+}
+
+impl From<Side> for OrderSide {
+    fn from(side: Side) -> Self {
+        match side {
+            Side::Buy => OrderSide::Buy,
+            Side::Sell => OrderSide::Sell,
+        }
+    }
+}
+
+impl From<OrderSide> for Side {
+    fn from(side: OrderSide) -> Self {
+        match side {
+            OrderSide::Buy => Side::Buy,
+            OrderSide::Sell => Side::Sell,
+        }
+    }
+}
+
+impl From<OrderStatus> for OrderState {
+    fn from(status: OrderStatus) -> Self {
+        match status {
+            OrderStatus::New | OrderStatus::PendingCancel => OrderState::Open,
+            OrderStatus::PartiallyFilled => OrderState::PartiallyFilled,
+            OrderStatus::Filled => OrderState::Filled,
+            OrderStatus::Canceled => OrderState::Canceled,
+            OrderStatus::Rejected => OrderState::Rejected,
+            OrderStatus::Expired | OrderStatus::ExpiredInMatch => OrderState::Expired,
+            OrderStatus::Unknown => OrderState::Unknown,
+        }
+    }
+}
+
+impl From<PriceLevel> for BookLevel {
+    fn from(level: PriceLevel) -> Self {
+        BookLevel { price: level.price, qty: level.qty }
+    }
+}
+
+impl From<UMOrderResponse> for OrderInfo {
+    fn from(order: UMOrderResponse) -> Self {
+        OrderInfo {
+            instrument: Instrument::new(InstrumentKind::LinearSwap, &order.symbol),
+            order_id: order.order_id.to_string(),
+            client_order_id: order.client_order_id,
+            side: Side::from(order.side),
+            price: order.price,
+            quantity: order.orig_qty,
+            filled_qty: order.executed_qty,
+            avg_price: order.avg_price,
+            state: OrderState::from(order.status),
+            update_time: Some(order.update_time),
+        }
+    }
+}
+
+impl From<CMOrderResponse> for OrderInfo {
+    fn from(order: CMOrderResponse) -> Self {
+        OrderInfo {
+            instrument: Instrument::new(InstrumentKind::InverseSwap, &order.symbol),
+            order_id: order.order_id.to_string(),
+            client_order_id: order.client_order_id,
+            side: Side::from(order.side),
+            price: order.price,
+            quantity: order.orig_qty,
+            filled_qty: order.executed_qty,
+            avg_price: order.avg_price,
+            state: OrderState::from(order.status),
+            update_time: Some(order.update_time),
+        }
+    }
+}
+
+impl From<MarginOrder> for OrderInfo {
+    fn from(order: MarginOrder) -> Self {
+        OrderInfo {
+            instrument: Instrument::new(InstrumentKind::Margin, &order.symbol),
+            order_id: order.order_id.to_string(),
+            client_order_id: order.client_order_id,
+            side: Side::from(order.side),
+            price: order.price,
+            quantity: order.orig_qty,
+            filled_qty: order.executed_qty,
+            avg_price: average_price(order.cummulative_quote_qty, order.executed_qty),
+            state: OrderState::from(order.status),
+            update_time: Some(order.update_time),
+        }
+    }
+}
+
+impl From<MarginCancelResponse> for OrderInfo {
+    fn from(order: MarginCancelResponse) -> Self {
+        OrderInfo {
+            instrument: Instrument::new(InstrumentKind::Margin, &order.symbol),
+            order_id: order.order_id.to_string(),
+            client_order_id: order.orig_client_order_id,
+            side: Side::from(order.side),
+            price: order.price,
+            quantity: order.orig_qty,
+            filled_qty: order.executed_qty,
+            avg_price: average_price(order.cummulative_quote_qty, order.executed_qty),
+            state: OrderState::from(order.status),
+            update_time: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::settings::test_account;
+    use crate::utils::parse_test_json;
+
+    #[test]
+    fn test_order_info_from_binance() {
+        let um = OrderInfo::from(parse_test_json::<UMOrderResponse>("tests/data/binance_papi_um_order.json"));
+        assert_eq!(Instrument::new(InstrumentKind::LinearSwap, "BTCUSDT"), um.instrument);
+        assert_eq!("22542179", um.order_id);
+        assert_eq!(Side::Buy, um.side);
+        assert_eq!(OrderState::Open, um.state);
+        assert_eq!(dec!(0.01), um.quantity);
+
+        let request = margin_order_request(&OrderRequest::market(Instrument::new(InstrumentKind::Spot, "SOLUSDT"), Side::Sell, dec!(5)), "abc".to_string()).unwrap();
+        let margin = margin_order_info(parse_test_json::<MarginOrderResponse>("tests/data/binance_papi_margin_order.json"), &request);
+        assert_eq!(InstrumentKind::Margin, margin.instrument.kind);
+        assert_eq!(OrderState::Filled, margin.state);
+        assert_eq!(dec!(142.5), margin.avg_price, "成交金额除以成交数量");
+    }
+
+    #[test]
+    fn test_order_request_to_binance() {
+        let instrument = Instrument::new(InstrumentKind::LinearSwap, "BTCUSDT");
+        let post_only = OrderRequest::limit(instrument.clone(), Side::Buy, dec!(0.01), dec!(58000)).with_kind(OrderKind::PostOnly).reduce_only();
+        let actual = pm_order_request(&post_only, "abc".to_string()).unwrap();
+        assert_eq!("symbol=BTCUSDT&side=BUY&type=LIMIT&timeInForce=GTX&quantity=0.01&price=58000&reduceOnly=true&newClientOrderId=abc", actual.to_string());
+
+        let margin = margin_order_request(&post_only, "abc".to_string()).unwrap();
+        assert_eq!(OrderType::LimitMaker, margin.order_type);
+        assert_eq!(None, margin.time_in_force);
+
+        let mut no_price = OrderRequest::limit(instrument, Side::Sell, dec!(1), dec!(1));
+        no_price.price = None;
+        assert!(pm_order_request(&no_price, "abc".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_executor_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::POST, "/papi/v1/um/order", vec![MockResponse::fixture("tests/data/binance_papi_um_order.json")])
+            .route(hyper::Method::DELETE, "/papi/v1/um/order", vec![MockResponse::fixture("tests/data/binance_papi_um_order.json")]);
+        let executor: Box<dyn OrderExecutor> = Box::new(PMOrderExecutor::with_context(test_account(), server.context()));
+
+        let instrument = Instrument::new(InstrumentKind::LinearSwap, "BTCUSDT");
+        let mut request = OrderRequest::limit(instrument.clone(), Side::Buy, dec!(0.01), dec!(58000));
+        request.client_order_id = Some("4372859113592143872".to_string());
+        let placed = executor.place_order(&request).await.unwrap();
+        assert_eq!("4372859113592143872", placed.client_order_id);
+
+        executor.cancel_order(&instrument, &placed.order_id).await.unwrap();
+        assert!(executor.cancel_order(&instrument, "abc").await.is_err(), "币安的orderId是数字");
+
+        let cancel = server.requests().into_iter().find(|r| r.method == hyper::Method::DELETE).unwrap();
+        assert!(cancel.body.starts_with("symbol=BTCUSDT&orderId=22542179&timestamp="), "{}", cancel.body);
+        assert!(cancel.body.contains("&signature="));
+    }
+
+    #[tokio::test]
+    async fn test_market_data_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/fapi/v1/premiumIndex", vec![MockResponse::fixture("tests/data/binance_fapi_premium_index.json")])
+            .route(hyper::Method::GET, "/dapi/v1/premiumIndex", vec![MockResponse::fixture("tests/data/binance_dapi_premium_index.json")])
+            .route(hyper::Method::GET, "/api/v3/ticker/price", vec![MockResponse::fixture("tests/data/binance_spot_ticker.json")]);
+        let market: Box<dyn MarketDataReader> = Box::new(BinanceMarketData::with_context(server.context()));
+
+        let swap = market.prices(InstrumentKind::LinearSwap).await.unwrap();
+        assert_eq!(Some(&dec!(145.27)), swap.get("SOLUSDT"), "永续用标记价格");
+        assert_eq!(Some(&dec!(59703.3)), swap.get("BTCUSDT_240927"), "交割合约的资金费率是空字符串");
+
+        let inverse = market.prices(InstrumentKind::InverseSwap).await.unwrap();
+        assert_eq!(Some(&dec!(58915.9)), inverse.get("BTCUSD_PERP"));
+        assert_eq!(Some(&dec!(59688.7)), inverse.get("BTCUSD_240927"));
+
+        let spot = market.prices(InstrumentKind::Spot).await.unwrap();
+        assert_eq!(Some(&dec!(0.04382)), spot.get("ETHBTC"));
+
+        let book = market.order_book(&Instrument::new(InstrumentKind::InverseSwap, "BTCUSD_PERP"), 100).await;
+        assert!(matches!(book, Err(BraavosError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::binance::bn_models::{BinanceContext, Endpoints};
+use hyper::body::to_bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use std::convert::Infallible;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/** 本地的交易所替身，按照method和path返回配置好的内容，一般是tests/data下面的json。
+* 同一个接口可以配置多个返回，按顺序返回，最后一个会一直重复，方便测试重试之类的场景
+*/
+#[derive(Debug, Clone)]
+pub(crate) struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockResponse {
+    pub fn fixture(path: &str) -> MockResponse {
+        MockResponse::json(200, &fs::read_to_string(path).unwrap())
+    }
+
+    pub fn json(status: u16, body: &str) -> MockResponse {
+        MockResponse {
+            status,
+            headers: vec![],
+            body: String::from(body),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+}
+
+/** 收到的请求，用来检查签名和参数
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub body: String,
+    pub api_key: Option<String>,
+    pub headers: hyper::HeaderMap,
+}
+
+struct MockRoute {
+    method: Method,
+    path: String,
+    responses: Vec<MockResponse>,
+    hits: usize,
+}
+
+pub(crate) struct MockServer {
+    addr: SocketAddr,
+    routes: Arc<Mutex<Vec<MockRoute>>>,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /** 需要在tokio的runtime里面调用，端口是随机分配的
+     */
+    pub fn start() -> MockServer {
+        let routes: Arc<Mutex<Vec<MockRoute>>> = Arc::new(Mutex::new(vec![]));
+        let requests: Arc<Mutex<Vec<RecordedRequest>>> = Arc::new(Mutex::new(vec![]));
+
+        let service_routes = routes.clone();
+        let service_requests = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let routes = service_routes.clone();
+            let requests = service_requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(req, routes.clone(), requests.clone())
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+
+        MockServer {
+            addr,
+            routes,
+            requests,
+            shutdown: Some(tx),
+        }
+    }
+
+    pub fn route(&self, method: Method, path: &str, responses: Vec<MockResponse>) -> &MockServer {
+        self.routes.lock().unwrap().push(MockRoute {
+            method,
+            path: String::from(path),
+            responses,
+            hits: 0,
+        });
+        self
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /** 所有的BinanceBase都指向这个server
+     */
+    pub fn context(&self) -> BinanceContext {
+        BinanceContext::new(reqwest::Client::new(), Endpoints::with_base(&self.base_url()))
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+async fn handle(req: Request<Body>,
+                routes: Arc<Mutex<Vec<MockRoute>>>,
+                requests: Arc<Mutex<Vec<RecordedRequest>>>) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = String::from(req.uri().path());
+    let query = req.uri().query().map(String::from);
+    let headers = req.headers().clone();
+    let api_key = req.headers()
+        .get("X-MBX-APIKEY")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let body = to_bytes(req.into_body()).await.unwrap_or_default();
+    let body = String::from_utf8_lossy(&body).to_string();
+
+    requests.lock().unwrap().push(RecordedRequest {
+        method: method.clone(),
+        path: path.clone(),
+        query,
+        body,
+        api_key,
+        headers,
+    });
+
+    let mock_response = {
+        let mut routes = routes.lock().unwrap();
+        routes.iter_mut()
+            .find(|r| r.method == method && r.path == path)
+            .map(|route| {
+                let idx = route.hits.min(route.responses.len() - 1);
+                route.hits += 1;
+                route.responses[idx].clone()
+            })
+    };
+
+    let mock_response = mock_response.unwrap_or_else(|| {
+        MockResponse::json(404, &format!("{{\"code\":-5,\"msg\":\"mock route not found:{} {}\"}}", method, path))
+    });
+
+    let mut builder = Response::builder().status(mock_response.status);
+    for (name, value) in &mock_response.headers {
+        builder = builder.header(name, value);
+    }
+    Ok(builder.body(Body::from(mock_response.body)).unwrap())
+}
@@ -1,66 +1,327 @@
+use crate::binance::bn_commands::FundingCache;
+use crate::binance::bn_rate_limit::{OrderLimit, RateLimiter, RequestLimit};
+use crate::binance::bn_symbol_rules::SymbolRegistry;
+use crate::valuation::ValuationPolicy;
+use crate::binance::bn_time::ServerClock;
 use crate::models::{Decimal, UnixTimeStamp};
+use crate::retry::RetryPolicy;
 use crate::utils;
 use crate::utils::unix_time;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum BinanceBase {
     Normal,
     PortfolioMargin,
+    UsdMFutures,
+    CoinMFutures,
 }
 
 
 impl From<BinanceBase> for String {
     fn from(url: BinanceBase) -> Self {
-        String::from(
-            match url {
-                BinanceBase::Normal => String::from("https://api.binance.com/"),
-                BinanceBase::PortfolioMargin => String::from("https://papi.binance.com/")
-            }
-        )
+        match url {
+            BinanceBase::Normal => String::from("https://api.binance.com/"),
+            BinanceBase::PortfolioMargin => String::from("https://papi.binance.com/"),
+            BinanceBase::UsdMFutures => String::from("https://fapi.binance.com/"),
+            BinanceBase::CoinMFutures => String::from("https://dapi.binance.com/"),
+        }
+    }
+}
+
+impl BinanceBase {
+    pub const ALL: [BinanceBase; 4] = [BinanceBase::Normal, BinanceBase::PortfolioMargin, BinanceBase::UsdMFutures, BinanceBase::CoinMFutures];
+
+    /** 每个base的权重和下单数限制，见exchangeInfo里面的rateLimits
+     */
+    pub fn request_limit(&self) -> RequestLimit {
+        match self {
+            BinanceBase::Normal => RequestLimit {
+                weight_per_minute: 6000,
+                orders: vec![OrderLimit { interval: "10s", limit: 100 }, OrderLimit { interval: "1d", limit: 200_000 }],
+            },
+            BinanceBase::PortfolioMargin => RequestLimit {
+                weight_per_minute: 6000,
+                orders: vec![OrderLimit { interval: "1m", limit: 1200 }],
+            },
+            BinanceBase::UsdMFutures => RequestLimit {
+                weight_per_minute: 2400,
+                orders: vec![OrderLimit { interval: "10s", limit: 300 }, OrderLimit { interval: "1m", limit: 1200 }],
+            },
+            BinanceBase::CoinMFutures => RequestLimit {
+                weight_per_minute: 2400,
+                orders: vec![OrderLimit { interval: "1m", limit: 1200 }],
+            },
+        }
+    }
+}
+
+/** 各个BinanceBase对应的实际地址，默认是生产环境。测试的时候可以指向testnet或者本地的mock server
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoints {
+    pub normal: String,
+    pub portfolio_margin: String,
+    pub usdm_futures: String, //U本位合约的公开行情，比如资金费率
+    pub coinm_futures: String, //币本位合约的公开行情
+}
+
+impl Endpoints {
+    pub fn production() -> Endpoints {
+        Endpoints {
+            normal: String::from(BinanceBase::Normal),
+            portfolio_margin: String::from(BinanceBase::PortfolioMargin),
+            usdm_futures: String::from(BinanceBase::UsdMFutures),
+            coinm_futures: String::from(BinanceBase::CoinMFutures),
+        }
+    }
+
+    /** 统一账户没有testnet，papi也指向现货的testnet，这样调用会直接报错，而不是打到生产环境上。
+    * U本位和币本位合约有自己的testnet
+    */
+    pub fn testnet() -> Endpoints {
+        Endpoints {
+            usdm_futures: String::from("https://testnet.binancefuture.com/"),
+            coinm_futures: String::from("https://testnet.binancefuture.com/"),
+            ..Endpoints::with_base("https://testnet.binance.vision/")
+        }
+    }
+
+    /** 所有的接口都走同一个地址，一般是本地的mock server
+     */
+    pub fn with_base(base_url: &str) -> Endpoints {
+        Endpoints {
+            normal: String::from(base_url),
+            portfolio_margin: String::from(base_url),
+            usdm_futures: String::from(base_url),
+            coinm_futures: String::from(base_url),
+        }
+    }
+
+    /** 配置文件里面的endpoint，production，testnet或者是一个url
+     */
+    pub fn from_setting(endpoint: &str) -> Endpoints {
+        match endpoint {
+            "production" => Endpoints::production(),
+            "testnet" => Endpoints::testnet(),
+            base_url => Endpoints::with_base(base_url),
+        }
+    }
+
+    pub fn url_of(&self, base: &BinanceBase) -> &str {
+        match base {
+            BinanceBase::Normal => &self.normal,
+            BinanceBase::PortfolioMargin => &self.portfolio_margin,
+            BinanceBase::UsdMFutures => &self.usdm_futures,
+            BinanceBase::CoinMFutures => &self.coinm_futures,
+        }
     }
 }
 
 pub enum BinancePath {
     Normal(NormalAPI),
     PAPI(PmAPI),
+    FAPI(UmAPI),
+    DAPI(CmAPI),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum NormalAPI {
     PingAPI,
+    ServerTimeAPI,
     SpotTickerAPI,
+    DepthAPI,
+    ExchangeInfoAPI,
+    AccountAPI,
 }
 
 
+#[derive(Debug, Clone, Copy)]
 pub enum PmAPI { //统一账户
     BalanceAPI,
+    AccountAPI,
     SwapPositionAPI,
+    CMPositionAPI,
+    UMOrderAPI,
+    CMOrderAPI,
+    MarginOrderAPI,
+    UMOpenOrdersAPI,
+    UMAllOrdersAPI,
+    UMUserTradesAPI,
+    CMOpenOrdersAPI,
+    CMAllOrdersAPI,
+    CMUserTradesAPI,
+    MarginOpenOrdersAPI,
+    MarginAllOrdersAPI,
+    MarginUserTradesAPI,
+    UMIncomeAPI,
+    ListenKeyAPI,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UmAPI { //U本位合约
+    PremiumIndexAPI,
+    DepthAPI,
+    ExchangeInfoAPI,
+    AccountAPI,
+    PositionRiskAPI,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CmAPI { //币本位合约
+    PremiumIndexAPI,
+    ExchangeInfoAPI,
+    AccountAPI,
+    PositionRiskAPI,
 }
 
 
 impl From<BinancePath> for String {
     fn from(api: BinancePath) -> Self {
-        String::from(
-            match api {
-                BinancePath::Normal(route) => match route {
-                    NormalAPI::PingAPI => String::from("api/v3/Ping"),
-                    NormalAPI::SpotTickerAPI => String::from("/api/v3/ticker/price"),
-                }
-                BinancePath::PAPI(route) => match route {
-                    PmAPI::BalanceAPI => String::from("/papi/v1/balance"),
-                    PmAPI::SwapPositionAPI => String::from("/papi/v1/um/positionRisk"),
-                }
+        match api {
+            BinancePath::Normal(route) => match route {
+                NormalAPI::PingAPI => String::from("api/v3/Ping"),
+                NormalAPI::ServerTimeAPI => String::from("/api/v3/time"),
+                NormalAPI::SpotTickerAPI => String::from("/api/v3/ticker/price"),
+                NormalAPI::DepthAPI => String::from("/api/v3/depth"),
+                NormalAPI::ExchangeInfoAPI => String::from("/api/v3/exchangeInfo"),
+                NormalAPI::AccountAPI => String::from("/api/v3/account"),
+            }
+            BinancePath::PAPI(route) => match route {
+                PmAPI::BalanceAPI => String::from("/papi/v1/balance"),
+                PmAPI::AccountAPI => String::from("/papi/v1/account"),
+                PmAPI::SwapPositionAPI => String::from("/papi/v1/um/positionRisk"),
+                PmAPI::CMPositionAPI => String::from("/papi/v1/cm/positionRisk"),
+                PmAPI::UMOrderAPI => String::from("/papi/v1/um/order"),
+                PmAPI::CMOrderAPI => String::from("/papi/v1/cm/order"),
+                PmAPI::MarginOrderAPI => String::from("/papi/v1/margin/order"),
+                PmAPI::UMOpenOrdersAPI => String::from("/papi/v1/um/openOrders"),
+                PmAPI::UMAllOrdersAPI => String::from("/papi/v1/um/allOrders"),
+                PmAPI::UMUserTradesAPI => String::from("/papi/v1/um/userTrades"),
+                PmAPI::CMOpenOrdersAPI => String::from("/papi/v1/cm/openOrders"),
+                PmAPI::CMAllOrdersAPI => String::from("/papi/v1/cm/allOrders"),
+                PmAPI::CMUserTradesAPI => String::from("/papi/v1/cm/userTrades"),
+                PmAPI::MarginOpenOrdersAPI => String::from("/papi/v1/margin/openOrders"),
+                PmAPI::MarginAllOrdersAPI => String::from("/papi/v1/margin/allOrders"),
+                PmAPI::MarginUserTradesAPI => String::from("/papi/v1/margin/myTrades"),
+                PmAPI::UMIncomeAPI => String::from("/papi/v1/um/income"),
+                PmAPI::ListenKeyAPI => String::from("/papi/v1/listenKey"),
+            }
+            BinancePath::FAPI(route) => match route {
+                UmAPI::PremiumIndexAPI => String::from("/fapi/v1/premiumIndex"),
+                UmAPI::DepthAPI => String::from("/fapi/v1/depth"),
+                UmAPI::ExchangeInfoAPI => String::from("/fapi/v1/exchangeInfo"),
+                UmAPI::AccountAPI => String::from("/fapi/v2/account"),
+                UmAPI::PositionRiskAPI => String::from("/fapi/v2/positionRisk"),
+            }
+            BinancePath::DAPI(route) => match route {
+                CmAPI::PremiumIndexAPI => String::from("/dapi/v1/premiumIndex"),
+                CmAPI::ExchangeInfoAPI => String::from("/dapi/v1/exchangeInfo"),
+                CmAPI::AccountAPI => String::from("/dapi/v1/account"),
+                CmAPI::PositionRiskAPI => String::from("/dapi/v1/positionRisk"),
             }
-        )
+        }
+    }
+}
+
+impl BinancePath {
+    /** 下单的接口，POST的时候要算下单数
+     */
+    pub fn is_order(&self) -> bool {
+        matches!(self, BinancePath::PAPI(PmAPI::UMOrderAPI | PmAPI::CMOrderAPI | PmAPI::MarginOrderAPI))
+    }
+
+    /** 接口的请求权重，见各个接口文档里面的weight
+     */
+    pub fn weight(&self) -> u32 {
+        match self {
+            BinancePath::Normal(route) => match route {
+                NormalAPI::PingAPI => 1,
+                NormalAPI::ServerTimeAPI => 1,
+                NormalAPI::SpotTickerAPI => 4,
+                NormalAPI::DepthAPI => 50,       // limit是1000的权重，limit越大权重越高
+                NormalAPI::ExchangeInfoAPI => 20,
+                NormalAPI::AccountAPI => 20,
+            }
+            BinancePath::PAPI(route) => match route {
+                PmAPI::BalanceAPI => 20,
+                PmAPI::AccountAPI => 20,
+                PmAPI::SwapPositionAPI => 5,
+                PmAPI::CMPositionAPI => 1,
+                PmAPI::UMOrderAPI => 1,
+                PmAPI::CMOrderAPI => 1,
+                PmAPI::MarginOrderAPI => 1,
+                PmAPI::UMOpenOrdersAPI => 40,    // 带symbol是1，不带是40，按照多的算
+                PmAPI::UMAllOrdersAPI => 5,
+                PmAPI::UMUserTradesAPI => 5,
+                PmAPI::CMOpenOrdersAPI => 40,
+                PmAPI::CMAllOrdersAPI => 40,     // 带symbol是20，带pair是40
+                PmAPI::CMUserTradesAPI => 40,
+                PmAPI::MarginOpenOrdersAPI => 5,
+                PmAPI::MarginAllOrdersAPI => 100,
+                PmAPI::MarginUserTradesAPI => 5,
+                PmAPI::UMIncomeAPI => 30,
+                PmAPI::ListenKeyAPI => 1,
+            }
+            BinancePath::FAPI(route) => match route {
+                UmAPI::PremiumIndexAPI => 10,    // 带symbol是1，不带是10
+                UmAPI::DepthAPI => 20,           // limit是1000的权重
+                UmAPI::ExchangeInfoAPI => 1,
+                UmAPI::AccountAPI => 5,
+                UmAPI::PositionRiskAPI => 5,
+            }
+            BinancePath::DAPI(route) => match route {
+                CmAPI::PremiumIndexAPI => 10,
+                CmAPI::ExchangeInfoAPI => 1,
+                CmAPI::AccountAPI => 5,
+                CmAPI::PositionRiskAPI => 1,
+            }
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum WsMethod {
     Ping,
     Time,
     SUBSCRIBE,
+    UNSUBSCRIBE,
+    ListSubscriptions,
+    SessionLogon,
+    SessionStatus,
+    SessionLogout,
+    OrderPlace,
+    OrderCancel,
+    OrderStatus,
+    AccountStatus,
+}
+
+const WS_METHODS: [(WsMethod, &str); 12] = [
+    (WsMethod::Ping, "ping"),
+    (WsMethod::Time, "time"),
+    (WsMethod::SUBSCRIBE, "SUBSCRIBE"),
+    (WsMethod::UNSUBSCRIBE, "UNSUBSCRIBE"),
+    (WsMethod::ListSubscriptions, "LIST_SUBSCRIPTIONS"),
+    (WsMethod::SessionLogon, "session.logon"),
+    (WsMethod::SessionStatus, "session.status"),
+    (WsMethod::SessionLogout, "session.logout"),
+    (WsMethod::OrderPlace, "order.place"),
+    (WsMethod::OrderCancel, "order.cancel"),
+    (WsMethod::OrderStatus, "order.status"),
+    (WsMethod::AccountStatus, "account.status"),
+];
+
+impl WsMethod {
+    /** 发给服务器的method名字
+     */
+    pub fn name(&self) -> &'static str {
+        WS_METHODS.iter().find(|(method, _)| method == self).map(|(_, name)| *name).unwrap()
+    }
+
+    pub fn from_name(name: &str) -> Option<WsMethod> {
+        WS_METHODS.iter().find(|(_, n)| *n == name).map(|(method, _)| *method)
+    }
 }
 
 
@@ -69,27 +330,56 @@ pub fn serialize_wx_method<S>(shape: &WsMethod, serializer: S) -> Result<S::Ok,
 where
     S: Serializer,
 {
-    match shape {
-        WsMethod::Ping => serializer.serialize_str("ping"),
-        WsMethod::Time => serializer.serialize_str("time"),
-        WsMethod::SUBSCRIBE => serializer.serialize_str("SUBSCRIBE"),
-    }
+    serializer.serialize_str(shape.name())
 }
 
-// 自定义反序列化函数
+// 自定义反序列化函数，不认识的method返回错误
 pub fn deserialize_wx_method<'de, D>(deserializer: D) -> Result<WsMethod, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s: String = String::deserialize(deserializer)?;
-    match s.as_str() {
-        "ping" => { Ok(WsMethod::Ping) }
-        "time" => { Ok(WsMethod::Time) }
-        "SUBSCRIBE" => { Ok(WsMethod::SUBSCRIBE) }
-        _ => {
-            panic!("not found command")
-        }
-    }
+    WsMethod::from_name(&s).ok_or_else(|| serde::de::Error::custom(format!("unknown ws method: {}", s)))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerTime {
+    #[serde(rename = "serverTime")]
+    pub server_time: UnixTimeStamp,
+}
+
+/** 币安出错的时候返回的内容，比如 {"code":-1021,"msg":"Timestamp for this request is outside of the recvWindow."}
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinanceErrorResponse {
+    pub code: i64,
+    pub msg: String,
+}
+
+/** 统一账户的保证金信息，/papi/v1/account。uniMMR低于1.05会被强平
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PMAccountInfo {
+    #[serde(rename = "uniMMR")]
+    pub uni_mmr: Decimal, // 统一账户维持保证金率 = 权益 / 维持保证金
+
+    #[serde(rename = "accountEquity")]
+    pub account_equity: Decimal, // 以USD计价的账户权益
+
+    #[serde(rename = "actualEquity")]
+    pub actual_equity: Decimal, // 不考虑质押率的账户权益
+
+    #[serde(rename = "accountInitialMargin")]
+    pub account_initial_margin: Decimal,
+
+    #[serde(rename = "accountMaintMargin")]
+    pub account_maint_margin: Decimal, // 以USD计价的维持保证金
+
+    #[serde(rename = "accountStatus")]
+    pub account_status: String, // NORMAL，MARGIN_CALL，SUPPLY_MARGIN，REDUCE_ONLY，ACTIVE_LIQUIDATION，FORCE_LIQUIDATION，BANKRUPTED
+
+    #[serde(rename = "updateTime")]
+    pub update_time: UnixTimeStamp,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -220,10 +510,114 @@ pub struct UMSwapPosition {
     pub break_even_price: Decimal, //表仓位盈亏平衡价
 }
 
+/** 币本位合约的持仓，positionAmt是张数，notionalValue和unRealizedProfit的单位是币
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CMSwapPosition {
+    #[serde(rename = "symbol")]
+    pub symbol: String, // 交易对，比如BTCUSD_PERP
+
+    #[serde(rename = "positionAmt")]
+    pub position_amt: Decimal, // 头寸数量（张），正数为多，负数为空
+
+    #[serde(rename = "entryPrice")]
+    pub entry_price: Decimal, // 开仓均价
+
+    #[serde(rename = "markPrice")]
+    pub mark_price: Decimal, // 当前标记价格
+
+    #[serde(rename = "unRealizedProfit")]
+    pub unrealized_profit: Decimal, // 持仓未实现盈亏，单位是币
+
+    #[serde(rename = "liquidationPrice")]
+    pub liquidation_price: Decimal, // 爆仓价格
+
+    #[serde(rename = "leverage", deserialize_with = "utils::str_to_u16")]
+    pub leverage: u16, // 当前杠杆倍数
+
+    #[serde(rename = "positionSide")]
+    pub position_side: String, // 持仓方向
+
+    #[serde(rename = "notionalValue")]
+    pub notional_value: Decimal, // 名义价值，单位是币，符号和positionAmt一样
+
+    #[serde(rename = "updateTime")]
+    pub update_time: UnixTimeStamp, // 更新时间
+}
+
+impl CMSwapPosition {
+    /** 保证金币种，BTCUSD_PERP和BTCUSD_241227都是BTC
+     */
+    pub fn margin_asset(&self) -> &str {
+        let pair = self.symbol.split('_').next().unwrap_or(&self.symbol);
+        pair.strip_suffix("USD").unwrap_or(pair)
+    }
+}
+
+/** U本位和币本位合约账户里面每个资产的余额。U本位是USDT这些，币本位是BTC这些，单位都是币
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FuturesAsset {
+    pub asset: String,
+
+    #[serde(rename = "walletBalance")]
+    pub wallet_balance: Decimal, // 余额
+
+    #[serde(rename = "unrealizedProfit")]
+    pub unrealized_profit: Decimal, // 未实现盈亏
+
+    #[serde(rename = "marginBalance")]
+    pub margin_balance: Decimal, // 保证金余额，余额加上未实现盈亏
+}
+
+/** U本位合约账户，/fapi/v2/account。total开头的都是按照USDT算的
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UMAccountInfo {
+    #[serde(rename = "totalWalletBalance")]
+    pub total_wallet_balance: Decimal,
+
+    #[serde(rename = "totalUnrealizedProfit")]
+    pub total_unrealized_profit: Decimal,
+
+    #[serde(rename = "totalMarginBalance")]
+    pub total_margin_balance: Decimal,
+
+    pub assets: Vec<FuturesAsset>,
+}
+
+/** 币本位合约账户，/dapi/v1/account。没有汇总，需要用现货价格折算
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CMAccountInfo {
+    pub assets: Vec<FuturesAsset>,
+}
+
+pub struct SpotRawAccountData {
+    pub account: SpotAccountStatus,
+    pub spot_ticker: Vec<Ticker>,
+}
+
+pub struct UMRawAccountData {
+    pub account: UMAccountInfo,
+    pub um_swap_position: Vec<UMSwapPosition>,
+    pub spot_ticker: Vec<Ticker>,
+}
+
+pub struct CMRawAccountData {
+    pub account: CMAccountInfo,
+    pub cm_swap_position: Vec<CMSwapPosition>,
+    pub spot_ticker: Vec<Ticker>,
+}
+
 pub struct PMRawAccountData {
+    pub account_info: PMAccountInfo,
     pub account_balance: Vec<PMBalance>,
     pub spot_ticker: Vec<Ticker>,
     pub um_swap_position: Vec<UMSwapPosition>,
+    pub cm_swap_position: Vec<CMSwapPosition>,
+    pub premium_index: Vec<PremiumIndex>, //配置了funding_rate_arbitrage或者用标记价格估值才会查
+    pub funding_income: Vec<UMIncome>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -246,17 +640,132 @@ pub struct Ticker {
     pub time: Option<UnixTimeStamp>,   // 撮合引擎时间,Spot的不存在这个数据
 }
 
+/** 只查需要的交易对，symbols=["BTCUSDT","BNBUSDT"]。有一个交易对不存在整个请求都会报-1121
+ */
+#[derive(Debug, Clone)]
+pub struct TickerQuery {
+    pub symbols: Vec<String>,
+}
+
+impl std::fmt::Display for TickerQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbols: Vec<String> = self.symbols.iter().map(|s| format!("\"{}\"", s)).collect();
+        let query = QueryBuilder::new()
+            .param("symbols", format!("[{}]", symbols.join(",")))
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+/** 只查一个交易对的标记价格和资金费率，权重是1
+ */
+#[derive(Debug, Clone)]
+pub struct PremiumIndexQuery {
+    pub symbol: String,
+}
+
+impl std::fmt::Display for PremiumIndexQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "symbol={}", self.symbol)
+    }
+}
+
+/** 标记价格和资金费率
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PremiumIndex {
+    pub symbol: String,
+
+    #[serde(rename = "markPrice")]
+    pub mark_price: Decimal,
+
+    #[serde(rename = "indexPrice")]
+    pub index_price: Decimal,
+
+    #[serde(rename = "lastFundingRate", deserialize_with = "utils::empty_as_zero")]
+    pub last_funding_rate: Decimal, // 最近更新的资金费率，下次结算的时候用这个。交割合约没有资金费率，返回空字符串
+
+    #[serde(rename = "interestRate", deserialize_with = "utils::empty_as_zero")]
+    pub interest_rate: Decimal,
+
+    #[serde(rename = "nextFundingTime")]
+    pub next_funding_time: UnixTimeStamp,
+
+    pub time: UnixTimeStamp,
+}
+
+/** 查询收益流水，income_type是FUNDING_FEE，REALIZED_PNL，COMMISSION这些
+ */
+#[derive(Debug, Clone, Default)]
+pub struct IncomeQuery {
+    pub symbol: Option<String>,
+    pub income_type: Option<String>,
+    pub start_time: Option<UnixTimeStamp>,
+    pub end_time: Option<UnixTimeStamp>,
+    pub limit: Option<usize>,
+}
+
+impl std::fmt::Display for IncomeQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .optional("symbol", &self.symbol)
+            .optional("incomeType", &self.income_type)
+            .optional("startTime", &self.start_time)
+            .optional("endTime", &self.end_time)
+            .optional("limit", &self.limit)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+pub const FUNDING_FEE: &str = "FUNDING_FEE";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UMIncome {
+    pub symbol: String, // 转账之类的没有symbol，是空字符串
+
+    #[serde(rename = "incomeType")]
+    pub income_type: String,
+
+    pub income: Decimal, // 正数是收到的，负数是付出的
+
+    pub asset: String,
+
+    pub info: String,
+
+    pub time: UnixTimeStamp,
+
+    #[serde(rename = "tranId")]
+    pub tran_id: u64,
+
+    #[serde(rename = "tradeId")]
+    pub trade_id: String,
+}
 
 pub struct SecurityInfo {
     pub api_key: String,
     pub api_secret: String,
 }
 
+/** 发送请求需要的环境，http client和各个接口的地址。clone的成本很低，client内部是Arc
+ */
+#[derive(Debug, Clone)]
+pub struct BinanceContext {
+    pub client: reqwest::Client,
+    pub endpoints: Endpoints,
+    pub rate_limiter: Arc<RateLimiter>, //clone之后还是同一个，这样所有账户一起算权重
+    pub clock: Arc<ServerClock>,
+    pub retry_policy: RetryPolicy,
+    pub valuation: ValuationPolicy, //余额怎么折算成USDT
+    pub funding_cache: Arc<FundingCache>, //已经查过的资金费流水，clone之后还是同一个
+    pub symbol_registry: Arc<SymbolRegistry>, //exchangeInfo的缓存，clone之后还是同一个
+}
+
 pub struct CommandInfo<'a> {
     pub base: BinanceBase,
     pub path: BinancePath,
     pub security: Option<SecurityInfo>,
-    pub client: &'a reqwest::Client,
+    pub context: &'a BinanceContext,
 }
 
 
@@ -271,6 +780,14 @@ impl std::fmt::Display for TimeStampRequest {
     }
 }
 
+impl TimeStampRequest {
+    pub fn new(timestamp: UnixTimeStamp, rec_window: u16) -> TimeStampRequest {
+        TimeStampRequest { timestamp, rec_window }
+    }
+}
+
+/** 本地时间，没有和服务器同步过。正常的请求用BinanceContext::timestamp_request
+ */
 impl Default for TimeStampRequest {
     fn default() -> Self {
         TimeStampRequest {
@@ -280,14 +797,1554 @@ impl Default for TimeStampRequest {
     }
 }
 
+/** 需要签名的请求，参数后面加上timestamp和recvWindow。重试的时候重新生成，保证timestamp是新的
+ */
+pub struct WithTimeStamp<R: std::fmt::Display> {
+    pub request: R,
+    pub timestamp: TimeStampRequest,
+}
 
-#[cfg(test)]
-mod tests {
-    use crate::binance::bn_models::{BinanceBase, BinancePath, NormalAPI};
+impl<R: std::fmt::Display> WithTimeStamp<R> {
+    pub fn new(request: R, timestamp: TimeStampRequest) -> WithTimeStamp<R> {
+        WithTimeStamp { request, timestamp }
+    }
+}
 
-    #[test]
-    fn test_api_define() {
-        assert_eq!("api/v3/Ping", String::from(BinancePath::Normal(NormalAPI::PingAPI)));
-        assert_eq!("https://api.binance.com/", String::from(BinanceBase::Normal));
+impl<R: std::fmt::Display> std::fmt::Display for WithTimeStamp<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let request = self.request.to_string();
+        if request.is_empty() {
+            write!(f, "{}", self.timestamp)
+        } else {
+            write!(f, "{}&{}", request, self.timestamp)
+        }
+    }
+}
+
+/** 拼接query string，None的参数不拼
+ */
+pub(crate) struct QueryBuilder {
+    serializer: url::form_urlencoded::Serializer<'static, String>,
+}
+
+impl QueryBuilder {
+    pub fn new() -> QueryBuilder {
+        QueryBuilder { serializer: url::form_urlencoded::Serializer::new(String::new()) }
+    }
+
+    pub fn param<V: std::fmt::Display>(mut self, name: &str, value: V) -> QueryBuilder {
+        self.serializer.append_pair(name, &value.to_string());
+        self
+    }
+
+    pub fn optional<V: std::fmt::Display>(self, name: &str, value: &Option<V>) -> QueryBuilder {
+        match value {
+            Some(v) => self.param(name, v),
+            None => self,
+        }
+    }
+
+    pub fn build(mut self) -> String {
+        self.serializer.finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PositionSide {
+    Both,  // 单向持仓
+    Long,  // 双向持仓的多头
+    Short, // 双向持仓的空头
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderType {
+    Limit,
+    Market,
+    StopLoss,
+    StopLossLimit,
+    TakeProfit,
+    TakeProfitLimit,
+    LimitMaker,
+    Stop,
+    StopMarket,
+    TakeProfitMarket,
+    TrailingStopMarket,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    GTC, // 成交为止
+    IOC, // 无法立即成交的部分就撤销
+    FOK, // 无法全部立即成交就撤销
+    GTX, // 只做maker
+    GTD, // 到指定时间撤销
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    PendingCancel,
+    Rejected,
+    Expired,
+    ExpiredInMatch,
+    #[serde(other)]
+    Unknown,
+}
+
+/** 杠杆下单的时候是否自动借款/还款
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SideEffectType {
+    NoSideEffect,
+    MarginBuy,
+    AutoRepay,
+}
+
+/** 枚举按照币安的格式输出，拼query string的时候用
+ */
+macro_rules! display_as_serde {
+    ($($name:ty),+) => {
+        $(
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                let value = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+                write!(f, "{}", value.as_str().unwrap_or_default())
+            }
+        }
+        )+
+    };
+}
+
+display_as_serde!(OrderSide, PositionSide, OrderType, TimeInForce, OrderStatus, SideEffectType);
+
+/** 统一账户UM和CM合约下单，两个接口的参数是一样的
+ */
+#[derive(Debug, Clone)]
+pub struct PMOrderRequest {
+    pub symbol: String,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub quantity: Decimal,
+    pub price: Option<Decimal>,
+    pub time_in_force: Option<TimeInForce>,
+    pub reduce_only: Option<bool>,
+    pub position_side: Option<PositionSide>,
+    pub new_client_order_id: String,
+}
+
+impl std::fmt::Display for PMOrderRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .param("symbol", &self.symbol)
+            .param("side", self.side)
+            .optional("positionSide", &self.position_side)
+            .param("type", self.order_type)
+            .optional("timeInForce", &self.time_in_force)
+            .param("quantity", self.quantity.normalize())
+            .optional("price", &self.price.map(|p| p.normalize()))
+            .optional("reduceOnly", &self.reduce_only)
+            .param("newClientOrderId", &self.new_client_order_id)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+/** 统一账户杠杆下单
+ */
+#[derive(Debug, Clone)]
+pub struct MarginOrderRequest {
+    pub symbol: String,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub quantity: Option<Decimal>,
+    pub quote_order_qty: Option<Decimal>,
+    pub price: Option<Decimal>,
+    pub stop_price: Option<Decimal>,
+    pub time_in_force: Option<TimeInForce>,
+    pub side_effect_type: Option<SideEffectType>,
+    pub new_client_order_id: String,
+}
+
+impl std::fmt::Display for MarginOrderRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .param("symbol", &self.symbol)
+            .param("side", self.side)
+            .param("type", self.order_type)
+            .optional("quantity", &self.quantity.map(|q| q.normalize()))
+            .optional("quoteOrderQty", &self.quote_order_qty.map(|q| q.normalize()))
+            .optional("price", &self.price.map(|p| p.normalize()))
+            .optional("stopPrice", &self.stop_price.map(|p| p.normalize()))
+            .optional("timeInForce", &self.time_in_force)
+            .optional("sideEffectType", &self.side_effect_type)
+            .param("newClientOrderId", &self.new_client_order_id)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UMOrderResponse {
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+
+    pub symbol: String,
+
+    pub side: OrderSide,
+
+    #[serde(rename = "positionSide")]
+    pub position_side: PositionSide,
+
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: TimeInForce,
+
+    pub status: OrderStatus,
+
+    pub price: Decimal,
+
+    #[serde(rename = "avgPrice")]
+    pub avg_price: Decimal,    // 成交均价
+
+    #[serde(rename = "origQty")]
+    pub orig_qty: Decimal,     // 下单数量
+
+    #[serde(rename = "executedQty")]
+    pub executed_qty: Decimal, // 成交数量
+
+    #[serde(rename = "cumQuote")]
+    pub cum_quote: Decimal,    // 成交金额
+
+    #[serde(rename = "reduceOnly")]
+    pub reduce_only: bool,
+
+    #[serde(default)]
+    pub time: Option<UnixTimeStamp>, // 下单时间，查询订单的时候才有
+
+    #[serde(rename = "updateTime")]
+    pub update_time: UnixTimeStamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CMOrderResponse {
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+
+    pub symbol: String,
+
+    pub pair: String,
+
+    pub side: OrderSide,
+
+    #[serde(rename = "positionSide")]
+    pub position_side: PositionSide,
+
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: TimeInForce,
+
+    pub status: OrderStatus,
+
+    pub price: Decimal,
+
+    #[serde(rename = "avgPrice")]
+    pub avg_price: Decimal,
+
+    #[serde(rename = "origQty")]
+    pub orig_qty: Decimal,     // 下单数量，单位是张
+
+    #[serde(rename = "executedQty")]
+    pub executed_qty: Decimal,
+
+    #[serde(rename = "cumBase")]
+    pub cum_base: Decimal,     // 成交金额，单位是标的币
+
+    #[serde(rename = "reduceOnly")]
+    pub reduce_only: bool,
+
+    #[serde(default)]
+    pub time: Option<UnixTimeStamp>, // 下单时间，查询订单的时候才有
+
+    #[serde(rename = "updateTime")]
+    pub update_time: UnixTimeStamp,
+}
+
+/** 杠杆下单的返回，newOrderRespType是ACK的时候只有前面几个字段
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginOrderResponse {
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+
+    pub symbol: String,
+
+    #[serde(rename = "transactTime")]
+    pub transact_time: UnixTimeStamp,
+
+    pub side: Option<OrderSide>,
+
+    #[serde(rename = "type")]
+    pub order_type: Option<OrderType>,
+
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: Option<TimeInForce>,
+
+    pub status: Option<OrderStatus>,
+
+    pub price: Option<Decimal>,
+
+    #[serde(rename = "origQty")]
+    pub orig_qty: Option<Decimal>,
+
+    #[serde(rename = "executedQty")]
+    pub executed_qty: Option<Decimal>,
+
+    #[serde(rename = "cummulativeQuoteQty")]
+    pub cummulative_quote_qty: Option<Decimal>,
+
+    #[serde(rename = "marginBuyBorrowAsset")]
+    pub margin_buy_borrow_asset: Option<String>,
+
+    #[serde(rename = "marginBuyBorrowAmount")]
+    pub margin_buy_borrow_amount: Option<Decimal>,
+}
+
+/** 撤单和查单，orderId和origClientOrderId至少要有一个。UM，CM和杠杆的参数是一样的
+ */
+#[derive(Debug, Clone)]
+pub struct CancelOrderRequest {
+    pub symbol: String,
+    pub order_id: Option<u64>,
+    pub orig_client_order_id: Option<String>,
+}
+
+impl std::fmt::Display for CancelOrderRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .param("symbol", &self.symbol)
+            .optional("orderId", &self.order_id)
+            .optional("origClientOrderId", &self.orig_client_order_id)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+/** 杠杆撤单的返回，没有下单时间和更新时间。clientOrderId是这次撤单的id，原来的id在origClientOrderId
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginCancelResponse {
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    #[serde(rename = "origClientOrderId")]
+    pub orig_client_order_id: String,
+
+    pub symbol: String,
+
+    pub side: OrderSide,
+
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+
+    pub status: OrderStatus,
+
+    pub price: Decimal,
+
+    #[serde(rename = "origQty")]
+    pub orig_qty: Decimal,
+
+    #[serde(rename = "executedQty")]
+    pub executed_qty: Decimal,
+
+    #[serde(rename = "cummulativeQuoteQty")]
+    pub cummulative_quote_qty: Decimal,
+}
+
+/** 现货下单，WS API的order.place用。参数和REST的一样，只是放在params里面
+ */
+#[derive(Debug, Clone)]
+pub struct SpotOrderRequest {
+    pub symbol: String,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub quantity: Option<Decimal>,
+    pub quote_order_qty: Option<Decimal>,
+    pub price: Option<Decimal>,
+    pub time_in_force: Option<TimeInForce>,
+    pub new_client_order_id: String,
+}
+
+impl std::fmt::Display for SpotOrderRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .param("symbol", &self.symbol)
+            .param("side", self.side)
+            .param("type", self.order_type)
+            .optional("quantity", &self.quantity.map(|q| q.normalize()))
+            .optional("quoteOrderQty", &self.quote_order_qty.map(|q| q.normalize()))
+            .optional("price", &self.price.map(|p| p.normalize()))
+            .optional("timeInForce", &self.time_in_force)
+            .param("newClientOrderId", &self.new_client_order_id)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+/** 现货下单和撤单的返回，newOrderRespType是ACK的时候只有前面几个字段
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotOrderResponse {
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+
+    #[serde(rename = "origClientOrderId")]
+    pub orig_client_order_id: Option<String>, // 撤单才有
+
+    pub symbol: String,
+
+    #[serde(rename = "transactTime")]
+    pub transact_time: Option<UnixTimeStamp>,
+
+    pub side: Option<OrderSide>,
+
+    #[serde(rename = "type")]
+    pub order_type: Option<OrderType>,
+
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: Option<TimeInForce>,
+
+    pub status: Option<OrderStatus>,
+
+    pub price: Option<Decimal>,
+
+    #[serde(rename = "origQty")]
+    pub orig_qty: Option<Decimal>,
+
+    #[serde(rename = "executedQty")]
+    pub executed_qty: Option<Decimal>,
+
+    #[serde(rename = "cummulativeQuoteQty")]
+    pub cummulative_quote_qty: Option<Decimal>,
+
+    #[serde(default)]
+    pub fills: Vec<SpotFill>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotFill {
+    pub price: Decimal,
+
+    pub qty: Decimal,
+
+    pub commission: Decimal,
+
+    #[serde(rename = "commissionAsset")]
+    pub commission_asset: String,
+
+    #[serde(rename = "tradeId")]
+    pub trade_id: u64,
+}
+
+/** 现货账户，WS API的account.status和REST的/api/v3/account
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotAccountStatus {
+    #[serde(rename = "canTrade")]
+    pub can_trade: bool,
+
+    #[serde(rename = "canWithdraw")]
+    pub can_withdraw: bool,
+
+    #[serde(rename = "canDeposit")]
+    pub can_deposit: bool,
+
+    #[serde(rename = "updateTime")]
+    pub update_time: UnixTimeStamp,
+
+    #[serde(rename = "accountType")]
+    pub account_type: String,
+
+    pub balances: Vec<SpotBalance>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotBalance {
+    pub asset: String,
+    pub free: Decimal,
+    pub locked: Decimal,
+}
+
+/** 查询挂单，历史订单和成交的参数，不同的接口用到的参数不一样，没用到的是None
+ */
+#[derive(Debug, Clone, Default)]
+pub struct PMHistoryQuery {
+    pub symbol: Option<String>,
+    pub pair: Option<String>,              // 只有CM用
+    pub from_id: Option<u64>,              // 成交记录从这个id开始，不能和时间一起用
+    pub start_time: Option<UnixTimeStamp>,
+    pub end_time: Option<UnixTimeStamp>,
+    pub limit: Option<usize>,
+}
+
+impl std::fmt::Display for PMHistoryQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .optional("symbol", &self.symbol)
+            .optional("pair", &self.pair)
+            .optional("fromId", &self.from_id)
+            .optional("startTime", &self.start_time)
+            .optional("endTime", &self.end_time)
+            .optional("limit", &self.limit)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+/** 杠杆的挂单和历史订单
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginOrder {
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    #[serde(rename = "clientOrderId")]
+    pub client_order_id: String,
+
+    pub symbol: String,
+
+    pub side: OrderSide,
+
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: TimeInForce,
+
+    pub status: OrderStatus,
+
+    pub price: Decimal,
+
+    #[serde(rename = "stopPrice")]
+    pub stop_price: Decimal,
+
+    #[serde(rename = "origQty")]
+    pub orig_qty: Decimal,
+
+    #[serde(rename = "executedQty")]
+    pub executed_qty: Decimal,
+
+    #[serde(rename = "cummulativeQuoteQty")]
+    pub cummulative_quote_qty: Decimal,
+
+    #[serde(rename = "isWorking")]
+    pub is_working: bool,
+
+    pub time: UnixTimeStamp,
+
+    #[serde(rename = "updateTime")]
+    pub update_time: UnixTimeStamp,
+}
+
+/** 下单结果未知的时候用查单的结果代替，查单没有transactTime，用updateTime
+ */
+impl From<MarginOrder> for MarginOrderResponse {
+    fn from(order: MarginOrder) -> Self {
+        MarginOrderResponse {
+            order_id: order.order_id,
+            client_order_id: order.client_order_id,
+            symbol: order.symbol,
+            transact_time: order.update_time,
+            side: Some(order.side),
+            order_type: Some(order.order_type),
+            time_in_force: Some(order.time_in_force),
+            status: Some(order.status),
+            price: Some(order.price),
+            orig_qty: Some(order.orig_qty),
+            executed_qty: Some(order.executed_qty),
+            cummulative_quote_qty: Some(order.cummulative_quote_qty),
+            margin_buy_borrow_asset: None,
+            margin_buy_borrow_amount: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UMTrade {
+    pub id: u64,
+
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    pub symbol: String,
+
+    pub side: OrderSide,
+
+    #[serde(rename = "positionSide")]
+    pub position_side: PositionSide,
+
+    pub price: Decimal,
+
+    pub qty: Decimal,
+
+    #[serde(rename = "quoteQty")]
+    pub quote_qty: Decimal,
+
+    #[serde(rename = "realizedPnl")]
+    pub realized_pnl: Decimal,
+
+    pub commission: Decimal,
+
+    #[serde(rename = "commissionAsset")]
+    pub commission_asset: String,
+
+    pub buyer: bool,
+
+    pub maker: bool,
+
+    pub time: UnixTimeStamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CMTrade {
+    pub id: u64,
+
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    pub symbol: String,
+
+    pub pair: String,
+
+    pub side: OrderSide,
+
+    #[serde(rename = "positionSide")]
+    pub position_side: PositionSide,
+
+    pub price: Decimal,
+
+    pub qty: Decimal,      // 单位是张
+
+    #[serde(rename = "baseQty")]
+    pub base_qty: Decimal, // 单位是标的币
+
+    #[serde(rename = "realizedPnl")]
+    pub realized_pnl: Decimal,
+
+    #[serde(rename = "marginAsset")]
+    pub margin_asset: String,
+
+    pub commission: Decimal,
+
+    #[serde(rename = "commissionAsset")]
+    pub commission_asset: String,
+
+    pub buyer: bool,
+
+    pub maker: bool,
+
+    pub time: UnixTimeStamp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginTrade {
+    pub id: u64,
+
+    #[serde(rename = "orderId")]
+    pub order_id: u64,
+
+    pub symbol: String,
+
+    pub price: Decimal,
+
+    pub qty: Decimal,
+
+    pub commission: Decimal,
+
+    #[serde(rename = "commissionAsset")]
+    pub commission_asset: String,
+
+    #[serde(rename = "isBuyer")]
+    pub is_buyer: bool,
+
+    #[serde(rename = "isMaker")]
+    pub is_maker: bool,
+
+    pub time: UnixTimeStamp,
+}
+
+
+/** 深度里面的一档，币安返回的是["价格","数量"]
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "(Decimal, Decimal)", into = "(Decimal, Decimal)")]
+pub struct PriceLevel {
+    pub price: Decimal,
+    pub qty: Decimal, // 0表示这一档被删掉了
+}
+
+impl From<(Decimal, Decimal)> for PriceLevel {
+    fn from((price, qty): (Decimal, Decimal)) -> Self {
+        PriceLevel { price, qty }
+    }
+}
+
+impl From<PriceLevel> for (Decimal, Decimal) {
+    fn from(level: PriceLevel) -> Self {
+        (level.price, level.qty)
+    }
+}
+
+/** 归集成交，<symbol>@aggTrade
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggTradeEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "a")]
+    pub agg_trade_id: u64,
+
+    #[serde(rename = "p")]
+    pub price: Decimal,
+
+    #[serde(rename = "q")]
+    pub qty: Decimal,
+
+    #[serde(rename = "f")]
+    pub first_trade_id: u64,
+
+    #[serde(rename = "l")]
+    pub last_trade_id: u64,
+
+    #[serde(rename = "T")]
+    pub trade_time: UnixTimeStamp,
+
+    #[serde(rename = "m")]
+    pub buyer_is_maker: bool, // true表示主动卖出
+}
+
+/** 逐笔成交，<symbol>@trade，只有现货有
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TradeEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "t")]
+    pub trade_id: u64,
+
+    #[serde(rename = "p")]
+    pub price: Decimal,
+
+    #[serde(rename = "q")]
+    pub qty: Decimal,
+
+    #[serde(rename = "T")]
+    pub trade_time: UnixTimeStamp,
+
+    #[serde(rename = "m")]
+    pub buyer_is_maker: bool,
+}
+
+/** 增量深度，<symbol>@depth。现货用U和u判断连续，合约多了pu(上一条的u)
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepthUpdateEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "T", default)]
+    pub transaction_time: Option<UnixTimeStamp>, // 只有合约有
+
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "U")]
+    pub first_update_id: u64,
+
+    #[serde(rename = "u")]
+    pub final_update_id: u64,
+
+    #[serde(rename = "pu", default)]
+    pub prev_final_update_id: Option<u64>, // 只有合约有
+
+    #[serde(rename = "b")]
+    pub bids: Vec<PriceLevel>,
+
+    #[serde(rename = "a")]
+    pub asks: Vec<PriceLevel>,
+}
+
+/** 最优挂单，<symbol>@bookTicker。现货的推送里面没有e和E
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookTickerEvent {
+    #[serde(rename = "E", default)]
+    pub event_time: Option<UnixTimeStamp>,
+
+    #[serde(rename = "u")]
+    pub update_id: u64,
+
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "b")]
+    pub bid_price: Decimal,
+
+    #[serde(rename = "B")]
+    pub bid_qty: Decimal,
+
+    #[serde(rename = "a")]
+    pub ask_price: Decimal,
+
+    #[serde(rename = "A")]
+    pub ask_qty: Decimal,
+}
+
+/** K线，<symbol>@kline_<interval>
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KlineEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "k")]
+    pub kline: Kline,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Kline {
+    #[serde(rename = "t")]
+    pub open_time: UnixTimeStamp,
+
+    #[serde(rename = "T")]
+    pub close_time: UnixTimeStamp,
+
+    #[serde(rename = "i")]
+    pub interval: String,
+
+    #[serde(rename = "o")]
+    pub open: Decimal,
+
+    #[serde(rename = "c")]
+    pub close: Decimal,
+
+    #[serde(rename = "h")]
+    pub high: Decimal,
+
+    #[serde(rename = "l")]
+    pub low: Decimal,
+
+    #[serde(rename = "v")]
+    pub volume: Decimal,
+
+    #[serde(rename = "q")]
+    pub quote_volume: Decimal,
+
+    #[serde(rename = "n")]
+    pub trade_count: u64,
+
+    #[serde(rename = "x")]
+    pub closed: bool, // 这根K线是不是已经走完了
+
+    #[serde(rename = "V")]
+    pub taker_buy_volume: Decimal,
+
+    #[serde(rename = "Q")]
+    pub taker_buy_quote_volume: Decimal,
+}
+
+/** 标记价格和资金费率，<symbol>@markPrice，只有合约有
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarkPriceEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "p")]
+    pub mark_price: Decimal,
+
+    #[serde(rename = "i")]
+    pub index_price: Decimal,
+
+    #[serde(rename = "P")]
+    pub estimated_settle_price: Decimal,
+
+    #[serde(rename = "r")]
+    pub funding_rate: Decimal,
+
+    #[serde(rename = "T")]
+    pub next_funding_time: UnixTimeStamp,
+}
+
+/** 强平订单，<symbol>@forceOrder，只有合约有
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForceOrderEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "o")]
+    pub order: ForceOrder,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForceOrder {
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "S")]
+    pub side: OrderSide,
+
+    #[serde(rename = "o")]
+    pub order_type: OrderType,
+
+    #[serde(rename = "f")]
+    pub time_in_force: TimeInForce,
+
+    #[serde(rename = "q")]
+    pub qty: Decimal,
+
+    #[serde(rename = "p")]
+    pub price: Decimal,
+
+    #[serde(rename = "ap")]
+    pub avg_price: Decimal,
+
+    #[serde(rename = "X")]
+    pub status: OrderStatus,
+
+    #[serde(rename = "l")]
+    pub last_filled_qty: Decimal,
+
+    #[serde(rename = "z")]
+    pub filled_qty: Decimal,
+
+    #[serde(rename = "T")]
+    pub trade_time: UnixTimeStamp,
+}
+
+/** REST的深度快照，合约的返回多了E和T，用不到
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepthSnapshot {
+    #[serde(rename = "lastUpdateId")]
+    pub last_update_id: u64,
+
+    pub bids: Vec<PriceLevel>,
+
+    pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DepthQuery {
+    pub symbol: String,
+    pub limit: u16,
+}
+
+impl std::fmt::Display for DepthQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let query = QueryBuilder::new()
+            .param("symbol", &self.symbol)
+            .param("limit", self.limit)
+            .build();
+        write!(f, "{}", query)
+    }
+}
+
+/** 行情推送，按照e字段区分类型。
+* 现货的bookTicker没有e字段，按照u，b，a这些字段判断；!markPrice@arr这种推送的是数组，变成Batch；
+* 不认识的或者解析不了的是Unknown，不会因为币安加了新的推送就断掉
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarketEvent {
+    AggTrade(AggTradeEvent),
+    Trade(TradeEvent),
+    DepthUpdate(DepthUpdateEvent),
+    BookTicker(BookTickerEvent),
+    Kline(KlineEvent),
+    MarkPrice(MarkPriceEvent),
+    ForceOrder(ForceOrderEvent),
+    Batch(Vec<MarketEvent>),
+    Unknown(serde_json::Value),
+}
+
+impl MarketEvent {
+    pub fn from_value(value: serde_json::Value) -> Result<MarketEvent, serde_json::Error> {
+        use serde_json::from_value;
+        if let serde_json::Value::Array(items) = value {
+            return items.into_iter()
+                .map(MarketEvent::from_value)
+                .collect::<Result<Vec<_>, _>>()
+                .map(MarketEvent::Batch);
+        }
+
+        let event_type = value.get("e").and_then(|e| e.as_str()).map(String::from);
+        let event = match event_type.as_deref() {
+            Some("aggTrade") => MarketEvent::AggTrade(from_value(value)?),
+            Some("trade") => MarketEvent::Trade(from_value(value)?),
+            Some("depthUpdate") => MarketEvent::DepthUpdate(from_value(value)?),
+            Some("bookTicker") => MarketEvent::BookTicker(from_value(value)?),
+            Some("kline") => MarketEvent::Kline(from_value(value)?),
+            Some("markPriceUpdate") => MarketEvent::MarkPrice(from_value(value)?),
+            Some("forceOrder") => MarketEvent::ForceOrder(from_value(value)?),
+            None if ["u", "s", "b", "a"].iter().all(|k| value.get(k).is_some()) => MarketEvent::BookTicker(from_value(value)?),
+            _ => MarketEvent::Unknown(value),
+        };
+        Ok(event)
+    }
+}
+
+impl<'de> Deserialize<'de> for MarketEvent {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        MarketEvent::from_value(value).map_err(serde::de::Error::custom)
+    }
+}
+
+
+/** exchangeInfo里面的交易对信息，现货，U本位和币本位合约的字段不完全一样，没有的是None
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeInfo {
+    #[serde(rename = "serverTime")]
+    pub server_time: UnixTimeStamp,
+
+    pub symbols: Vec<SymbolInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SymbolInfo {
+    pub symbol: String,
+
+    pub status: Option<String>, // 现货和U本位，TRADING才能交易
+
+    #[serde(rename = "contractStatus")]
+    pub contract_status: Option<String>, // 币本位
+
+    #[serde(rename = "baseAsset")]
+    pub base_asset: String,
+
+    #[serde(rename = "quoteAsset")]
+    pub quote_asset: String,
+
+    #[serde(rename = "marginAsset")]
+    pub margin_asset: Option<String>,
+
+    #[serde(rename = "contractType")]
+    pub contract_type: Option<String>, // PERPETUAL，CURRENT_QUARTER这些
+
+    #[serde(rename = "contractSize")]
+    pub contract_size: Option<Decimal>, // 币本位一张合约多少USD
+
+    #[serde(rename = "pricePrecision")]
+    pub price_precision: Option<u32>,
+
+    #[serde(rename = "quantityPrecision")]
+    pub quantity_precision: Option<u32>,
+
+    pub filters: Vec<SymbolFilter>,
+}
+
+impl SymbolInfo {
+    pub fn is_trading(&self) -> bool {
+        self.status.as_deref().or(self.contract_status.as_deref()) == Some("TRADING")
+    }
+}
+
+/** 下单相关的filter，其他的不关心
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "filterType")]
+pub enum SymbolFilter {
+    #[serde(rename = "PRICE_FILTER")]
+    PriceFilter {
+        #[serde(rename = "minPrice")]
+        min_price: Decimal,
+        #[serde(rename = "maxPrice")]
+        max_price: Decimal,
+        #[serde(rename = "tickSize")]
+        tick_size: Decimal,
+    },
+
+    #[serde(rename = "LOT_SIZE")]
+    LotSize {
+        #[serde(rename = "minQty")]
+        min_qty: Decimal,
+        #[serde(rename = "maxQty")]
+        max_qty: Decimal,
+        #[serde(rename = "stepSize")]
+        step_size: Decimal,
+    },
+
+    #[serde(rename = "MARKET_LOT_SIZE")]
+    MarketLotSize {
+        #[serde(rename = "minQty")]
+        min_qty: Decimal,
+        #[serde(rename = "maxQty")]
+        max_qty: Decimal,
+        #[serde(rename = "stepSize")]
+        step_size: Decimal,
+    },
+
+    // 现货以前的名字是minNotional，U本位是notional
+    #[serde(rename = "MIN_NOTIONAL")]
+    MinNotional {
+        #[serde(rename = "minNotional", alias = "notional")]
+        min_notional: Decimal,
+    },
+
+    // 现货现在用的
+    #[serde(rename = "NOTIONAL")]
+    Notional {
+        #[serde(rename = "minNotional")]
+        min_notional: Decimal,
+        #[serde(rename = "maxNotional")]
+        max_notional: Option<Decimal>,
+    },
+
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenKey {
+    #[serde(rename = "listenKey")]
+    pub listen_key: String,
+}
+
+/** 统一账户UM/CM订单的成交和状态变化，ORDER_TRADE_UPDATE
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderTradeUpdateEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "T")]
+    pub transaction_time: UnixTimeStamp,
+
+    #[serde(rename = "fs")]
+    pub business_unit: String, // UM或者CM
+
+    #[serde(rename = "o")]
+    pub order: OrderTradeUpdate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderTradeUpdate {
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "c")]
+    pub client_order_id: String,
+
+    #[serde(rename = "S")]
+    pub side: OrderSide,
+
+    #[serde(rename = "o")]
+    pub order_type: OrderType,
+
+    #[serde(rename = "f")]
+    pub time_in_force: TimeInForce,
+
+    #[serde(rename = "q")]
+    pub orig_qty: Decimal,
+
+    #[serde(rename = "p")]
+    pub price: Decimal,
+
+    #[serde(rename = "ap")]
+    pub avg_price: Decimal,
+
+    #[serde(rename = "x")]
+    pub execution_type: String, // NEW，TRADE，CANCELED，EXPIRED这些
+
+    #[serde(rename = "X")]
+    pub status: OrderStatus,
+
+    #[serde(rename = "i")]
+    pub order_id: u64,
+
+    #[serde(rename = "l")]
+    pub last_filled_qty: Decimal,
+
+    #[serde(rename = "z")]
+    pub filled_qty: Decimal,
+
+    #[serde(rename = "L")]
+    pub last_filled_price: Decimal,
+
+    #[serde(rename = "N", default)]
+    pub commission_asset: Option<String>, // 没有成交的时候没有手续费
+
+    #[serde(rename = "n", default)]
+    pub commission: Option<Decimal>,
+
+    #[serde(rename = "T")]
+    pub trade_time: UnixTimeStamp,
+
+    #[serde(rename = "t")]
+    pub trade_id: u64,
+
+    #[serde(rename = "m")]
+    pub is_maker: bool,
+
+    #[serde(rename = "R")]
+    pub reduce_only: bool,
+
+    #[serde(rename = "ps")]
+    pub position_side: PositionSide,
+
+    #[serde(rename = "rp")]
+    pub realized_profit: Decimal,
+}
+
+/** 统一账户UM/CM的余额和持仓变化，ACCOUNT_UPDATE。只推送有变化的资产和持仓
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountUpdateEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "T")]
+    pub transaction_time: UnixTimeStamp,
+
+    #[serde(rename = "fs")]
+    pub business_unit: String,
+
+    #[serde(rename = "a")]
+    pub update: AccountUpdate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountUpdate {
+    #[serde(rename = "m")]
+    pub reason: String, // ORDER，FUNDING_FEE，DEPOSIT这些
+
+    #[serde(rename = "B", default)]
+    pub balances: Vec<AccountUpdateBalance>,
+
+    #[serde(rename = "P", default)]
+    pub positions: Vec<AccountUpdatePosition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountUpdateBalance {
+    #[serde(rename = "a")]
+    pub asset: String,
+
+    #[serde(rename = "wb")]
+    pub wallet_balance: Decimal,
+
+    #[serde(rename = "cw", default)]
+    pub cross_wallet_balance: Decimal,
+
+    #[serde(rename = "bc", default)]
+    pub balance_change: Decimal, // 除了盈亏和手续费以外的变化
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountUpdatePosition {
+    #[serde(rename = "s")]
+    pub symbol: String,
+
+    #[serde(rename = "pa")]
+    pub position_amt: Decimal,
+
+    #[serde(rename = "ep")]
+    pub entry_price: Decimal,
+
+    #[serde(rename = "cr")]
+    pub accumulated_realized: Decimal,
+
+    #[serde(rename = "up")]
+    pub unrealized_pnl: Decimal,
+
+    #[serde(rename = "ps")]
+    pub position_side: PositionSide,
+
+    #[serde(rename = "bep", default)]
+    pub break_even_price: Option<Decimal>,
+}
+
+/** 杠杆账户的充提和划转，balanceUpdate
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceUpdateEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "a")]
+    pub asset: String,
+
+    #[serde(rename = "d")]
+    pub delta: Decimal,
+
+    #[serde(rename = "T")]
+    pub clear_time: UnixTimeStamp,
+}
+
+/** 杠杆账户余额变化之后的最新余额，outboundAccountPosition
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboundAccountPositionEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "u")]
+    pub last_update_time: UnixTimeStamp,
+
+    #[serde(rename = "B")]
+    pub balances: Vec<MarginBalance>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarginBalance {
+    #[serde(rename = "a")]
+    pub asset: String,
+
+    #[serde(rename = "f")]
+    pub free: Decimal,
+
+    #[serde(rename = "l")]
+    pub locked: Decimal,
+}
+
+/** 统一账户风险等级变化，riskLevelChange
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RiskLevelChangeEvent {
+    #[serde(rename = "E")]
+    pub event_time: UnixTimeStamp,
+
+    #[serde(rename = "u")]
+    pub uni_mmr: Decimal, // 统一账户维持保证金率
+
+    #[serde(rename = "s")]
+    pub level: String, // MARGIN_CALL，SUPPLY_MARGIN，REDUCE_ONLY，FORCE_LIQUIDATION
+
+    #[serde(rename = "eq")]
+    pub account_equity: Decimal,
+
+    #[serde(rename = "ae")]
+    pub actual_equity: Decimal,
+
+    #[serde(rename = "m")]
+    pub maintenance_margin: Decimal,
+}
+
+/** 统一账户的用户数据推送，按照e字段区分类型。listenKeyExpired之后需要重新申请listenKey
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserDataEvent {
+    OrderTradeUpdate(Box<OrderTradeUpdateEvent>),
+    AccountUpdate(AccountUpdateEvent),
+    BalanceUpdate(BalanceUpdateEvent),
+    OutboundAccountPosition(OutboundAccountPositionEvent),
+    RiskLevelChange(RiskLevelChangeEvent),
+    ListenKeyExpired,
+    Unknown(serde_json::Value),
+}
+
+impl UserDataEvent {
+    pub fn from_value(value: serde_json::Value) -> Result<UserDataEvent, serde_json::Error> {
+        use serde_json::from_value;
+        let event_type = value.get("e").and_then(|e| e.as_str()).map(String::from);
+        let event = match event_type.as_deref() {
+            Some("ORDER_TRADE_UPDATE") => UserDataEvent::OrderTradeUpdate(Box::new(from_value(value)?)),
+            Some("ACCOUNT_UPDATE") => UserDataEvent::AccountUpdate(from_value(value)?),
+            Some("balanceUpdate") => UserDataEvent::BalanceUpdate(from_value(value)?),
+            Some("outboundAccountPosition") => UserDataEvent::OutboundAccountPosition(from_value(value)?),
+            Some("riskLevelChange") => UserDataEvent::RiskLevelChange(from_value(value)?),
+            Some("listenKeyExpired") => UserDataEvent::ListenKeyExpired,
+            _ => UserDataEvent::Unknown(value),
+        };
+        Ok(event)
+    }
+}
+
+impl<'de> Deserialize<'de> for UserDataEvent {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        UserDataEvent::from_value(value).map_err(serde::de::Error::custom)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::binance::bn_models::{BinanceBase, BinancePath, Endpoints, MarketEvent, NormalAPI, OrderSide, OrderStatus, PositionSide, PriceLevel, UserDataEvent};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_api_define() {
+        assert_eq!("api/v3/Ping", String::from(BinancePath::Normal(NormalAPI::PingAPI)));
+        assert_eq!("https://api.binance.com/", String::from(BinanceBase::Normal));
+    }
+
+    #[test]
+    fn test_endpoints_from_setting() {
+        let production = Endpoints::from_setting("production");
+        assert_eq!("https://papi.binance.com/", production.url_of(&BinanceBase::PortfolioMargin));
+        assert_eq!("https://api.binance.com/", production.url_of(&BinanceBase::Normal));
+
+        let testnet = Endpoints::from_setting("testnet");
+        assert_eq!("https://testnet.binance.vision/", testnet.url_of(&BinanceBase::Normal));
+        assert_eq!("https://testnet.binancefuture.com/", testnet.url_of(&BinanceBase::UsdMFutures));
+        assert_eq!("https://dapi.binance.com/", production.url_of(&BinanceBase::CoinMFutures));
+
+        let local = Endpoints::from_setting("http://127.0.0.1:8080/");
+        assert_eq!("http://127.0.0.1:8080/", local.url_of(&BinanceBase::PortfolioMargin));
+        assert_eq!("http://127.0.0.1:8080/", local.url_of(&BinanceBase::Normal));
+    }
+
+    #[test]
+    fn test_parse_market_event() {
+        let agg_trade = r#"{"e":"aggTrade","E":1723960451595,"s":"BTCUSDT","a":26129,"p":"58000.01","q":"0.010","f":100,"l":105,"T":1723960451590,"m":true,"M":true}"#;
+        match serde_json::from_str::<MarketEvent>(agg_trade).unwrap() {
+            MarketEvent::AggTrade(e) => {
+                assert_eq!(dec!(58000.01), e.price);
+                assert_eq!(105, e.last_trade_id);
+                assert!(e.buyer_is_maker);
+            }
+            other => panic!("应该是aggTrade:{:?}", other)
+        }
+
+        let depth = r#"{"e":"depthUpdate","E":1723960451595,"T":1723960451590,"s":"BTCUSDT","U":157,"u":160,"pu":149,"b":[["58000.00","10"]],"a":[["58000.10","0"]]}"#;
+        match serde_json::from_str::<MarketEvent>(depth).unwrap() {
+            MarketEvent::DepthUpdate(e) => {
+                assert_eq!(Some(149), e.prev_final_update_id);
+                assert_eq!(vec![PriceLevel { price: dec!(58000), qty: dec!(10) }], e.bids);
+                assert_eq!(dec!(0), e.asks[0].qty);
+            }
+            other => panic!("应该是depthUpdate:{:?}", other)
+        }
+
+        let spot_book_ticker = r#"{"u":400900217,"s":"BNBUSDT","b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}"#;
+        match serde_json::from_str::<MarketEvent>(spot_book_ticker).unwrap() {
+            MarketEvent::BookTicker(e) => {
+                assert_eq!(None, e.event_time);
+                assert_eq!(dec!(25.3652), e.ask_price);
+            }
+            other => panic!("现货的bookTicker没有e字段:{:?}", other)
+        }
+
+        let kline = r#"{"e":"kline","E":1723960451595,"s":"BTCUSDT","k":{"t":1723960440000,"T":1723960499999,"s":"BTCUSDT","i":"1m","f":100,"L":200,"o":"0.0010","c":"0.0020","h":"0.0025","l":"0.0015","v":"1000","n":100,"x":false,"q":"1.0000","V":"500","Q":"0.500","B":"123456"}}"#;
+        match serde_json::from_str::<MarketEvent>(kline).unwrap() {
+            MarketEvent::Kline(e) => {
+                assert_eq!("1m", e.kline.interval);
+                assert_eq!(dec!(0.0025), e.kline.high);
+                assert!(!e.kline.closed);
+            }
+            other => panic!("应该是kline:{:?}", other)
+        }
+
+        let force_order = r#"{"e":"forceOrder","E":1568014460893,"o":{"s":"BTCUSDT","S":"SELL","o":"LIMIT","f":"IOC","q":"0.014","p":"9910","ap":"9910","X":"FILLED","l":"0.014","z":"0.014","T":1568014460893}}"#;
+        match serde_json::from_str::<MarketEvent>(force_order).unwrap() {
+            MarketEvent::ForceOrder(e) => {
+                assert_eq!(OrderSide::Sell, e.order.side);
+                assert_eq!(OrderStatus::Filled, e.order.status);
+            }
+            other => panic!("应该是forceOrder:{:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_parse_market_event_batch() {
+        let mark_prices = r#"[{"e":"markPriceUpdate","E":1562305380000,"s":"BTCUSDT","p":"11794.15000000","i":"11784.62659091","P":"11784.25641265","r":"0.00038167","T":1562306400000}]"#;
+        match serde_json::from_str::<MarketEvent>(mark_prices).unwrap() {
+            MarketEvent::Batch(events) => {
+                assert!(matches!(&events[0], MarketEvent::MarkPrice(e) if e.funding_rate == dec!(0.00038167)));
+            }
+            other => panic!("数组应该是Batch:{:?}", other)
+        }
+
+        let unknown = r#"{"e":"24hrMiniTicker","E":1672515782136,"s":"BNBBTC"}"#;
+        assert!(matches!(serde_json::from_str::<MarketEvent>(unknown).unwrap(), MarketEvent::Unknown(_)), "不认识的推送不报错");
+    }
+
+    #[test]
+    fn test_parse_user_data_event() {
+        let order = r#"{"e":"ORDER_TRADE_UPDATE","fs":"UM","E":1568879465651,"T":1568879465650,"i":"","o":{"s":"BTCUSDT","c":"TEST","S":"SELL","o":"TRAILING_STOP_MARKET","f":"GTC","q":"0.001","p":"0","ap":"0","sp":"7103.04","x":"NEW","X":"NEW","i":8886774,"l":"0","z":"0","L":"0","T":1568879465650,"t":0,"b":"0","a":"9.91","m":false,"R":false,"ps":"LONG","rp":"0","st":"C_TAKE_PROFIT","si":12893,"V":"EXPIRE_TAKER","gtd":0}}"#;
+        match serde_json::from_str::<UserDataEvent>(order).unwrap() {
+            UserDataEvent::OrderTradeUpdate(e) => {
+                assert_eq!("UM", e.business_unit);
+                assert_eq!(OrderSide::Sell, e.order.side);
+                assert_eq!(OrderStatus::New, e.order.status);
+                assert_eq!(None, e.order.commission, "没有成交的时候没有手续费");
+                assert_eq!(PositionSide::Long, e.order.position_side);
+            }
+            other => panic!("应该是ORDER_TRADE_UPDATE:{:?}", other)
+        }
+
+        let account = r#"{"e":"ACCOUNT_UPDATE","fs":"UM","E":1564745798939,"T":1564745798938,"i":"","a":{"m":"ORDER","B":[{"a":"USDT","wb":"122624.12345678","cw":"100.12345678","bc":"50.12345678"}],"P":[{"s":"BTCUSDT","pa":"0","ep":"0.00000","cr":"200","up":"0","ps":"BOTH","bep":"0.0"}]}}"#;
+        match serde_json::from_str::<UserDataEvent>(account).unwrap() {
+            UserDataEvent::AccountUpdate(e) => {
+                assert_eq!(dec!(122624.12345678), e.update.balances[0].wallet_balance);
+                assert_eq!(dec!(200), e.update.positions[0].accumulated_realized);
+            }
+            other => panic!("应该是ACCOUNT_UPDATE:{:?}", other)
+        }
+
+        let risk = r#"{"e":"riskLevelChange","E":1587727187525,"u":"1.99999999","s":"MARGIN_CALL","eq":"30.23416728","ae":"30.23416728","m":"15.11708371"}"#;
+        assert!(matches!(serde_json::from_str::<UserDataEvent>(risk).unwrap(), UserDataEvent::RiskLevelChange(e) if e.uni_mmr == dec!(1.99999999)));
+
+        let balance = r#"{"e":"outboundAccountPosition","E":1564034571105,"u":1564034571073,"U":1027053479517,"B":[{"a":"ETH","f":"10000.000000","l":"0.000000"}]}"#;
+        assert!(matches!(serde_json::from_str::<UserDataEvent>(balance).unwrap(), UserDataEvent::OutboundAccountPosition(e) if e.balances[0].free == dec!(10000)));
+
+        let expired = r#"{"e":"listenKeyExpired","E":1576653824250,"listenKey":"abc"}"#;
+        assert_eq!(UserDataEvent::ListenKeyExpired, serde_json::from_str::<UserDataEvent>(expired).unwrap());
     }
 }
@@ -0,0 +1,362 @@
+use crate::binance::bn_commands::{BNCommand, GetCommand};
+use crate::binance::bn_models::{BinanceBase, BinanceContext, BinancePath, DepthQuery, DepthSnapshot, DepthUpdateEvent, MarketEvent, NormalAPI, PriceLevel, UmAPI};
+use crate::binance::bn_ws_stream::StreamEvent;
+use crate::errors::BraavosError;
+use crate::models::Decimal;
+use log::{debug, warn};
+use rust_decimal_macros::dec;
+use std::collections::{BTreeMap, VecDeque};
+
+/** 快照还没回来的时候最多缓存多少条增量，超过了丢掉最早的，反正快照会比它们新
+ */
+const MAX_BUFFERED: usize = 10_000;
+
+/** 现货和U本位合约增量深度的连续性规则不一样
+* 现货：第一条要满足 U <= lastUpdateId+1 <= u，后面每条的U等于上一条的u+1
+* 合约：第一条要满足 U <= lastUpdateId <= u，后面每条的pu等于上一条的u
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookMarket {
+    Spot,
+    UsdM,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookSide {
+    Bid,
+    Ask,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookStatus {
+    Synced,
+    NeedSnapshot, //还没有快照或者发现了断档，调用方需要调用resync
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SyncState {
+    Unsynced,
+    Snapshot(u64), //加载了快照，还在等第一条能接上的增量
+    Live(u64),     //上一条增量的u
+}
+
+/** 本地的订单簿。REST快照加上<symbol>@depth的增量，增量断档了就重新拉快照
+*
+* ```ignore
+* let mut book = OrderBook::new(BookMarket::Spot, "BTCUSDT");
+* while let Some(event) = events.recv().await {
+*     if book.on_stream_event(&event) == BookStatus::NeedSnapshot {
+*         book.resync(&context, 1000).await?;
+*     }
+* }
+* ```
+*/
+#[derive(Debug)]
+pub struct OrderBook {
+    market: BookMarket,
+    symbol: String,
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+    state: SyncState,
+    buffer: VecDeque<DepthUpdateEvent>,
+}
+
+impl OrderBook {
+    pub fn new(market: BookMarket, symbol: &str) -> OrderBook {
+        OrderBook {
+            market,
+            symbol: symbol.to_uppercase(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            state: SyncState::Unsynced,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.state != SyncState::Unsynced
+    }
+
+    /** 最后一次更新的id，没有同步的时候是None
+     */
+    pub fn last_update_id(&self) -> Option<u64> {
+        match self.state {
+            SyncState::Unsynced => None,
+            SyncState::Snapshot(id) | SyncState::Live(id) => Some(id),
+        }
+    }
+
+    /** 断线之后中间的增量都丢了，清空等待新的快照
+     */
+    pub fn reset(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+        self.buffer.clear();
+        self.state = SyncState::Unsynced;
+    }
+
+    /** 直接把stream client的事件丢进来，只处理这个symbol的增量和断线
+     */
+    pub fn on_stream_event(&mut self, event: &StreamEvent) -> BookStatus {
+        match event {
+            StreamEvent::Disconnected(_) => {
+                self.reset();
+                BookStatus::NeedSnapshot
+            }
+            StreamEvent::Data { event: MarketEvent::DepthUpdate(update), .. } if update.symbol == self.symbol => {
+                self.on_update(update.clone())
+            }
+            _ => self.status(),
+        }
+    }
+
+    pub fn on_update(&mut self, update: DepthUpdateEvent) -> BookStatus {
+        if self.state == SyncState::Unsynced {
+            if self.buffer.len() >= MAX_BUFFERED {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(update);
+            return BookStatus::NeedSnapshot;
+        }
+        self.apply_update(update)
+    }
+
+    /** 加载快照，然后把缓存的增量按照规则接上去。接不上的时候，这一条和后面的增量留在缓存里面，等下一个快照
+     */
+    pub fn apply_snapshot(&mut self, snapshot: DepthSnapshot) -> BookStatus {
+        self.bids = snapshot.bids.iter().filter(|l| l.qty > dec!(0)).map(|l| (l.price, l.qty)).collect();
+        self.asks = snapshot.asks.iter().filter(|l| l.qty > dec!(0)).map(|l| (l.price, l.qty)).collect();
+        self.state = SyncState::Snapshot(snapshot.last_update_id);
+        debug!("{} snapshot loaded, lastUpdateId:{}, {} buffered", self.symbol, snapshot.last_update_id, self.buffer.len());
+
+        let mut buffered = std::mem::take(&mut self.buffer).into_iter();
+        while let Some(update) = buffered.next() {
+            if self.apply_update(update) == BookStatus::NeedSnapshot {
+                self.buffer.extend(buffered);
+                return BookStatus::NeedSnapshot;
+            }
+        }
+        self.status()
+    }
+
+    /** 拉REST快照并且接上缓存的增量。limit越大权重越高，见DepthAPI的weight
+     */
+    pub async fn resync(&mut self, context: &BinanceContext, limit: u16) -> Result<BookStatus, BraavosError> {
+        let snapshot = fetch_depth_snapshot(context, self.market, &self.symbol, limit).await?;
+        Ok(self.apply_snapshot(snapshot))
+    }
+
+    fn status(&self) -> BookStatus {
+        if self.is_synced() { BookStatus::Synced } else { BookStatus::NeedSnapshot }
+    }
+
+    fn apply_update(&mut self, update: DepthUpdateEvent) -> BookStatus {
+        let accepted = match (self.state, self.market) {
+            (SyncState::Unsynced, _) => false,
+            (SyncState::Snapshot(id), BookMarket::Spot) => {
+                if update.final_update_id <= id {
+                    return BookStatus::Synced; //比快照旧，丢掉
+                }
+                update.first_update_id <= id + 1
+            }
+            (SyncState::Snapshot(id), BookMarket::UsdM) => {
+                if update.final_update_id < id {
+                    return BookStatus::Synced;
+                }
+                update.first_update_id <= id
+            }
+            (SyncState::Live(prev), BookMarket::Spot) => update.first_update_id == prev + 1,
+            (SyncState::Live(prev), BookMarket::UsdM) => update.prev_final_update_id == Some(prev),
+        };
+
+        if !accepted {
+            warn!("{} depth gap, last:{:?}, update U:{} u:{} pu:{:?}, resync",
+                self.symbol, self.last_update_id(), update.first_update_id, update.final_update_id, update.prev_final_update_id);
+            self.reset();
+            self.buffer.push_back(update);
+            return BookStatus::NeedSnapshot;
+        }
+
+        apply_levels(&mut self.bids, &update.bids);
+        apply_levels(&mut self.asks, &update.asks);
+        self.state = SyncState::Live(update.final_update_id);
+        BookStatus::Synced
+    }
+
+    pub fn best_bid(&self) -> Option<PriceLevel> {
+        self.bids.iter().next_back().map(|(price, qty)| PriceLevel { price: *price, qty: *qty })
+    }
+
+    pub fn best_ask(&self) -> Option<PriceLevel> {
+        self.asks.iter().next().map(|(price, qty)| PriceLevel { price: *price, qty: *qty })
+    }
+
+    pub fn mid(&self) -> Option<Decimal> {
+        Some((self.best_bid()?.price + self.best_ask()?.price) / dec!(2))
+    }
+
+    pub fn spread(&self) -> Option<Decimal> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+
+    /** 某一个价格上挂的数量
+     */
+    pub fn qty_at(&self, side: BookSide, price: Decimal) -> Decimal {
+        let levels = match side {
+            BookSide::Bid => &self.bids,
+            BookSide::Ask => &self.asks,
+        };
+        levels.get(&price).copied().unwrap_or(dec!(0))
+    }
+
+    /** 从最优价到price(包含)一共挂了多少，买单是价格>=price的，卖单是价格<=price的
+     */
+    pub fn depth_to(&self, side: BookSide, price: Decimal) -> Decimal {
+        match side {
+            BookSide::Bid => self.bids.range(price..).map(|(_, qty)| *qty).sum(),
+            BookSide::Ask => self.asks.range(..=price).map(|(_, qty)| *qty).sum(),
+        }
+    }
+
+    /** 最优的n档，买单从高到低，卖单从低到高
+     */
+    pub fn levels(&self, side: BookSide, n: usize) -> Vec<PriceLevel> {
+        let to_level = |(price, qty): (&Decimal, &Decimal)| PriceLevel { price: *price, qty: *qty };
+        match side {
+            BookSide::Bid => self.bids.iter().rev().take(n).map(to_level).collect(),
+            BookSide::Ask => self.asks.iter().take(n).map(to_level).collect(),
+        }
+    }
+}
+
+fn apply_levels(book: &mut BTreeMap<Decimal, Decimal>, levels: &[PriceLevel]) {
+    for level in levels {
+        if level.qty == dec!(0) {
+            book.remove(&level.price);
+        } else {
+            book.insert(level.price, level.qty);
+        }
+    }
+}
+
+pub async fn fetch_depth_snapshot(context: &BinanceContext, market: BookMarket, symbol: &str, limit: u16) -> Result<DepthSnapshot, BraavosError> {
+    let info = || match market {
+        BookMarket::Spot => context.command_info(BinanceBase::Normal, BinancePath::Normal(NormalAPI::DepthAPI)),
+        BookMarket::UsdM => context.command_info(BinanceBase::UsdMFutures, BinancePath::FAPI(UmAPI::DepthAPI)),
+    };
+    let query = DepthQuery { symbol: String::from(symbol), limit };
+    let get = GetCommand::<DepthQuery, DepthSnapshot> { phantom: Default::default() };
+    get.execute_with_retry(|| (info(), Some(query.clone()))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+
+    fn update(first: u64, last: u64, prev: Option<u64>, bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) -> DepthUpdateEvent {
+        DepthUpdateEvent {
+            event_time: 1723960451595,
+            transaction_time: None,
+            symbol: "BTCUSDT".to_string(),
+            first_update_id: first,
+            final_update_id: last,
+            prev_final_update_id: prev,
+            bids: bids.iter().map(|l| PriceLevel::from(*l)).collect(),
+            asks: asks.iter().map(|l| PriceLevel::from(*l)).collect(),
+        }
+    }
+
+    fn snapshot(last_update_id: u64) -> DepthSnapshot {
+        DepthSnapshot {
+            last_update_id,
+            bids: vec![PriceLevel::from((dec!(100), dec!(1))), PriceLevel::from((dec!(99), dec!(2)))],
+            asks: vec![PriceLevel::from((dec!(101), dec!(1))), PriceLevel::from((dec!(102), dec!(3)))],
+        }
+    }
+
+    #[test]
+    fn test_spot_snapshot_and_diff() {
+        let mut book = OrderBook::new(BookMarket::Spot, "btcusdt");
+        assert_eq!(BookStatus::NeedSnapshot, book.on_update(update(90, 95, None, &[(dec!(98), dec!(9))], &[])));
+        assert_eq!(BookStatus::NeedSnapshot, book.on_update(update(96, 105, None, &[(dec!(100), dec!(0))], &[(dec!(101), dec!(5))])));
+        assert_eq!(None, book.best_bid(), "没有快照之前是空的");
+
+        assert_eq!(BookStatus::Synced, book.apply_snapshot(snapshot(100)));
+        assert_eq!(Some(105), book.last_update_id(), "u<=lastUpdateId的丢掉，U<=lastUpdateId+1<=u的接上");
+        assert_eq!(Some(PriceLevel::from((dec!(99), dec!(2)))), book.best_bid(), "数量是0的档位删掉");
+        assert_eq!(dec!(0), book.qty_at(BookSide::Bid, dec!(98)), "比快照旧的增量不能用");
+        assert_eq!(dec!(5), book.qty_at(BookSide::Ask, dec!(101)));
+
+        assert_eq!(BookStatus::Synced, book.on_update(update(106, 107, None, &[(dec!(100.5), dec!(4))], &[])));
+        assert_eq!(Some(dec!(100.75)), book.mid());
+        assert_eq!(Some(dec!(0.5)), book.spread());
+        assert_eq!(dec!(6), book.depth_to(BookSide::Bid, dec!(99)));
+        assert_eq!(dec!(8), book.depth_to(BookSide::Ask, dec!(102)));
+        assert_eq!(vec![dec!(100.5), dec!(99)], book.levels(BookSide::Bid, 5).iter().map(|l| l.price).collect::<Vec<_>>());
+
+        assert_eq!(BookStatus::NeedSnapshot, book.on_update(update(109, 110, None, &[], &[])), "U不等于上一条u+1就是断档");
+        assert!(!book.is_synced());
+        assert_eq!(None, book.best_ask());
+
+        assert_eq!(BookStatus::NeedSnapshot, book.apply_snapshot(snapshot(105)), "快照比缓存的增量还旧，要重新拉");
+        assert_eq!(BookStatus::Synced, book.apply_snapshot(snapshot(110)), "缓存的增量都比快照旧，等下一条");
+        assert_eq!(BookStatus::Synced, book.on_update(update(111, 112, None, &[], &[])));
+        assert_eq!(Some(112), book.last_update_id());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_buffer_after_gap() {
+        let mut book = OrderBook::new(BookMarket::Spot, "BTCUSDT");
+        book.on_update(update(109, 110, None, &[], &[]));
+        book.on_update(update(111, 112, None, &[], &[(dec!(101), dec!(4))]));
+        book.on_update(update(113, 115, None, &[(dec!(100), dec!(6))], &[]));
+
+        assert_eq!(BookStatus::NeedSnapshot, book.apply_snapshot(snapshot(105)), "快照太旧");
+        assert_eq!(BookStatus::Synced, book.apply_snapshot(snapshot(110)), "后面的增量还在缓存里面，不用再等");
+        assert_eq!(Some(115), book.last_update_id());
+        assert_eq!(dec!(4), book.qty_at(BookSide::Ask, dec!(101)));
+        assert_eq!(dec!(6), book.qty_at(BookSide::Bid, dec!(100)));
+    }
+
+    #[test]
+    fn test_usdm_pu_rule() {
+        let mut book = OrderBook::new(BookMarket::UsdM, "BTCUSDT");
+        book.on_update(update(95, 100, Some(94), &[], &[]));
+        book.on_update(update(101, 104, Some(100), &[(dec!(100), dec!(7))], &[]));
+        assert_eq!(BookStatus::Synced, book.apply_snapshot(snapshot(100)), "合约u>=lastUpdateId的都要，第一条U<=lastUpdateId");
+        assert_eq!(Some(104), book.last_update_id());
+        assert_eq!(dec!(7), book.qty_at(BookSide::Bid, dec!(100)));
+
+        assert_eq!(BookStatus::Synced, book.on_update(update(110, 120, Some(104), &[], &[])), "合约只看pu，U不连续没关系");
+        assert_eq!(BookStatus::NeedSnapshot, book.on_update(update(125, 130, Some(121), &[], &[])));
+    }
+
+    #[test]
+    fn test_disconnect_resets_book() {
+        let mut book = OrderBook::new(BookMarket::Spot, "BTCUSDT");
+        book.apply_snapshot(snapshot(100));
+        let other = StreamEvent::Data { stream: "ethusdt@depth".to_string(), event: MarketEvent::DepthUpdate(DepthUpdateEvent { symbol: "ETHUSDT".to_string(), ..update(1, 2, None, &[], &[]) }) };
+        assert_eq!(BookStatus::Synced, book.on_stream_event(&other), "别的symbol不管");
+        assert_eq!(BookStatus::NeedSnapshot, book.on_stream_event(&StreamEvent::Disconnected("closed".to_string())));
+        assert!(!book.is_synced());
+    }
+
+    #[tokio::test]
+    async fn test_resync_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/depth",
+                     vec![MockResponse::json(200, r#"{"lastUpdateId":100,"bids":[["100.00","1.0"]],"asks":[["101.00","2.0"]]}"#)]);
+        let mut book = OrderBook::new(BookMarket::Spot, "BTCUSDT");
+        book.on_update(update(99, 102, None, &[], &[(dec!(101), dec!(3))]));
+
+        assert_eq!(BookStatus::Synced, book.resync(&server.context(), 1000).await.unwrap());
+        assert_eq!(Some(PriceLevel::from((dec!(101), dec!(3)))), book.best_ask());
+        let request = &server.requests()[0];
+        assert_eq!(Some("symbol=BTCUSDT&limit=1000".to_string()), request.query);
+    }
+}
@@ -0,0 +1,806 @@
+use crate::binance::bn_commands::{BNCommand, DeleteCommand, GetCommand, PostCommand, DEFAULT_CONTEXT};
+use crate::binance::bn_symbol_rules::{SymbolMarket, SymbolRegistry, SymbolRules};
+use crate::binance::bn_models::{BinanceBase, BinanceContext, BinancePath, CancelOrderRequest, CMOrderResponse, CMTrade, MarginCancelResponse, MarginOrder, MarginOrderRequest, MarginOrderResponse, MarginTrade, OrderSide, OrderType, PMHistoryQuery, PMOrderRequest, PmAPI, PositionSide, SideEffectType, TimeInForce, UMIncome, UMOrderResponse, UMTrade, WithTimeStamp};
+use crate::errors::{BinanceErrorCode, BraavosError, OrderRuleViolation};
+use crate::models::{Decimal, UnixTimeStamp};
+use crate::retry::{RetryClassifier, RetryKind};
+use crate::settings::Account;
+use crate::utils::SnowyFlakeWrapper;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, LazyLock};
+
+static ORDER_ID: LazyLock<SnowyFlakeWrapper> = LazyLock::new(SnowyFlakeWrapper::new);
+
+/** 结果未知的订单，查几次都不存在才重新提交
+ */
+const ORDER_LOOKUPS: u32 = 2;
+
+/** 下单用的newClientOrderId。下单的结果未知的时候，用这个id查单，确认订单不存在才重新提交
+ */
+pub fn new_client_order_id() -> String {
+    ORDER_ID.next_id_string()
+}
+
+/** 下单请求的交易对和clientOrderId，查单的时候用
+ */
+pub(crate) trait ClientOrder {
+    fn symbol(&self) -> &str;
+    fn client_order_id(&self) -> &str;
+}
+
+impl ClientOrder for PMOrderRequest {
+    fn symbol(&self) -> &str { &self.symbol }
+    fn client_order_id(&self) -> &str { &self.new_client_order_id }
+}
+
+impl ClientOrder for MarginOrderRequest {
+    fn symbol(&self) -> &str { &self.symbol }
+    fn client_order_id(&self) -> &str { &self.new_client_order_id }
+}
+
+impl PMOrderRequest {
+    pub fn limit(symbol: &str, side: OrderSide, quantity: Decimal, price: Decimal, time_in_force: TimeInForce) -> PMOrderRequest {
+        PMOrderRequest {
+            symbol: String::from(symbol),
+            side,
+            order_type: OrderType::Limit,
+            quantity,
+            price: Some(price),
+            time_in_force: Some(time_in_force),
+            reduce_only: None,
+            position_side: None,
+            new_client_order_id: new_client_order_id(),
+        }
+    }
+
+    pub fn market(symbol: &str, side: OrderSide, quantity: Decimal) -> PMOrderRequest {
+        PMOrderRequest {
+            symbol: String::from(symbol),
+            side,
+            order_type: OrderType::Market,
+            quantity,
+            price: None,
+            time_in_force: None,
+            reduce_only: None,
+            position_side: None,
+            new_client_order_id: new_client_order_id(),
+        }
+    }
+
+    pub fn reduce_only(mut self) -> PMOrderRequest {
+        self.reduce_only = Some(true);
+        self
+    }
+
+    /** 双向持仓的时候需要指定LONG或者SHORT
+     */
+    pub fn position_side(mut self, position_side: PositionSide) -> PMOrderRequest {
+        self.position_side = Some(position_side);
+        self
+    }
+
+    /** 按照交易对的规则调整价格和数量，调整之后还是不符合的话返回错误，订单不会发出去
+     */
+    pub fn apply_rules(mut self, rules: &SymbolRules) -> Result<PMOrderRequest, BraavosError> {
+        let market_order = self.order_type == OrderType::Market;
+        let (price, quantity) = rules.normalize(self.side, self.price, self.quantity, market_order)?;
+        self.price = price;
+        self.quantity = quantity;
+        Ok(self)
+    }
+}
+
+impl MarginOrderRequest {
+    pub fn limit(symbol: &str, side: OrderSide, quantity: Decimal, price: Decimal, time_in_force: TimeInForce) -> MarginOrderRequest {
+        MarginOrderRequest {
+            symbol: String::from(symbol),
+            side,
+            order_type: OrderType::Limit,
+            quantity: Some(quantity),
+            quote_order_qty: None,
+            price: Some(price),
+            stop_price: None,
+            time_in_force: Some(time_in_force),
+            side_effect_type: None,
+            new_client_order_id: new_client_order_id(),
+        }
+    }
+
+    pub fn market(symbol: &str, side: OrderSide, quantity: Decimal) -> MarginOrderRequest {
+        MarginOrderRequest {
+            symbol: String::from(symbol),
+            side,
+            order_type: OrderType::Market,
+            quantity: Some(quantity),
+            quote_order_qty: None,
+            price: None,
+            stop_price: None,
+            time_in_force: None,
+            side_effect_type: None,
+            new_client_order_id: new_client_order_id(),
+        }
+    }
+
+    pub fn side_effect(mut self, side_effect_type: SideEffectType) -> MarginOrderRequest {
+        self.side_effect_type = Some(side_effect_type);
+        self
+    }
+
+    /** 用quoteOrderQty下单的市价单没有数量，不检查
+     */
+    pub fn apply_rules(mut self, rules: &SymbolRules) -> Result<MarginOrderRequest, BraavosError> {
+        if let Some(quantity) = self.quantity {
+            let market_order = self.order_type == OrderType::Market;
+            let (price, quantity) = rules.normalize(self.side, self.price, quantity, market_order)?;
+            self.price = price;
+            self.quantity = Some(quantity);
+        }
+        Ok(self)
+    }
+}
+
+/** 统一账户下单，UM合约，CM合约和杠杆。有registry的话，下单之前按照交易对的规则调整价格和数量，
+* 杠杆用现货的规则
+*/
+pub struct PMOrderExecutor {
+    pub account: Account,
+    pub context: BinanceContext,
+    pub registry: Option<Arc<SymbolRegistry>>,
+}
+
+impl PMOrderExecutor {
+    pub fn new(account: Account) -> PMOrderExecutor {
+        let context = DEFAULT_CONTEXT.clone();
+        let registry = context.symbol_registry.clone();
+        PMOrderExecutor::with_context(account, context).with_registry(registry)
+    }
+
+    pub fn with_context(account: Account, context: BinanceContext) -> PMOrderExecutor {
+        PMOrderExecutor { account, context, registry: None }
+    }
+
+    pub fn with_registry(mut self, registry: Arc<SymbolRegistry>) -> PMOrderExecutor {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub async fn place_um_order(&self, request: &PMOrderRequest) -> Result<UMOrderResponse, BraavosError> {
+        let request = self.normalize(SymbolMarket::UsdM, request, PMOrderRequest::apply_rules).await?;
+        self.place::<_, _, UMOrderResponse>(PmAPI::UMOrderAPI, &request).await
+    }
+
+    pub async fn place_cm_order(&self, request: &PMOrderRequest) -> Result<CMOrderResponse, BraavosError> {
+        let request = self.normalize(SymbolMarket::CoinM, request, PMOrderRequest::apply_rules).await?;
+        self.place::<_, _, CMOrderResponse>(PmAPI::CMOrderAPI, &request).await
+    }
+
+    pub async fn place_margin_order(&self, request: &MarginOrderRequest) -> Result<MarginOrderResponse, BraavosError> {
+        let request = self.normalize(SymbolMarket::Spot, request, MarginOrderRequest::apply_rules).await?;
+        self.place::<_, _, MarginOrder>(PmAPI::MarginOrderAPI, &request).await
+    }
+
+    pub async fn query_um_order(&self, request: &CancelOrderRequest) -> Result<UMOrderResponse, BraavosError> {
+        self.query_order(PmAPI::UMOrderAPI, request).await
+    }
+
+    pub async fn query_cm_order(&self, request: &CancelOrderRequest) -> Result<CMOrderResponse, BraavosError> {
+        self.query_order(PmAPI::CMOrderAPI, request).await
+    }
+
+    pub async fn query_margin_order(&self, request: &CancelOrderRequest) -> Result<MarginOrder, BraavosError> {
+        self.query_order(PmAPI::MarginOrderAPI, request).await
+    }
+
+    pub async fn cancel_um_order(&self, request: &CancelOrderRequest) -> Result<UMOrderResponse, BraavosError> {
+        self.cancel(PmAPI::UMOrderAPI, request).await
+    }
+
+    pub async fn cancel_cm_order(&self, request: &CancelOrderRequest) -> Result<CMOrderResponse, BraavosError> {
+        self.cancel(PmAPI::CMOrderAPI, request).await
+    }
+
+    pub async fn cancel_margin_order(&self, request: &CancelOrderRequest) -> Result<MarginCancelResponse, BraavosError> {
+        self.cancel(PmAPI::MarginOrderAPI, request).await
+    }
+
+    /** 没有registry的话原样返回。找不到交易对的话可能是新上线的，或者这个市场还没load过，重新load一次再找
+     */
+    async fn normalize<R>(&self, market: SymbolMarket, request: &R, apply_rules: fn(R, &SymbolRules) -> Result<R, BraavosError>) -> Result<R, BraavosError>
+    where
+        R: Clone + ClientOrder,
+    {
+        let Some(registry) = &self.registry else {
+            return Ok(request.clone());
+        };
+        let rules = match registry.rules(market, request.symbol()) {
+            Err(OrderRuleViolation::UnknownSymbol(_)) => {
+                registry.reload(&self.context, market).await?;
+                registry.rules(market, request.symbol())?
+            }
+            rules => rules?,
+        };
+        apply_rules(request.clone(), &rules)
+    }
+
+    /** 下单不是幂等的，只重试肯定没有执行的错误。超时，5xx这种结果未知的，先用clientOrderId查单：
+    * 查到了就用查单的结果，确认订单不存在才重新提交，见find_order。Q是查单返回的类型
+    */
+    async fn place<R, U, Q>(&self, api: PmAPI, request: &R) -> Result<U, BraavosError>
+    where
+        R: Display + Clone + ClientOrder,
+        U: DeserializeOwned,
+        Q: DeserializeOwned + Into<U>,
+    {
+        self.context.ensure_time_synced().await;
+        info!("{} place order {:?}:{}", self.account.name, api, request);
+
+        let post = PostCommand::<WithTimeStamp<R>, U> { phantom: Default::default(), idempotent: false };
+        let policy = &self.context.retry_policy;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match post.execute_with_retry(|| {
+                (self.context.command_info_with_security(BinanceBase::PortfolioMargin,
+                                                         BinancePath::PAPI(api),
+                                                         &self.account.api_key,
+                                                         &self.account.secret),
+                 Some(WithTimeStamp::new(request.clone(), self.context.timestamp_request(&self.account))))
+            }).await {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            if self.context.retry_kind(&err) != RetryKind::OutcomeUnknown {
+                return Err(err);
+            }
+
+            warn!("{} order {} outcome unknown, query it:{}", self.account.name, request.client_order_id(), err);
+            match self.find_order::<R, Q>(api, request, attempt).await {
+                Ok(Some(order)) => return Ok(order.into()),
+                Ok(None) if attempt < policy.max_attempts => {
+                    warn!("{} order {} not found, place again", self.account.name, request.client_order_id());
+                }
+                Ok(None) => {
+                    error!("{} order {} not found, give up", self.account.name, request.client_order_id());
+                    return Err(err);
+                }
+                Err(query_err) => {
+                    error!("{} order {} outcome unknown, query failed:{}", self.account.name, request.client_order_id(), query_err);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /** 查结果未知的订单。原来的请求可能还在币安那边处理，马上查会返回-2013，所以每次查之前都等一下，
+    * 返回-2013的话再等再查，ORDER_LOOKUPS次都不存在才认为没有下单成功
+    */
+    async fn find_order<R: ClientOrder, Q: DeserializeOwned>(&self, api: PmAPI, request: &R, attempt: u32) -> Result<Option<Q>, BraavosError> {
+        let query = CancelOrderRequest {
+            symbol: String::from(request.symbol()),
+            order_id: None,
+            orig_client_order_id: Some(String::from(request.client_order_id())),
+        };
+        for lookup in 0..ORDER_LOOKUPS {
+            tokio::time::sleep(self.context.retry_policy.backoff(attempt + lookup)).await;
+            match self.query_order::<Q>(api, &query).await {
+                Ok(order) => return Ok(Some(order)),
+                Err(err) if err.binance_code() == Some(BinanceErrorCode::NoSuchOrder) => {
+                    warn!("{} order {} not found yet:{}", self.account.name, request.client_order_id(), err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /** 查单和下单是同一个路径，只是用GET
+     */
+    async fn query_order<U: DeserializeOwned>(&self, api: PmAPI, request: &CancelOrderRequest) -> Result<U, BraavosError> {
+        self.context.ensure_time_synced().await;
+        let get = GetCommand::<WithTimeStamp<CancelOrderRequest>, U> { phantom: Default::default() };
+        get.execute_with_retry(|| {
+            (self.context.command_info_with_security(BinanceBase::PortfolioMargin,
+                                                     BinancePath::PAPI(api),
+                                                     &self.account.api_key,
+                                                     &self.account.secret),
+             Some(WithTimeStamp::new(request.clone(), self.context.timestamp_request(&self.account))))
+        }).await
+    }
+
+    /** 撤单和下单是同一个路径，只是用DELETE
+     */
+    async fn cancel<U: DeserializeOwned>(&self, api: PmAPI, request: &CancelOrderRequest) -> Result<U, BraavosError> {
+        self.context.ensure_time_synced().await;
+        info!("{} cancel order {:?}:{}", self.account.name, api, request);
+
+        let delete = DeleteCommand::<WithTimeStamp<CancelOrderRequest>, U> { phantom: Default::default() };
+        delete.execute_with_retry(|| {
+            (self.context.command_info_with_security(BinanceBase::PortfolioMargin,
+                                                     BinancePath::PAPI(api),
+                                                     &self.account.api_key,
+                                                     &self.account.secret),
+             Some(WithTimeStamp::new(request.clone(), self.context.timestamp_request(&self.account))))
+        }).await
+    }
+}
+
+pub(crate) const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/** 翻页用的，成交和订单的id和时间
+ */
+pub(crate) trait HistoryRecord {
+    fn record_id(&self) -> u64;
+    fn record_time(&self) -> UnixTimeStamp;
+}
+
+impl HistoryRecord for UMOrderResponse {
+    fn record_id(&self) -> u64 { self.order_id }
+    fn record_time(&self) -> UnixTimeStamp { self.time.unwrap_or(self.update_time) }
+}
+
+impl HistoryRecord for CMOrderResponse {
+    fn record_id(&self) -> u64 { self.order_id }
+    fn record_time(&self) -> UnixTimeStamp { self.time.unwrap_or(self.update_time) }
+}
+
+impl HistoryRecord for MarginOrder {
+    fn record_id(&self) -> u64 { self.order_id }
+    fn record_time(&self) -> UnixTimeStamp { self.time }
+}
+
+impl HistoryRecord for UMTrade {
+    fn record_id(&self) -> u64 { self.id }
+    fn record_time(&self) -> UnixTimeStamp { self.time }
+}
+
+impl HistoryRecord for CMTrade {
+    fn record_id(&self) -> u64 { self.id }
+    fn record_time(&self) -> UnixTimeStamp { self.time }
+}
+
+impl HistoryRecord for MarginTrade {
+    fn record_id(&self) -> u64 { self.id }
+    fn record_time(&self) -> UnixTimeStamp { self.time }
+}
+
+impl HistoryRecord for UMIncome {
+    fn record_id(&self) -> u64 { self.tran_id }
+    fn record_time(&self) -> UnixTimeStamp { self.time }
+}
+
+/** 每个历史接口一次最多能查多长时间，一页最多多少条
+ */
+fn page_rule(api: PmAPI) -> (u64, usize) {
+    match api {
+        PmAPI::MarginAllOrdersAPI => (DAY_MS, 500),
+        PmAPI::MarginUserTradesAPI => (DAY_MS, 1000),
+        PmAPI::CMAllOrdersAPI => (7 * DAY_MS, 100),
+        _ => (7 * DAY_MS, 1000),
+    }
+}
+
+/** 按照时间翻页。[start,end]按照window切开，每个窗口里面如果一页满了，就从这一页最后的时间继续查，用id去重。
+* 一页都是同一毫秒的话，按时间没办法继续往后翻，跳过这一毫秒会丢记录，这时候返回错误，需要改用fromId翻页
+*/
+pub(crate) async fn paginate_by_time<U, F, Fut>(start: UnixTimeStamp, end: UnixTimeStamp, window: u64, limit: usize, fetch: F) -> Result<Vec<U>, BraavosError>
+where
+    U: HistoryRecord,
+    F: Fn(UnixTimeStamp, UnixTimeStamp) -> Fut,
+    Fut: Future<Output=Result<Vec<U>, BraavosError>>,
+{
+    let mut result = vec![];
+    let mut seen = HashSet::new();
+    let mut window_start = start;
+    while window_start <= end {
+        let window_end = end.min(window_start + window - 1);
+        let mut cursor = window_start;
+        loop {
+            let page = fetch(cursor, window_end).await?;
+            let full = page.len() >= limit;
+            let last_time = page.iter().map(|r| r.record_time()).max();
+            for record in page {
+                if seen.insert(record.record_id()) {
+                    result.push(record);
+                }
+            }
+            match last_time {
+                Some(time) if full => {
+                    if time <= cursor {
+                        return Err(BraavosError::new(format!("more than {} records at {}, can't paginate by time", limit, cursor)));
+                    }
+                    cursor = time;
+                }
+                _ => break,
+            }
+        }
+        window_start = window_end + 1;
+    }
+    result.sort_by_key(|r| (r.record_time(), r.record_id()));
+    Ok(result)
+}
+
+/** 按照fromId翻页，一直查到不满一页为止
+ */
+pub(crate) async fn paginate_by_id<U, F, Fut>(from_id: u64, limit: usize, fetch: F) -> Result<Vec<U>, BraavosError>
+where
+    U: HistoryRecord,
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output=Result<Vec<U>, BraavosError>>,
+{
+    let mut result: Vec<U> = vec![];
+    let mut cursor = from_id;
+    loop {
+        let page = fetch(cursor).await?;
+        let full = page.len() >= limit;
+        let last_id = page.iter().map(|r| r.record_id()).max();
+        result.extend(page);
+        match last_id {
+            Some(id) if full => cursor = id + 1,
+            _ => break,
+        }
+    }
+    Ok(result)
+}
+
+/** 统一账户的挂单，历史订单和成交记录，用来和本地的状态对账
+ */
+pub struct PMOrderReader {
+    pub account: Account,
+    pub context: BinanceContext,
+}
+
+impl PMOrderReader {
+    pub fn new(account: Account) -> PMOrderReader {
+        PMOrderReader::with_context(account, DEFAULT_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: BinanceContext) -> PMOrderReader {
+        PMOrderReader { account, context }
+    }
+
+    /** symbol是None的话查所有的挂单，权重比较高
+     */
+    pub async fn um_open_orders(&self, symbol: Option<&str>) -> Result<Vec<UMOrderResponse>, BraavosError> {
+        self.query(PmAPI::UMOpenOrdersAPI, &symbol_query(symbol)).await
+    }
+
+    pub async fn cm_open_orders(&self, symbol: Option<&str>) -> Result<Vec<CMOrderResponse>, BraavosError> {
+        self.query(PmAPI::CMOpenOrdersAPI, &symbol_query(symbol)).await
+    }
+
+    pub async fn margin_open_orders(&self, symbol: Option<&str>) -> Result<Vec<MarginOrder>, BraavosError> {
+        self.query(PmAPI::MarginOpenOrdersAPI, &symbol_query(symbol)).await
+    }
+
+    pub async fn um_all_orders(&self, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<UMOrderResponse>, BraavosError> {
+        self.query_by_time(PmAPI::UMAllOrdersAPI, symbol, start, end).await
+    }
+
+    pub async fn cm_all_orders(&self, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<CMOrderResponse>, BraavosError> {
+        self.query_by_time(PmAPI::CMAllOrdersAPI, symbol, start, end).await
+    }
+
+    pub async fn margin_all_orders(&self, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<MarginOrder>, BraavosError> {
+        self.query_by_time(PmAPI::MarginAllOrdersAPI, symbol, start, end).await
+    }
+
+    pub async fn um_user_trades(&self, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<UMTrade>, BraavosError> {
+        self.query_by_time(PmAPI::UMUserTradesAPI, symbol, start, end).await
+    }
+
+    pub async fn cm_user_trades(&self, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<CMTrade>, BraavosError> {
+        self.query_by_time(PmAPI::CMUserTradesAPI, symbol, start, end).await
+    }
+
+    pub async fn margin_user_trades(&self, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<MarginTrade>, BraavosError> {
+        self.query_by_time(PmAPI::MarginUserTradesAPI, symbol, start, end).await
+    }
+
+    pub async fn um_user_trades_from_id(&self, symbol: &str, from_id: u64) -> Result<Vec<UMTrade>, BraavosError> {
+        self.query_by_id(PmAPI::UMUserTradesAPI, symbol, from_id).await
+    }
+
+    pub async fn cm_user_trades_from_id(&self, symbol: &str, from_id: u64) -> Result<Vec<CMTrade>, BraavosError> {
+        self.query_by_id(PmAPI::CMUserTradesAPI, symbol, from_id).await
+    }
+
+    pub async fn margin_user_trades_from_id(&self, symbol: &str, from_id: u64) -> Result<Vec<MarginTrade>, BraavosError> {
+        self.query_by_id(PmAPI::MarginUserTradesAPI, symbol, from_id).await
+    }
+
+    async fn query_by_time<U: DeserializeOwned + HistoryRecord>(&self, api: PmAPI, symbol: &str, start: UnixTimeStamp, end: UnixTimeStamp) -> Result<Vec<U>, BraavosError> {
+        let (window, limit) = page_rule(api);
+        paginate_by_time(start, end, window, limit, |page_start, page_end| {
+            let query = PMHistoryQuery {
+                symbol: Some(String::from(symbol)),
+                start_time: Some(page_start),
+                end_time: Some(page_end),
+                limit: Some(limit),
+                ..Default::default()
+            };
+            async move { self.query(api, &query).await }
+        }).await
+    }
+
+    async fn query_by_id<U: DeserializeOwned + HistoryRecord>(&self, api: PmAPI, symbol: &str, from_id: u64) -> Result<Vec<U>, BraavosError> {
+        let (_, limit) = page_rule(api);
+        paginate_by_id(from_id, limit, |cursor| {
+            let query = PMHistoryQuery {
+                symbol: Some(String::from(symbol)),
+                from_id: Some(cursor),
+                limit: Some(limit),
+                ..Default::default()
+            };
+            async move { self.query(api, &query).await }
+        }).await
+    }
+
+    async fn query<U: DeserializeOwned>(&self, api: PmAPI, query: &PMHistoryQuery) -> Result<Vec<U>, BraavosError> {
+        self.context.ensure_time_synced().await;
+        let get = GetCommand::<WithTimeStamp<PMHistoryQuery>, Vec<U>> { phantom: Default::default() };
+        get.execute_with_retry(|| {
+            (self.context.command_info_with_security(BinanceBase::PortfolioMargin,
+                                                     BinancePath::PAPI(api),
+                                                     &self.account.api_key,
+                                                     &self.account.secret),
+             Some(WithTimeStamp::new(query.clone(), self.context.timestamp_request(&self.account))))
+        }).await
+    }
+}
+
+fn symbol_query(symbol: Option<&str>) -> PMHistoryQuery {
+    PMHistoryQuery {
+        symbol: symbol.map(String::from),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::binance::bn_models::{ExchangeInfo, OrderStatus, TimeStampRequest};
+    use crate::binance::bn_symbol_rules::SymbolMarket;
+    use crate::errors::OrderRuleViolation;
+    use crate::retry::RetryPolicy;
+    use crate::settings::test_account;
+    use crate::utils::parse_test_json;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pm_order_request_query() {
+        let mut request = PMOrderRequest::limit("BTCUSDT", OrderSide::Buy, dec!(0.010), dec!(58000.00), TimeInForce::GTC)
+            .reduce_only()
+            .position_side(PositionSide::Long);
+        request.new_client_order_id = "abc".to_string();
+        let actual = WithTimeStamp::new(request, TimeStampRequest::new(1499827319559, 5000)).to_string();
+        assert_eq!("symbol=BTCUSDT&side=BUY&positionSide=LONG&type=LIMIT&timeInForce=GTC&quantity=0.01&price=58000&reduceOnly=true&newClientOrderId=abc&timestamp=1499827319559&recvWindow=5000", actual);
+    }
+
+    #[test]
+    fn test_margin_order_request_query() {
+        let mut request = MarginOrderRequest::market("SOLUSDT", OrderSide::Sell, dec!(5))
+            .side_effect(SideEffectType::MarginBuy);
+        request.new_client_order_id = "abc".to_string();
+        assert_eq!("symbol=SOLUSDT&side=SELL&type=MARKET&quantity=5&sideEffectType=MARGIN_BUY&newClientOrderId=abc", request.to_string());
+    }
+
+    #[test]
+    fn test_pm_order_request_apply_rules() {
+        let info = parse_test_json::<ExchangeInfo>("tests/data/binance_fapi_exchange_info.json");
+        let rules = SymbolRules::from_info(SymbolMarket::UsdM, &info.symbols[1]);
+
+        let request = PMOrderRequest::limit("SOLUSDT", OrderSide::Sell, dec!(5.8), dec!(154.4612), TimeInForce::GTC).apply_rules(&rules).unwrap();
+        assert_eq!(dec!(5), request.quantity);
+        assert_eq!(Some(dec!(154.47)), request.price);
+
+        let too_small = PMOrderRequest::limit("SOLUSDT", OrderSide::Sell, dec!(0.5), dec!(154.46), TimeInForce::GTC).apply_rules(&rules);
+        assert!(matches!(too_small, Err(BraavosError::InvalidOrder(OrderRuleViolation::QtyTooSmall { .. }))));
+    }
+
+    #[test]
+    fn test_client_order_id() {
+        let first = PMOrderRequest::market("BTCUSDT", OrderSide::Buy, dec!(1));
+        let second = PMOrderRequest::market("BTCUSDT", OrderSide::Buy, dec!(1));
+        assert_ne!(first.new_client_order_id, second.new_client_order_id);
+        assert!(first.new_client_order_id.len() <= 36, "币安的clientOrderId最长36位");
+    }
+
+    #[test]
+    fn test_parse_order_response() {
+        let um = parse_test_json::<UMOrderResponse>("tests/data/binance_papi_um_order.json");
+        assert_eq!(OrderStatus::New, um.status);
+        assert_eq!(OrderType::Limit, um.order_type);
+        assert_eq!(dec!(58000), um.price);
+
+        let margin = parse_test_json::<MarginOrderResponse>("tests/data/binance_papi_margin_order.json");
+        assert_eq!(Some(OrderStatus::Filled), margin.status);
+        assert_eq!(Some(dec!(5)), margin.margin_buy_borrow_amount);
+    }
+
+    #[tokio::test]
+    async fn test_place_um_order_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::POST, "/papi/v1/um/order",
+                   vec![MockResponse::json(503, "<html>unavailable</html>"),
+                        MockResponse::fixture("tests/data/binance_papi_um_order.json")])
+            .route(hyper::Method::GET, "/papi/v1/um/order",
+                   vec![MockResponse::json(400, r#"{"code":-2013,"msg":"Order does not exist."}"#)]);
+        let retry = RetryPolicy { max_attempts: 2, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+        let executor = PMOrderExecutor::with_context(test_account(), server.context().with_retry_policy(retry));
+
+        let request = PMOrderRequest::limit("BTCUSDT", OrderSide::Buy, dec!(0.01), dec!(58000), TimeInForce::GTC);
+        let actual = executor.place_um_order(&request).await.unwrap();
+        assert_eq!(22542179, actual.order_id);
+
+        let orders: Vec<_> = server.requests().into_iter().filter(|r| r.path == "/papi/v1/um/order").collect();
+        let methods: Vec<_> = orders.iter().map(|r| r.method.clone()).collect();
+        assert_eq!(vec![hyper::Method::POST, hyper::Method::GET, hyper::Method::GET, hyper::Method::POST], methods,
+                   "503之后先查单，两次都不存在才重新提交");
+        let query = orders[1].query.clone().unwrap_or_default();
+        assert!(query.contains(&format!("origClientOrderId={}", request.new_client_order_id)), "{}", query);
+        let client_order_id = format!("newClientOrderId={}", request.new_client_order_id);
+        assert!(orders[3].body.contains(&client_order_id), "重新提交不能换clientOrderId");
+        assert!(orders[0].body.contains("&signature="));
+    }
+
+    #[tokio::test]
+    async fn test_place_order_outcome_found_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::POST, "/papi/v1/margin/order", vec![MockResponse::json(503, "<html>unavailable</html>")])
+            .route(hyper::Method::GET, "/papi/v1/margin/order", vec![MockResponse::fixture("tests/data/binance_papi_margin_query_order.json")]);
+        let retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+        let executor = PMOrderExecutor::with_context(test_account(), server.context().with_retry_policy(retry));
+
+        let request = MarginOrderRequest::market("SOLUSDT", OrderSide::Buy, dec!(5));
+        let actual = executor.place_margin_order(&request).await.unwrap();
+        assert_eq!(Some(OrderStatus::Filled), actual.status, "市价单已经成交了，用查单的结果");
+
+        let posts = server.requests().into_iter().filter(|r| r.method == hyper::Method::POST).count();
+        assert_eq!(1, posts, "已经成交的订单不能重新提交");
+    }
+
+    #[tokio::test]
+    async fn test_place_order_found_on_second_lookup_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::POST, "/papi/v1/margin/order", vec![MockResponse::json(503, "<html>unavailable</html>")])
+            .route(hyper::Method::GET, "/papi/v1/margin/order",
+                   vec![MockResponse::json(400, r#"{"code":-2013,"msg":"Order does not exist."}"#),
+                        MockResponse::fixture("tests/data/binance_papi_margin_query_order.json")]);
+        let retry = RetryPolicy { max_attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+        let executor = PMOrderExecutor::with_context(test_account(), server.context().with_retry_policy(retry));
+
+        let request = MarginOrderRequest::market("SOLUSDT", OrderSide::Buy, dec!(5));
+        let actual = executor.place_margin_order(&request).await.unwrap();
+        assert_eq!(Some(OrderStatus::Filled), actual.status);
+
+        let methods: Vec<_> = server.requests().into_iter().filter(|r| r.path == "/papi/v1/margin/order").map(|r| r.method).collect();
+        assert_eq!(vec![hyper::Method::POST, hyper::Method::GET, hyper::Method::GET], methods, "第一次查单还没有处理完，不能马上重新提交");
+    }
+
+    #[tokio::test]
+    async fn test_place_order_with_registry_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::GET, "/fapi/v1/exchangeInfo", vec![MockResponse::fixture("tests/data/binance_fapi_exchange_info.json")])
+            .route(hyper::Method::POST, "/papi/v1/um/order", vec![MockResponse::fixture("tests/data/binance_papi_um_order.json")]);
+        let executor = PMOrderExecutor::with_context(test_account(), server.context()).with_registry(Arc::new(SymbolRegistry::new()));
+
+        let request = PMOrderRequest::limit("BTCUSDT", OrderSide::Buy, dec!(0.0129), dec!(58000.123), TimeInForce::GTC);
+        executor.place_um_order(&request).await.unwrap();
+        for _ in 0..3 {
+            let unknown = executor.place_um_order(&PMOrderRequest::market("ETHUSDT", OrderSide::Buy, dec!(1))).await;
+            assert!(matches!(unknown, Err(BraavosError::InvalidOrder(OrderRuleViolation::UnknownSymbol(_)))), "{:?}", unknown);
+        }
+
+        let requests = server.requests();
+        let posts: Vec<_> = requests.iter().filter(|r| r.method == hyper::Method::POST).collect();
+        assert_eq!(1, posts.len(), "不认识的交易对不发出去");
+        assert!(posts[0].body.starts_with("symbol=BTCUSDT&side=BUY&type=LIMIT&timeInForce=GTC&quantity=0.012&price=58000.1&"), "{}", posts[0].body);
+        let loads = requests.iter().filter(|r| r.path == "/fapi/v1/exchangeInfo").count();
+        assert_eq!(1, loads, "第一次下单load，刚load过的市场找不到交易对不再load");
+    }
+
+    #[derive(Debug, Clone)]
+    struct FakeRecord {
+        id: u64,
+        time: UnixTimeStamp,
+    }
+
+    impl HistoryRecord for FakeRecord {
+        fn record_id(&self) -> u64 { self.id }
+        fn record_time(&self) -> UnixTimeStamp { self.time }
+    }
+
+    fn fake_records() -> Vec<FakeRecord> {
+        // 同一毫秒有两条，用来测试翻页的时候不会丢也不会重复
+        vec![(1, 100), (2, 150), (3, 150), (4, 230), (5, 260), (6, 390), (7, 420)]
+            .into_iter()
+            .map(|(id, time)| FakeRecord { id, time })
+            .collect()
+    }
+
+    #[test]
+    fn test_history_query() {
+        let query = PMHistoryQuery {
+            symbol: Some("BTCUSDT".to_string()),
+            start_time: Some(1723960451595),
+            end_time: Some(1724565251594),
+            limit: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!("symbol=BTCUSDT&startTime=1723960451595&endTime=1724565251594&limit=1000", query.to_string());
+        assert_eq!("", PMHistoryQuery::default().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_paginate_by_time() {
+        let records = fake_records();
+        let calls = std::sync::Mutex::new(vec![]);
+        let fetch = |limit: usize| {
+            let records = &records;
+            let calls = &calls;
+            move |start: UnixTimeStamp, end: UnixTimeStamp| {
+                calls.lock().unwrap().push((start, end));
+                let page: Vec<FakeRecord> = records.iter()
+                    .filter(|r| r.time >= start && r.time <= end)
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                async move { Ok(page) }
+            }
+        };
+        let actual = paginate_by_time(100, 399, 200, 3, fetch(3)).await.unwrap();
+
+        let ids: Vec<u64> = actual.iter().map(|r| r.id).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], ids, "超出时间范围的不要，窗口里面满页的要继续翻");
+        let requested = calls.lock().unwrap().clone();
+        assert_eq!((100, 299), requested[0], "endTime是包含的，所以窗口要减1");
+        assert!(requested.contains(&(300, 399)));
+
+        let actual = paginate_by_time(100, 399, 200, 2, fetch(2)).await;
+        assert!(actual.is_err(), "一页都是150毫秒的，按时间翻页会丢记录");
+    }
+
+    #[tokio::test]
+    async fn test_paginate_by_id() {
+        let records = fake_records();
+        let actual = paginate_by_id(2, 3, |from_id| {
+            let page: Vec<FakeRecord> = records.iter().filter(|r| r.id >= from_id).take(3).cloned().collect();
+            async move { Ok(page) }
+        }).await.unwrap();
+        let ids: Vec<u64> = actual.iter().map(|r| r.id).collect();
+        assert_eq!(vec![2, 3, 4, 5, 6, 7], ids);
+    }
+
+    #[tokio::test]
+    async fn test_um_user_trades_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")])
+            .route(hyper::Method::GET, "/papi/v1/um/userTrades",
+                   vec![MockResponse::fixture("tests/data/binance_papi_um_user_trades.json")]);
+        let reader = PMOrderReader::with_context(test_account(), server.context());
+
+        let start = 1723960451000;
+        let actual = reader.um_user_trades("BTCUSDT", start, start + 10 * DAY_MS).await.unwrap();
+        assert_eq!(2, actual.len(), "两个窗口返回的是同一批成交，要去重");
+        assert_eq!(67880589, actual[0].id);
+        assert_eq!(dec!(2.585), actual[0].realized_pnl);
+
+        let trades: Vec<_> = server.requests().into_iter().filter(|r| r.path == "/papi/v1/um/userTrades").collect();
+        assert_eq!(2, trades.len(), "10天要按照7天切成两次查询");
+        let first_end = format!("endTime={}", start + 7 * DAY_MS - 1);
+        let query = trades[0].query.clone().unwrap_or_default();
+        assert!(query.contains(&first_end), "{}", query);
+        assert!(query.contains("limit=1000"));
+        assert!(query.contains("&signature="));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::errors::BraavosError;
+use crate::models::UnixTimeStamp;
+use crate::utils::unix_time;
+use log::{trace, warn};
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const USED_WEIGHT_HEADER: &str = "x-mbx-used-weight-";
+const ORDER_COUNT_HEADER: &str = "x-mbx-order-count-";
+const WEIGHT_WINDOW_MS: u64 = 60_000;
+
+/** 没有Retry-After的时候，429/418默认等多久
+ */
+const DEFAULT_BAN: Duration = Duration::from_secs(60);
+
+/** 一个base url的限制，现货，papi，fapi和dapi的限制都不一样
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestLimit {
+    pub weight_per_minute: u32,
+    pub orders: Vec<OrderLimit>,
+}
+
+/** 下单数的限制，interval和X-MBX-ORDER-COUNT-*后面的部分一样，比如10s，1m，1d
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderLimit {
+    pub interval: &'static str,
+    pub limit: u32,
+}
+
+/** 币安按照IP统计每分钟的请求权重，超过了先429，继续请求会被418 ban掉。
+* 这里按照base url分开记录(现货和papi是分开算的)，请求之前先预估权重，返回之后用X-MBX-USED-WEIGHT-*修正。
+* 下单的请求还要检查X-MBX-ORDER-COUNT-*，下单数是按账户算的，多个账户共用一个base的时候会偏保守。
+* 超过预算的请求，如果等到下一个窗口的时间不超过max_wait就等，否则直接拒绝
+*/
+#[derive(Debug)]
+pub struct RateLimiter {
+    default_limit: RequestLimit,
+    limits: HashMap<String, RequestLimit>,
+    max_wait: Duration,
+    states: Mutex<HashMap<String, LimitState>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct LimitState {
+    window: u64,
+    used_weight: u32,
+    order_counts: HashMap<String, OrderCount>,
+    banned_until: Option<UnixTimeStamp>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct OrderCount {
+    window: u64,
+    count: u32,
+}
+
+#[derive(Debug, PartialEq)]
+enum Permit {
+    Granted,
+    Wait(Duration),
+    Reject(Duration),
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(6000, Duration::from_secs(10))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(weight_per_minute: u32, max_wait: Duration) -> RateLimiter {
+        RateLimiter {
+            default_limit: RequestLimit { weight_per_minute, orders: vec![] },
+            limits: HashMap::new(),
+            max_wait,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /** 单独设置某个base url的限制。多个base用同一个地址(比如mock server)的时候，用第一次设置的
+     */
+    pub fn with_limit(mut self, key: &str, limit: RequestLimit) -> RateLimiter {
+        self.limits.entry(String::from(key)).or_insert(limit);
+        self
+    }
+
+    fn limit_of(&self, key: &str) -> &RequestLimit {
+        self.limits.get(key).unwrap_or(&self.default_limit)
+    }
+
+    /** 请求之前调用，权重或者下单数不够的时候会等待或者返回RateLimitExceeded。order是新下单的请求
+     */
+    pub async fn acquire(&self, key: &str, weight: u32, order: bool) -> Result<(), BraavosError> {
+        loop {
+            match self.check(key, weight, order, unix_time()) {
+                Permit::Granted => return Ok(()),
+                Permit::Wait(wait) => {
+                    warn!("{} request weight is running out, wait {:?}", key, wait);
+                    tokio::time::sleep(wait).await;
+                }
+                Permit::Reject(retry_after) => {
+                    warn!("{} request rejected by local rate limiter, retry after {:?}", key, retry_after);
+                    return Err(BraavosError::RateLimitExceeded { retry_after });
+                }
+            }
+        }
+    }
+
+    /** 用返回的header修正已经用掉的权重和下单数
+     */
+    pub fn record(&self, key: &str, headers: &HeaderMap) {
+        self.record_at(key, headers, unix_time())
+    }
+
+    /** 429/418的时候调用，Retry-After之前的请求都直接拒绝
+     */
+    pub fn ban(&self, key: &str, retry_after: Option<Duration>) {
+        self.ban_at(key, retry_after, unix_time())
+    }
+
+    pub fn used_weight(&self, key: &str) -> u32 {
+        let window = unix_time() / WEIGHT_WINDOW_MS;
+        let states = self.states.lock().unwrap();
+        states.get(key)
+            .filter(|s| s.window == window)
+            .map(|s| s.used_weight)
+            .unwrap_or(0)
+    }
+
+    /** 最后一次记录的下单数，interval是header后面的部分，比如10s，1d
+     */
+    pub fn order_count(&self, key: &str, interval: &str) -> Option<u32> {
+        let states = self.states.lock().unwrap();
+        states.get(key).and_then(|s| s.order_counts.get(&interval.to_lowercase()).map(|c| c.count))
+    }
+
+    fn check(&self, key: &str, weight: u32, order: bool, now: UnixTimeStamp) -> Permit {
+        let limit = self.limit_of(key);
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(String::from(key)).or_default();
+
+        if let Some(banned_until) = state.banned_until {
+            if banned_until > now {
+                let wait = Duration::from_millis(banned_until - now);
+                return if wait <= self.max_wait { Permit::Wait(wait) } else { Permit::Reject(wait) };
+            }
+            state.banned_until = None;
+        }
+
+        let window = now / WEIGHT_WINDOW_MS;
+        if state.window != window {
+            state.window = window;
+            state.used_weight = 0;
+        }
+
+        if state.used_weight + weight > limit.weight_per_minute {
+            let wait = Duration::from_millis((window + 1) * WEIGHT_WINDOW_MS - now);
+            return if wait <= self.max_wait { Permit::Wait(wait) } else { Permit::Reject(wait) };
+        }
+
+        let order_limits: Vec<(&OrderLimit, u64)> = match order {
+            true => limit.orders.iter().filter_map(|o| interval_ms(o.interval).map(|ms| (o, ms))).collect(),
+            false => vec![],
+        };
+        for (order_limit, interval) in &order_limits {
+            let window = now / interval;
+            let count = state.order_counts.get(order_limit.interval)
+                .filter(|c| c.window == window)
+                .map(|c| c.count)
+                .unwrap_or(0);
+            if count >= order_limit.limit {
+                let wait = Duration::from_millis((window + 1) * interval - now);
+                return if wait <= self.max_wait { Permit::Wait(wait) } else { Permit::Reject(wait) };
+            }
+        }
+
+        state.used_weight += weight;
+        for (order_limit, interval) in order_limits {
+            let window = now / interval;
+            let count = state.order_counts.entry(String::from(order_limit.interval)).or_default();
+            if count.window != window {
+                *count = OrderCount { window, count: 0 };
+            }
+            count.count += 1;
+        }
+        Permit::Granted
+    }
+
+    fn record_at(&self, key: &str, headers: &HeaderMap, now: UnixTimeStamp) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(String::from(key)).or_default();
+        for (name, value) in headers {
+            let name = name.as_str();
+            let value = match value.to_str().ok().and_then(|v| v.parse::<u32>().ok()) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(interval) = name.strip_prefix(USED_WEIGHT_HEADER) {
+                if interval == "1m" {
+                    state.window = now / WEIGHT_WINDOW_MS;
+                    state.used_weight = value;
+                }
+                trace!("{} used weight {}:{}", key, interval, value);
+            } else if let Some(interval) = name.strip_prefix(ORDER_COUNT_HEADER) {
+                let window = interval_ms(interval).map(|ms| now / ms).unwrap_or(0);
+                state.order_counts.insert(String::from(interval), OrderCount { window, count: value });
+                trace!("{} order count {}:{}", key, interval, value);
+            }
+        }
+    }
+
+    fn ban_at(&self, key: &str, retry_after: Option<Duration>, now: UnixTimeStamp) {
+        let wait = retry_after.unwrap_or(DEFAULT_BAN);
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(String::from(key)).or_default();
+        state.banned_until = Some(now + wait.as_millis() as u64);
+        warn!("{} is rate limited by binance for {:?}", key, wait);
+    }
+}
+
+/** 10s，1m，1h，1d这样的时间段转成毫秒
+ */
+fn interval_ms(interval: &str) -> Option<u64> {
+    let unit = interval.chars().last()?;
+    let num: u64 = interval[..interval.len() - unit.len_utf8()].parse().ok()?;
+    let unit_ms = match unit.to_ascii_lowercase() {
+        's' => 1_000,
+        'm' => 60_000,
+        'h' => 3_600_000,
+        'd' => 86_400_000,
+        _ => return None,
+    };
+    Some(num * unit_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const KEY: &str = "https://api.binance.com/";
+
+    #[test]
+    fn test_weight_budget() {
+        let limiter = RateLimiter::new(100, Duration::from_secs(10));
+        let now = 1_723_960_451_595;
+        assert_eq!(Permit::Granted, limiter.check(KEY, 60, false, now));
+        assert_eq!(Permit::Granted, limiter.check(KEY, 40, false, now));
+
+        let wait_until_next_minute = Duration::from_millis(60_000 - now % 60_000);
+        assert_eq!(Permit::Reject(wait_until_next_minute), limiter.check(KEY, 1, false, now), "超过10秒应该直接拒绝");
+
+        let near_end = (now / 60_000 + 1) * 60_000 - 5_000;
+        limiter.check(KEY, 100, false, near_end);
+        assert_eq!(Permit::Wait(Duration::from_secs(5)), limiter.check(KEY, 1, false, near_end), "下一分钟很近的时候等待");
+        assert_eq!(Permit::Granted, limiter.check(KEY, 100, false, near_end + 5_000), "新的一分钟重新计算");
+        assert_eq!(Permit::Granted, limiter.check("https://papi.binance.com/", 100, false, near_end + 5_000), "不同的base分开计算");
+    }
+
+    #[test]
+    fn test_record_headers() {
+        let limiter = RateLimiter::new(6000, Duration::from_secs(10));
+        let now = 1_723_960_451_595;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-mbx-used-weight-1m", HeaderValue::from_static("5990"));
+        headers.insert("x-mbx-order-count-10s", HeaderValue::from_static("3"));
+        headers.insert("x-mbx-order-count-1d", HeaderValue::from_static("27"));
+        limiter.record_at(KEY, &headers, now);
+
+        assert_eq!(Some(3), limiter.order_count(KEY, "10S"));
+        assert_eq!(Some(27), limiter.order_count(KEY, "1d"));
+        assert_eq!(Permit::Granted, limiter.check(KEY, 10, false, now));
+        assert!(matches!(limiter.check(KEY, 1, false, now), Permit::Reject(_)), "header里面的权重已经用完了");
+    }
+
+    #[test]
+    fn test_ban() {
+        let limiter = RateLimiter::new(6000, Duration::from_secs(10));
+        let now = 1_723_960_451_595;
+        limiter.ban_at(KEY, Some(Duration::from_secs(120)), now);
+        assert_eq!(Permit::Reject(Duration::from_secs(120)), limiter.check(KEY, 1, false, now));
+        assert_eq!(Permit::Wait(Duration::from_secs(5)), limiter.check(KEY, 1, false, now + 115_000));
+        assert_eq!(Permit::Granted, limiter.check(KEY, 1, false, now + 120_000));
+    }
+
+    #[test]
+    fn test_order_count() {
+        let limiter = RateLimiter::new(6000, Duration::from_secs(10))
+            .with_limit(KEY, RequestLimit { weight_per_minute: 6000, orders: vec![OrderLimit { interval: "10s", limit: 5 }] });
+        let now = 1_723_960_450_000;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-mbx-order-count-10s", HeaderValue::from_static("3"));
+        limiter.record_at(KEY, &headers, now);
+
+        assert_eq!(Permit::Granted, limiter.check(KEY, 1, true, now + 1_000));
+        assert_eq!(Permit::Granted, limiter.check(KEY, 1, true, now + 1_000));
+        assert_eq!(Permit::Wait(Duration::from_secs(9)), limiter.check(KEY, 1, true, now + 1_000), "10秒内只能下5单");
+        assert_eq!(Permit::Granted, limiter.check(KEY, 1, false, now + 1_000), "不是下单的请求不受影响");
+        assert_eq!(Permit::Granted, limiter.check(KEY, 1, true, now + 10_000), "下一个10秒重新计算");
+        assert_eq!(Permit::Granted, limiter.check("https://papi.binance.com/", 1, true, now + 1_000), "没有设置下单限制");
+    }
+
+    #[test]
+    fn test_interval_ms() {
+        assert_eq!(Some(10_000), interval_ms("10s"));
+        assert_eq!(Some(60_000), interval_ms("1M"));
+        assert_eq!(Some(86_400_000), interval_ms("1d"));
+        assert_eq!(None, interval_ms("d"));
+    }
+}
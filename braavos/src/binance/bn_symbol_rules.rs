@@ -0,0 +1,409 @@
+use crate::binance::bn_commands::{BNCommand, GetCommand};
+use crate::binance::bn_models::{BinanceBase, BinanceContext, BinancePath, CmAPI, ExchangeInfo, NormalAPI, OrderSide, SymbolFilter, SymbolInfo, UmAPI};
+use crate::errors::{BraavosError, OrderRuleViolation};
+use crate::models::{Decimal, EmptyObject};
+use log::{info, warn};
+use rust_decimal_macros::dec;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::join;
+
+/** 找不到交易对的时候最多多久重新load一次。exchangeInfo的权重是20以上，和查账户共用请求权重，
+* 一直发错的交易对不能每次都load
+*/
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/** 估值的时候有交易对不在缓存里面，可能是新上线的，缓存超过这个时间就重新load。
+* 估值的候选交易对很多本来就不存在，比如LDBTCUSDT，不能每次都load
+*/
+const SNAPSHOT_TTL: Duration = Duration::from_secs(600);
+
+/** exchangeInfo分三个市场查，同一个symbol在不同的市场规则不一样，比如现货和U本位的BTCUSDT
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolMarket {
+    Spot,
+    UsdM,
+    CoinM,
+}
+
+/** 一个交易对的下单规则，从exchangeInfo的filter里面整理出来。
+* 币安的规则里面0代表不限制，比如maxQty是0，stepSize是0
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolRules {
+    pub market: SymbolMarket,
+    pub symbol: String,
+    pub trading: bool,
+    pub min_price: Decimal,
+    pub max_price: Decimal,
+    pub tick_size: Decimal,
+    pub min_qty: Decimal,
+    pub max_qty: Decimal,
+    pub step_size: Decimal,
+    pub market_min_qty: Decimal, //市价单的数量限制，没有MARKET_LOT_SIZE的话和限价单一样
+    pub market_max_qty: Decimal,
+    pub market_step_size: Decimal,
+    pub min_notional: Option<Decimal>, //币本位是按照张数下单的，没有这个限制
+    pub max_notional: Option<Decimal>,
+    pub contract_size: Option<Decimal>, //币本位一张合约多少USD
+}
+
+impl SymbolRules {
+    pub fn from_info(market: SymbolMarket, info: &SymbolInfo) -> SymbolRules {
+        let mut rules = SymbolRules {
+            market,
+            symbol: info.symbol.clone(),
+            trading: info.is_trading(),
+            min_price: dec!(0),
+            max_price: dec!(0),
+            tick_size: dec!(0),
+            min_qty: dec!(0),
+            max_qty: dec!(0),
+            step_size: dec!(0),
+            market_min_qty: dec!(0),
+            market_max_qty: dec!(0),
+            market_step_size: dec!(0),
+            min_notional: None,
+            max_notional: None,
+            contract_size: info.contract_size,
+        };
+        let mut market_lot = None;
+        for filter in &info.filters {
+            match filter {
+                SymbolFilter::PriceFilter { min_price, max_price, tick_size } => {
+                    rules.min_price = *min_price;
+                    rules.max_price = *max_price;
+                    rules.tick_size = *tick_size;
+                }
+                SymbolFilter::LotSize { min_qty, max_qty, step_size } => {
+                    rules.min_qty = *min_qty;
+                    rules.max_qty = *max_qty;
+                    rules.step_size = *step_size;
+                }
+                SymbolFilter::MarketLotSize { min_qty, max_qty, step_size } => market_lot = Some((*min_qty, *max_qty, *step_size)),
+                SymbolFilter::MinNotional { min_notional } => rules.min_notional = Some(*min_notional),
+                SymbolFilter::Notional { min_notional, max_notional } => {
+                    rules.min_notional = Some(*min_notional);
+                    rules.max_notional = *max_notional;
+                }
+                SymbolFilter::Other => {}
+            }
+        }
+        // 现货的MARKET_LOT_SIZE一般是0，用LOT_SIZE的
+        let (min_qty, max_qty, step_size) = market_lot.unwrap_or((rules.min_qty, rules.max_qty, rules.step_size));
+        rules.market_min_qty = if min_qty.is_zero() { rules.min_qty } else { min_qty };
+        rules.market_max_qty = if max_qty.is_zero() { rules.max_qty } else { max_qty };
+        rules.market_step_size = if step_size.is_zero() { rules.step_size } else { step_size };
+        rules
+    }
+
+    /** 按照tick取整。买单往下，卖单往上，这样不会比原来的价格更激进
+     */
+    pub fn round_price(&self, price: Decimal, side: OrderSide) -> Decimal {
+        match side {
+            OrderSide::Buy => floor_to_step(price, self.tick_size, self.min_price),
+            OrderSide::Sell => ceil_to_step(price, self.tick_size, self.min_price),
+        }
+    }
+
+    /** 按照step往下取整，不会超过原来的数量
+     */
+    pub fn round_qty(&self, qty: Decimal, market_order: bool) -> Decimal {
+        let (min_qty, _, step_size) = self.lot(market_order);
+        floor_to_step(qty, step_size, min_qty)
+    }
+
+    /** 检查价格和数量，不做任何调整。price是None的时候，比如市价单，不检查名义价值
+     */
+    pub fn check(&self, price: Option<Decimal>, qty: Decimal, market_order: bool) -> Result<(), OrderRuleViolation> {
+        if !self.trading {
+            return Err(OrderRuleViolation::SymbolNotTrading(self.symbol.clone()));
+        }
+        if let Some(price) = price {
+            if price < self.min_price || price.is_sign_negative() || price.is_zero() {
+                return Err(OrderRuleViolation::PriceTooLow { price, min_price: self.min_price });
+            }
+            if !self.max_price.is_zero() && price > self.max_price {
+                return Err(OrderRuleViolation::PriceTooHigh { price, max_price: self.max_price });
+            }
+            if !on_step(price, self.tick_size, self.min_price) {
+                return Err(OrderRuleViolation::PriceNotOnTick { price, tick_size: self.tick_size });
+            }
+        }
+
+        let (min_qty, max_qty, step_size) = self.lot(market_order);
+        if qty < min_qty || qty.is_sign_negative() || qty.is_zero() {
+            return Err(OrderRuleViolation::QtyTooSmall { qty, min_qty });
+        }
+        if !max_qty.is_zero() && qty > max_qty {
+            return Err(OrderRuleViolation::QtyTooLarge { qty, max_qty });
+        }
+        if !on_step(qty, step_size, min_qty) {
+            return Err(OrderRuleViolation::QtyNotOnStep { qty, step_size });
+        }
+
+        if let Some(price) = price {
+            let notional = price * qty;
+            if let Some(min_notional) = self.min_notional.filter(|n| notional < *n) {
+                return Err(OrderRuleViolation::NotionalTooSmall { notional, min_notional });
+            }
+            if let Some(max_notional) = self.max_notional.filter(|n| !n.is_zero() && notional > *n) {
+                return Err(OrderRuleViolation::NotionalTooLarge { notional, max_notional });
+            }
+        }
+        Ok(())
+    }
+
+    /** 先取整再检查，返回可以直接下单的价格和数量
+     */
+    pub fn normalize(&self, side: OrderSide, price: Option<Decimal>, qty: Decimal, market_order: bool) -> Result<(Option<Decimal>, Decimal), OrderRuleViolation> {
+        let price = price.map(|p| self.round_price(p, side).normalize());
+        let qty = self.round_qty(qty, market_order).normalize();
+        self.check(price, qty, market_order)?;
+        Ok((price, qty))
+    }
+
+    fn lot(&self, market_order: bool) -> (Decimal, Decimal, Decimal) {
+        if market_order {
+            (self.market_min_qty, self.market_max_qty, self.market_step_size)
+        } else {
+            (self.min_qty, self.max_qty, self.step_size)
+        }
+    }
+}
+
+/** 币安的规则是 (value - min) % step == 0，所以从min开始算步数
+ */
+fn floor_to_step(value: Decimal, step: Decimal, base: Decimal) -> Decimal {
+    if step.is_zero() || value <= base {
+        return value;
+    }
+    base + ((value - base) / step).floor() * step
+}
+
+fn ceil_to_step(value: Decimal, step: Decimal, base: Decimal) -> Decimal {
+    if step.is_zero() || value <= base {
+        return value;
+    }
+    base + ((value - base) / step).ceil() * step
+}
+
+fn on_step(value: Decimal, step: Decimal, base: Decimal) -> bool {
+    step.is_zero() || ((value - base) % step).is_zero()
+}
+
+pub async fn fetch_exchange_info(context: &BinanceContext, market: SymbolMarket) -> Result<ExchangeInfo, BraavosError> {
+    let info = || match market {
+        SymbolMarket::Spot => context.command_info(BinanceBase::Normal, BinancePath::Normal(NormalAPI::ExchangeInfoAPI)),
+        SymbolMarket::UsdM => context.command_info(BinanceBase::UsdMFutures, BinancePath::FAPI(UmAPI::ExchangeInfoAPI)),
+        SymbolMarket::CoinM => context.command_info(BinanceBase::CoinMFutures, BinancePath::DAPI(CmAPI::ExchangeInfoAPI)),
+    };
+    let get = GetCommand::<EmptyObject, ExchangeInfo> { phantom: Default::default() };
+    get.execute_with_retry(|| (info(), None)).await
+}
+
+/** exchangeInfo的缓存。规则很少变，每个市场第一次用的时候load，之后找不到交易对的时候才重新load，有最短的间隔：
+* PMOrderExecutor下单是MIN_RELOAD_INTERVAL，估值过滤交易对是SNAPSHOT_TTL。
+* BinanceContext里面有一个，clone之后还是同一个
+*/
+#[derive(Debug, Default)]
+pub struct SymbolRegistry {
+    rules: RwLock<HashMap<(SymbolMarket, String), Arc<SymbolRules>>>,
+    loaded_at: Mutex<HashMap<SymbolMarket, Instant>>,
+}
+
+impl SymbolRegistry {
+    pub fn new() -> SymbolRegistry {
+        SymbolRegistry::default()
+    }
+
+    /** 重新查一个市场，替换掉这个市场原来的规则，返回交易对的数量
+     */
+    pub async fn load(&self, context: &BinanceContext, market: SymbolMarket) -> Result<usize, BraavosError> {
+        self.loaded_at.lock().unwrap().insert(market, Instant::now());
+        let exchange_info = fetch_exchange_info(context, market).await?;
+        let count = exchange_info.symbols.len();
+        let mut rules = self.rules.write().unwrap();
+        rules.retain(|(m, _), _| *m != market);
+        for symbol in &exchange_info.symbols {
+            rules.insert((market, symbol.symbol.clone()), Arc::new(SymbolRules::from_info(market, symbol)));
+        }
+        info!("{:?} exchange info loaded, {} symbols", market, count);
+        Ok(count)
+    }
+
+    /** 三个市场一起load，不想第一次下单的时候才load的话，启动的时候调用
+     */
+    pub async fn load_all(&self, context: &BinanceContext) -> Result<(), BraavosError> {
+        let (spot, um, cm) = join!(
+            self.load(context, SymbolMarket::Spot),
+            self.load(context, SymbolMarket::UsdM),
+            self.load(context, SymbolMarket::CoinM)
+        );
+        spot?;
+        um?;
+        cm?;
+        Ok(())
+    }
+
+    /** 找不到交易对的时候用。同一个市场MIN_RELOAD_INTERVAL之内只load一次，load失败也算，返回有没有load
+     */
+    pub async fn reload(&self, context: &BinanceContext, market: SymbolMarket) -> Result<bool, BraavosError> {
+        self.reload_older_than(context, market, MIN_RELOAD_INTERVAL).await
+    }
+
+    async fn reload_older_than(&self, context: &BinanceContext, market: SymbolMarket, age: Duration) -> Result<bool, BraavosError> {
+        if self.loaded_at.lock().unwrap().get(&market).is_some_and(|t| t.elapsed() < age) {
+            return Ok(false);
+        }
+        self.load(context, market).await?;
+        Ok(true)
+    }
+
+    /** 去掉一个市场里面不存在的交易对，包括不能交易的也留着。估值查价格之前用，有一个交易对不存在整个请求都会失败。
+    * 有交易对找不到的话，缓存超过SNAPSHOT_TTL就重新load，新上线的交易对最多晚这么久才有价格。
+    * 去掉的每个交易对都打warn，这些币会当成没有价格
+    */
+    pub async fn retain_known(&self, context: &BinanceContext, market: SymbolMarket, symbols: Vec<String>) -> Result<Vec<String>, BraavosError> {
+        let loaded = || self.rules.read().unwrap().keys().any(|(m, _)| *m == market);
+        let known = |symbol: &String| self.rules.read().unwrap().contains_key(&(market, symbol.clone()));
+        if !loaded() {
+            self.reload(context, market).await?;
+            if !loaded() {
+                return Err(BraavosError::Custom(format!("{:?} exchange info is not loaded", market)));
+            }
+        } else if !symbols.iter().all(known) {
+            self.reload_older_than(context, market, SNAPSHOT_TTL).await?;
+        }
+        let (known, unknown): (Vec<String>, Vec<String>) = symbols.into_iter().partition(known);
+        for symbol in &unknown {
+            warn!("{} is not in {:?} exchange info, skip it", symbol, market);
+        }
+        Ok(known)
+    }
+
+    pub fn get(&self, market: SymbolMarket, symbol: &str) -> Option<Arc<SymbolRules>> {
+        self.rules.read().unwrap().get(&(market, String::from(symbol))).cloned()
+    }
+
+    /** 下单之前用，没有这个交易对或者不能交易的时候返回错误
+     */
+    pub fn rules(&self, market: SymbolMarket, symbol: &str) -> Result<Arc<SymbolRules>, OrderRuleViolation> {
+        let rules = self.get(market, symbol).ok_or_else(|| OrderRuleViolation::UnknownSymbol(String::from(symbol)))?;
+        if !rules.trading {
+            return Err(OrderRuleViolation::SymbolNotTrading(String::from(symbol)));
+        }
+        Ok(rules)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::utils::parse_test_json;
+
+    fn rules_of(market: SymbolMarket, path: &str, symbol: &str) -> SymbolRules {
+        let info = parse_test_json::<ExchangeInfo>(path);
+        SymbolRules::from_info(market, info.symbols.iter().find(|s| s.symbol == symbol).unwrap())
+    }
+
+    #[test]
+    fn test_rules_from_exchange_info() {
+        let spot = rules_of(SymbolMarket::Spot, "tests/data/binance_spot_exchange_info.json", "BTCUSDT");
+        assert_eq!(dec!(0.01), spot.tick_size);
+        assert_eq!(dec!(0.00001), spot.step_size);
+        assert_eq!(dec!(0.00001), spot.market_step_size, "现货的MARKET_LOT_SIZE是0，用LOT_SIZE的");
+        assert_eq!(dec!(91.4562327), spot.market_max_qty);
+        assert_eq!(Some(dec!(5)), spot.min_notional);
+
+        let um = rules_of(SymbolMarket::UsdM, "tests/data/binance_fapi_exchange_info.json", "BTCUSDT");
+        assert_eq!(dec!(556.80), um.min_price);
+        assert_eq!(Some(dec!(100)), um.min_notional, "U本位的字段是notional");
+        assert_eq!(dec!(120), um.market_max_qty);
+
+        let cm = rules_of(SymbolMarket::CoinM, "tests/data/binance_dapi_exchange_info.json", "BTCUSD_PERP");
+        assert!(cm.trading, "币本位用contractStatus");
+        assert_eq!(Some(dec!(100)), cm.contract_size);
+        assert_eq!(None, cm.min_notional);
+
+        let settling = rules_of(SymbolMarket::UsdM, "tests/data/binance_fapi_exchange_info.json", "OLDUSDT");
+        assert!(!settling.trading);
+    }
+
+    #[test]
+    fn test_round_and_check() {
+        let um = rules_of(SymbolMarket::UsdM, "tests/data/binance_fapi_exchange_info.json", "BTCUSDT");
+        assert_eq!(dec!(58000.10), um.round_price(dec!(58000.123), OrderSide::Buy));
+        assert_eq!(dec!(58000.20), um.round_price(dec!(58000.123), OrderSide::Sell));
+        assert_eq!(dec!(58000.10), um.round_price(dec!(58000.10), OrderSide::Sell), "已经对齐的不变");
+        assert_eq!(dec!(0.012), um.round_qty(dec!(0.0129), false));
+
+        assert_eq!(Ok((Some(dec!(58000.1)), dec!(0.012))), um.normalize(OrderSide::Buy, Some(dec!(58000.123)), dec!(0.0129), false));
+        assert_eq!(Err(OrderRuleViolation::PriceNotOnTick { price: dec!(58000.15), tick_size: dec!(0.10) }), um.check(Some(dec!(58000.15)), dec!(0.01), false));
+        assert_eq!(Err(OrderRuleViolation::PriceTooLow { price: dec!(500), min_price: dec!(556.80) }), um.check(Some(dec!(500)), dec!(1), false));
+        assert_eq!(Err(OrderRuleViolation::QtyTooSmall { qty: dec!(0.0009), min_qty: dec!(0.001) }), um.normalize(OrderSide::Buy, None, dec!(0.0009), false));
+        assert_eq!(Err(OrderRuleViolation::QtyTooLarge { qty: dec!(200), max_qty: dec!(120) }), um.check(None, dec!(200), true), "市价单用MARKET_LOT_SIZE");
+        assert!(um.check(None, dec!(200), false).is_ok());
+        assert_eq!(Err(OrderRuleViolation::NotionalTooSmall { notional: dec!(58.0001), min_notional: dec!(100) }), um.check(Some(dec!(58000.1)), dec!(0.001), false));
+
+        let cm = rules_of(SymbolMarket::CoinM, "tests/data/binance_dapi_exchange_info.json", "BTCUSD_PERP");
+        assert_eq!(Ok((Some(dec!(58000.1)), dec!(3))), cm.normalize(OrderSide::Buy, Some(dec!(58000.19)), dec!(3.7), false), "币本位按照张数");
+    }
+
+    #[tokio::test]
+    async fn test_registry_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/exchangeInfo", vec![MockResponse::fixture("tests/data/binance_spot_exchange_info.json")])
+            .route(hyper::Method::GET, "/fapi/v1/exchangeInfo", vec![MockResponse::fixture("tests/data/binance_fapi_exchange_info.json")])
+            .route(hyper::Method::GET, "/dapi/v1/exchangeInfo", vec![MockResponse::fixture("tests/data/binance_dapi_exchange_info.json")]);
+        let context = server.context();
+        let registry = SymbolRegistry::new();
+        assert!(registry.is_empty());
+
+        registry.load_all(&context).await.unwrap();
+        assert_eq!(4 + 3 + 1, registry.len());
+        assert_eq!(dec!(0.01), registry.get(SymbolMarket::Spot, "BTCUSDT").unwrap().tick_size);
+        assert_eq!(dec!(0.10), registry.get(SymbolMarket::UsdM, "BTCUSDT").unwrap().tick_size, "同一个symbol不同市场分开存");
+        assert_eq!(Err(OrderRuleViolation::UnknownSymbol(String::from("ETHUSD_PERP"))), registry.rules(SymbolMarket::CoinM, "ETHUSD_PERP"));
+        assert_eq!(Err(OrderRuleViolation::SymbolNotTrading(String::from("OLDUSDT"))), registry.rules(SymbolMarket::UsdM, "OLDUSDT"));
+
+        registry.load(&context, SymbolMarket::Spot).await.unwrap();
+        assert_eq!(8, registry.len(), "重新load替换原来的");
+        assert!(!registry.reload(&context, SymbolMarket::Spot).await.unwrap(), "刚load过的市场不重新load");
+        assert_eq!(2, server.requests().iter().filter(|r| r.path == "/api/v3/exchangeInfo").count());
+    }
+
+    #[tokio::test]
+    async fn test_retain_known_with_mock() {
+        let server = MockServer::start();
+        let listed = r#"{"serverTime":1723960451595,"symbols":[
+            {"symbol":"BTCUSDT","status":"TRADING","baseAsset":"BTC","quoteAsset":"USDT","filters":[]},
+            {"symbol":"NEWUSDT","status":"TRADING","baseAsset":"NEW","quoteAsset":"USDT","filters":[]}]}"#;
+        server.route(hyper::Method::GET, "/api/v3/exchangeInfo",
+                     vec![MockResponse::fixture("tests/data/binance_spot_exchange_info.json"), MockResponse::json(200, listed)]);
+        let context = server.context();
+        let registry = SymbolRegistry::new();
+        let symbols = || vec![String::from("BTCUSDT"), String::from("NEWUSDT"), String::from("LDBTCUSDT")];
+
+        assert_eq!(vec!["BTCUSDT"], registry.retain_known(&context, SymbolMarket::Spot, symbols()).await.unwrap());
+        assert_eq!(vec!["BTCUSDT"], registry.retain_known(&context, SymbolMarket::Spot, symbols()).await.unwrap());
+        let loads = || server.requests().iter().filter(|r| r.path == "/api/v3/exchangeInfo").count();
+        assert_eq!(1, loads(), "缓存没有过期，找不到的交易对不重新load");
+
+        let expired = Instant::now().checked_sub(SNAPSHOT_TTL).unwrap();
+        registry.loaded_at.lock().unwrap().insert(SymbolMarket::Spot, expired);
+        assert_eq!(vec!["BTCUSDT", "NEWUSDT"], registry.retain_known(&context, SymbolMarket::Spot, symbols()).await.unwrap(), "新上线的交易对");
+        assert_eq!(2, loads());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::models::UnixTimeStamp;
+use crate::utils::unix_time;
+use log::debug;
+use std::sync::Mutex;
+
+/** 新的样本占的比例是1/SMOOTHING，避免一次网络抖动就把offset带偏
+ */
+const SMOOTHING: i64 = 4;
+
+/** 本地时间和币安服务器时间的差值。容器里面的时钟经常会漂，直接用本地时间签名会被-1021拒绝。
+* 通过/api/v3/time采样，offset = 服务器时间 - 请求来回的中间时刻，然后做平滑。
+* papi没有单独的time接口，和现货用的是同一个撮合时钟，所以共用一个offset
+*/
+#[derive(Debug, Default)]
+pub struct ServerClock {
+    state: Mutex<ClockState>,
+}
+
+#[derive(Debug, Default)]
+struct ClockState {
+    offset: Option<i64>,
+    synced_at: Option<UnixTimeStamp>,
+}
+
+impl ServerClock {
+    pub fn new() -> ServerClock {
+        ServerClock::default()
+    }
+
+    /** 修正过的当前时间，没同步过就是本地时间
+     */
+    pub fn now(&self) -> UnixTimeStamp {
+        (unix_time() as i64 + self.offset()) as UnixTimeStamp
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.state.lock().unwrap().offset.unwrap_or(0)
+    }
+
+    /** 从来没同步过，或者上次同步超过max_age_ms，或者被invalidate过
+     */
+    pub fn needs_sync(&self, max_age_ms: u64) -> bool {
+        match self.state.lock().unwrap().synced_at {
+            None => true,
+            Some(synced_at) => unix_time().saturating_sub(synced_at) > max_age_ms,
+        }
+    }
+
+    /** 收到-1021的时候调用，下次请求之前重新同步
+     */
+    pub fn invalidate(&self) {
+        self.state.lock().unwrap().synced_at = None;
+    }
+
+    /** request_at和response_at是本地发请求和收到返回的时间
+     */
+    pub fn update(&self, request_at: UnixTimeStamp, server_time: UnixTimeStamp, response_at: UnixTimeStamp) {
+        let local_mid = (request_at + response_at) / 2;
+        let sample = server_time as i64 - local_mid as i64;
+
+        let mut state = self.state.lock().unwrap();
+        let offset = match state.offset {
+            None => sample,
+            Some(offset) => offset + (sample - offset) / SMOOTHING,
+        };
+        debug!("server time sample:{}ms,round trip:{}ms,offset:{}ms", sample, response_at - request_at, offset);
+        state.offset = Some(offset);
+        state.synced_at = Some(response_at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_offset_smoothing() {
+        let clock = ServerClock::new();
+        assert!(clock.needs_sync(60_000));
+        assert_eq!(0, clock.offset());
+
+        clock.update(1000, 2100, 1200);
+        assert_eq!(1000, clock.offset(), "第一次直接用样本");
+
+        clock.update(5000, 6500, 5200);
+        assert_eq!(1100, clock.offset(), "后面的样本按照1/4平滑");
+
+        clock.update(9000, 9100, 9200);
+        assert_eq!(825, clock.offset());
+    }
+
+    #[test]
+    fn test_clock_needs_sync() {
+        let clock = ServerClock::new();
+        let now = unix_time();
+        clock.update(now, now + 3000, now);
+        assert!(!clock.needs_sync(60_000));
+        assert!(clock.now() >= now + 3000);
+
+        clock.invalidate();
+        assert!(clock.needs_sync(60_000), "invalidate之后需要重新同步");
+        assert_eq!(3000, clock.offset(), "invalidate不影响offset");
+    }
+}
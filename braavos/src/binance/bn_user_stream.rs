@@ -0,0 +1,361 @@
+use crate::binance::bn_commands::{BNCommand, DeleteCommand, PostCommand, PutCommand, DEFAULT_CONTEXT};
+use crate::binance::bn_models::{BinanceBase, BinanceContext, BinancePath, CommandInfo, ListenKey, PmAPI, UserDataEvent};
+use crate::errors::{BinanceErrorCode, BraavosError};
+use crate::models::EmptyObject;
+use crate::retry::RetryPolicy;
+use crate::settings::Account;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+pub const PM_USER_STREAM_URL: &str = "wss://fstream.binance.com/pm/ws/";
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/** 统一账户用户数据流的配置。url后面直接拼listenKey
+ */
+#[derive(Debug, Clone)]
+pub struct UserStreamConfig {
+    pub url: String,
+    pub keepalive_interval: Duration, //listenKey 60分钟没有延期就会过期，币安建议30分钟延期一次
+    pub reconnect: RetryPolicy,       //只用里面的退避时间，断线之后会一直重连，直到handle被drop
+    pub channel_size: usize,
+}
+
+impl UserStreamConfig {
+    pub fn new(url: &str) -> UserStreamConfig {
+        UserStreamConfig {
+            url: String::from(url),
+            keepalive_interval: Duration::from_secs(30 * 60),
+            reconnect: RetryPolicy {
+                max_attempts: u32::MAX,
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+            },
+            channel_size: 1024,
+        }
+    }
+}
+
+/** 断线期间的推送会丢，收到Connected之后调用方应该用REST重新对一下账户
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserStreamEvent {
+    Connected,
+    Disconnected(String),
+    Data(UserDataEvent),
+}
+
+/** 统一账户的listenKey和用户数据流。同一个api key只有一个listenKey，重复申请返回的是同一个，并且会延期
+ */
+pub struct PMUserDataStream {
+    pub account: Account,
+    pub context: BinanceContext,
+}
+
+impl PMUserDataStream {
+    pub fn new(account: Account) -> PMUserDataStream {
+        PMUserDataStream::with_context(account, DEFAULT_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: BinanceContext) -> PMUserDataStream {
+        PMUserDataStream { account, context }
+    }
+
+    pub async fn create_listen_key(&self) -> Result<String, BraavosError> {
+        let post = PostCommand::<EmptyObject, ListenKey> { phantom: Default::default(), idempotent: true };
+        let key = post.execute_with_retry(|| (self.command_info(), None)).await?;
+        Ok(key.listen_key)
+    }
+
+    /** 延期60分钟。listenKey已经过期的话返回-1125
+     */
+    pub async fn keepalive_listen_key(&self) -> Result<(), BraavosError> {
+        let put = PutCommand::<EmptyObject, EmptyObject> { phantom: Default::default() };
+        put.execute_with_retry(|| (self.command_info(), None)).await.map(|_| ())
+    }
+
+    pub async fn close_listen_key(&self) -> Result<(), BraavosError> {
+        let delete = DeleteCommand::<EmptyObject, EmptyObject> { phantom: Default::default() };
+        delete.execute_with_retry(|| (self.command_info(), None)).await.map(|_| ())
+    }
+
+    /** 在后台的task里面维护连接：
+    * 1. 按照keepalive_interval延期listenKey
+    * 2. 服务器的ping直接回pong
+    * 3. 收到listenKeyExpired或者延期的时候发现listenKey不存在，重新申请listenKey再连接
+    * 4. 断线之后按照退避时间重连
+    *
+    * handle被drop或者close之后，关闭listenKey并退出。需要在tokio的runtime里面调用
+    */
+    pub fn start(self, config: UserStreamConfig) -> (UserStreamHandle, mpsc::Receiver<UserStreamEvent>) {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (event_tx, event_rx) = mpsc::channel(config.channel_size);
+        let task = tokio::spawn(run(self, config, shutdown_rx, event_tx));
+        (UserStreamHandle { shutdown: shutdown_tx, task }, event_rx)
+    }
+
+    fn command_info(&self) -> CommandInfo<'_> {
+        // listenKey只需要api key的头，不用签名
+        self.context.command_info_with_security(BinanceBase::PortfolioMargin,
+                                                BinancePath::PAPI(PmAPI::ListenKeyAPI),
+                                                &self.account.api_key,
+                                                &self.account.secret)
+    }
+}
+
+pub struct UserStreamHandle {
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl UserStreamHandle {
+    /** 等后台task关闭listenKey之后再返回
+     */
+    pub async fn close(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.task.await;
+    }
+}
+
+enum SessionEnd {
+    Closed,
+    Expired,
+    Disconnected(String),
+}
+
+async fn run(stream: PMUserDataStream,
+             config: UserStreamConfig,
+             mut shutdown: oneshot::Receiver<()>,
+             events: mpsc::Sender<UserStreamEvent>) {
+    let name = stream.account.name.clone();
+    let mut listen_key: Option<String> = None;
+    let mut attempt = 0;
+    loop {
+        let key = match listen_key.take() {
+            Some(key) => Some(key),
+            None => match stream.create_listen_key().await {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    warn!("{} can't create listenKey: {}", name, e);
+                    None
+                }
+            },
+        };
+
+        if let Some(key) = key {
+            match connect_async(format!("{}{}", config.url, key)).await {
+                Ok((ws, _)) => {
+                    info!("{} user stream connected", name);
+                    attempt = 0;
+                    if events.send(UserStreamEvent::Connected).await.is_err() {
+                        close(&stream).await;
+                        return;
+                    }
+                    let reason = match session(ws, &stream, &config, &mut shutdown, &events).await {
+                        SessionEnd::Closed => {
+                            close(&stream).await;
+                            return;
+                        }
+                        SessionEnd::Expired => String::from("listenKey expired"),
+                        SessionEnd::Disconnected(reason) => {
+                            listen_key = Some(key);
+                            reason
+                        }
+                    };
+                    warn!("{} user stream disconnected: {}", name, reason);
+                    if events.send(UserStreamEvent::Disconnected(reason)).await.is_err() {
+                        close(&stream).await;
+                        return;
+                    }
+                    if listen_key.is_none() {
+                        // listenKey过期不是网络的问题，马上重新申请
+                        continue;
+                    }
+                }
+                // 连不上也可能是listenKey已经失效，下次重新申请，没过期的话币安返回的还是同一个
+                Err(e) => warn!("{} can't connect user stream: {}", name, e),
+            }
+        }
+
+        attempt += 1;
+        let wait = config.reconnect.backoff(attempt);
+        debug!("{} reconnect user stream after {:?}", name, wait);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut shutdown => {
+                close(&stream).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn close(stream: &PMUserDataStream) {
+    match stream.close_listen_key().await {
+        Ok(_) => info!("{} listenKey closed", stream.account.name),
+        Err(e) => warn!("{} can't close listenKey: {}", stream.account.name, e),
+    }
+}
+
+async fn session(ws: WsStream,
+                 stream: &PMUserDataStream,
+                 config: &UserStreamConfig,
+                 shutdown: &mut oneshot::Receiver<()>,
+                 events: &mpsc::Sender<UserStreamEvent>) -> SessionEnd {
+    let (mut write, mut read) = ws.split();
+    let mut keepalive = interval_at(Instant::now() + config.keepalive_interval, config.keepalive_interval);
+    loop {
+        tokio::select! {
+            _ = &mut *shutdown => {
+                let _ = write.send(Message::Close(None)).await;
+                return SessionEnd::Closed;
+            }
+            _ = keepalive.tick() => match stream.keepalive_listen_key().await {
+                Ok(_) => debug!("{} listenKey extended", stream.account.name),
+                Err(e) if e.binance_code() == Some(BinanceErrorCode::InvalidListenKey) => return SessionEnd::Expired,
+                // 延期失败的话listenKey还有时间，下次再延期
+                Err(e) => warn!("{} can't extend listenKey: {}", stream.account.name, e),
+            },
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let event = match serde_json::from_str::<UserDataEvent>(&text) {
+                        Ok(event) => event,
+                        Err(e) => {
+                            warn!("can't parse user data event ({}): {}", e, text);
+                            continue;
+                        }
+                    };
+                    if event == UserDataEvent::ListenKeyExpired {
+                        return SessionEnd::Expired;
+                    }
+                    if events.send(UserStreamEvent::Data(event)).await.is_err() {
+                        return SessionEnd::Closed;
+                    }
+                }
+                Some(Ok(Message::Ping(payload))) => {
+                    if let Err(e) = write.send(Message::Pong(payload)).await {
+                        return SessionEnd::Disconnected(e.to_string());
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => return SessionEnd::Disconnected(format!("closed by server: {:?}", frame)),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return SessionEnd::Disconnected(e.to_string()),
+                None => return SessionEnd::Disconnected(String::from("connection closed")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::settings::test_account;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_hdr_async;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    const ORDER_UPDATE: &str = r#"{"e":"ORDER_TRADE_UPDATE","fs":"UM","E":1568879465651,"T":1568879465650,"i":"","o":{"s":"BTCUSDT","c":"TEST","S":"BUY","o":"LIMIT","f":"GTC","q":"0.001","p":"58000","ap":"58000","sp":"0","x":"TRADE","X":"FILLED","i":8886774,"l":"0.001","z":"0.001","L":"58000","N":"USDT","n":"0.0116","T":1568879465650,"t":12,"b":"0","a":"0","m":true,"R":false,"ps":"BOTH","rp":"0"}}"#;
+    const ACCOUNT_UPDATE: &str = r#"{"e":"ACCOUNT_UPDATE","fs":"UM","E":1564745798939,"T":1564745798938,"i":"","a":{"m":"ORDER","B":[{"a":"USDT","wb":"1000","cw":"1000","bc":"0"}],"P":[{"s":"BTCUSDT","pa":"0.001","ep":"58000","cr":"0","up":"0","ps":"BOTH","bep":"58000"}]}}"#;
+    const EXPIRED: &str = r#"{"e":"listenKeyExpired","E":1576653824250,"listenKey":"key1"}"#;
+
+    /** 本地的用户数据服务器，连接的path转发到channel里面。
+    * 第一个连接发一条订单推送和listenKeyExpired，后面的连接发一条账户推送
+    */
+    // 握手的回调是tungstenite定义的，Err的类型改不了
+    #[allow(clippy::result_large_err)]
+    async fn mock_user_stream_server() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/pm/ws/", listener.local_addr().unwrap());
+        let (record_tx, record_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut connection = 0;
+            while let Ok((tcp, _)) = listener.accept().await {
+                connection += 1;
+                let record = record_tx.clone();
+                let callback = |request: &Request, response: Response| {
+                    let _ = record.send(request.uri().path().to_string());
+                    Ok(response)
+                };
+                let mut ws = accept_hdr_async(tcp, callback).await.unwrap();
+                tokio::spawn(async move {
+                    if connection == 1 {
+                        ws.send(Message::Text(String::from(ORDER_UPDATE))).await.unwrap();
+                        ws.send(Message::Text(String::from(EXPIRED))).await.unwrap();
+                    } else {
+                        ws.send(Message::Text(String::from(ACCOUNT_UPDATE))).await.unwrap();
+                    }
+                    while let Some(Ok(_)) = ws.next().await {}
+                });
+            }
+        });
+        (url, record_rx)
+    }
+
+    #[tokio::test]
+    async fn test_listen_key_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::POST, "/papi/v1/listenKey", vec![MockResponse::json(200, r#"{"listenKey":"key1"}"#)])
+            .route(hyper::Method::PUT, "/papi/v1/listenKey", vec![MockResponse::json(200, "{}")])
+            .route(hyper::Method::DELETE, "/papi/v1/listenKey", vec![MockResponse::json(200, "{}")]);
+        let stream = PMUserDataStream::with_context(test_account(), server.context());
+
+        assert_eq!("key1", stream.create_listen_key().await.unwrap());
+        stream.keepalive_listen_key().await.unwrap();
+        stream.close_listen_key().await.unwrap();
+
+        let requests = server.requests();
+        let methods: Vec<hyper::Method> = requests.iter().map(|r| r.method.clone()).collect();
+        assert_eq!(vec![hyper::Method::POST, hyper::Method::PUT, hyper::Method::DELETE], methods);
+        for request in requests {
+            assert_eq!(Some(String::from("key")), request.api_key);
+            assert!(!request.body.contains("signature"), "listenKey不用签名:{}", request.body);
+            assert!(!request.query.clone().unwrap_or_default().contains("signature"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_stream_renew_expired_listen_key() {
+        let server = MockServer::start();
+        server.route(hyper::Method::POST, "/papi/v1/listenKey",
+                     vec![MockResponse::json(200, r#"{"listenKey":"key1"}"#), MockResponse::json(200, r#"{"listenKey":"key2"}"#)])
+            .route(hyper::Method::PUT, "/papi/v1/listenKey", vec![MockResponse::json(200, "{}")])
+            .route(hyper::Method::DELETE, "/papi/v1/listenKey", vec![MockResponse::json(200, "{}")]);
+        let (url, mut paths) = mock_user_stream_server().await;
+        let mut config = UserStreamConfig::new(&url);
+        config.keepalive_interval = Duration::from_millis(20);
+        config.reconnect.base_delay = Duration::from_millis(10);
+
+        let stream = PMUserDataStream::with_context(test_account(), server.context());
+        let (handle, mut events) = stream.start(config);
+
+        let timeout = Duration::from_secs(5);
+        let mut received = vec![];
+        while received.len() < 5 {
+            received.push(tokio::time::timeout(timeout, events.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(UserStreamEvent::Connected, received[0]);
+        assert!(matches!(&received[1], UserStreamEvent::Data(UserDataEvent::OrderTradeUpdate(e)) if e.order.commission == Some(rust_decimal_macros::dec!(0.0116))));
+        assert_eq!(UserStreamEvent::Disconnected(String::from("listenKey expired")), received[2]);
+        assert_eq!(UserStreamEvent::Connected, received[3]);
+        assert!(matches!(&received[4], UserStreamEvent::Data(UserDataEvent::AccountUpdate(_))));
+
+        assert_eq!("/pm/ws/key1", paths.recv().await.unwrap());
+        assert_eq!("/pm/ws/key2", paths.recv().await.unwrap(), "过期之后用新的listenKey连接");
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.close().await;
+
+        let methods: Vec<hyper::Method> = server.requests().iter().map(|r| r.method.clone()).collect();
+        assert_eq!(2, methods.iter().filter(|m| **m == hyper::Method::POST).count());
+        assert!(methods.contains(&hyper::Method::PUT), "应该延期过listenKey");
+        assert_eq!(Some(&hyper::Method::DELETE), methods.last(), "关闭的时候删除listenKey");
+    }
+}
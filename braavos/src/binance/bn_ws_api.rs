@@ -0,0 +1,492 @@
+use crate::binance::bn_models::{BinanceContext, ServerTime, SpotAccountStatus, SpotOrderRequest, SpotOrderResponse, WsMethod};
+use crate::binance::bn_ws_commands::WsRequest;
+use crate::errors::BraavosError;
+use crate::models::UnixTimeStamp;
+use crate::settings::Account;
+use crate::utils::sign_hmac;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::pkcs8::DecodePrivateKey;
+use ed25519_dalek::{Signer, SigningKey};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+pub const WS_API_URL: &str = "wss://ws-api.binance.com:443/ws-api/v3";
+pub const WS_API_TESTNET_URL: &str = "wss://ws-api.testnet.binance.vision/ws-api/v3";
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/** WS API的签名方式。HMAC每个请求都要签名，Ed25519可以session.logon之后不再签名
+ */
+pub enum WsApiCredentials {
+    Hmac { api_key: String, secret: String },
+    Ed25519 { api_key: String, key: SigningKey },
+}
+
+impl WsApiCredentials {
+    pub fn hmac(account: &Account) -> WsApiCredentials {
+        WsApiCredentials::Hmac { api_key: account.api_key.clone(), secret: account.secret.clone() }
+    }
+
+    /** private_key_pem是自己生成的Ed25519私钥，PKCS#8的PEM格式，公钥需要先在币安上注册拿到api key
+     */
+    pub fn ed25519(api_key: &str, private_key_pem: &str) -> Result<WsApiCredentials, BraavosError> {
+        let key = SigningKey::from_pkcs8_pem(private_key_pem)
+            .map_err(|e| BraavosError::Custom(format!("invalid ed25519 private key: {}", e)))?;
+        Ok(WsApiCredentials::Ed25519 { api_key: String::from(api_key), key })
+    }
+
+    pub fn api_key(&self) -> &str {
+        match self {
+            WsApiCredentials::Hmac { api_key, .. } => api_key,
+            WsApiCredentials::Ed25519 { api_key, .. } => api_key,
+        }
+    }
+
+    /** HMAC是16进制，Ed25519是base64
+     */
+    fn sign(&self, payload: &str) -> String {
+        match self {
+            WsApiCredentials::Hmac { secret, .. } => sign_hmac(payload, secret).unwrap(),
+            WsApiCredentials::Ed25519 { key, .. } => STANDARD.encode(key.sign(payload.as_bytes()).to_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WsApiConfig {
+    pub url: String,
+    pub request_timeout: Duration,
+    pub recv_window: u64,
+}
+
+impl WsApiConfig {
+    pub fn new(url: &str) -> WsApiConfig {
+        WsApiConfig {
+            url: String::from(url),
+            request_timeout: Duration::from_secs(10),
+            recv_window: 5000,
+        }
+    }
+}
+
+struct ApiCommand {
+    request: WsRequest,
+    reply: oneshot::Sender<Result<Value, BraavosError>>,
+}
+
+/** WS API的客户端，请求和返回按照id对应起来。连接在后台的task里面维护，服务器的ping直接回pong。
+* 断线之后不会自动重连，因为session.logon的状态也没了，之后的请求都返回错误，调用方需要重新connect
+*/
+#[derive(Clone)]
+pub struct WsApiClient {
+    commands: mpsc::UnboundedSender<ApiCommand>,
+    credentials: Arc<WsApiCredentials>,
+    context: BinanceContext, //只用里面的时钟，timestamp和REST一样按照服务器的时间算
+    logged_on: Arc<AtomicBool>,
+    request_timeout: Duration,
+    recv_window: u64,
+}
+
+impl WsApiClient {
+    pub async fn connect(config: WsApiConfig, credentials: WsApiCredentials, context: BinanceContext) -> Result<WsApiClient, BraavosError> {
+        let (ws, _) = connect_async(config.url.as_str()).await
+            .map_err(|e| BraavosError::WebSocket(format!("can't connect {}: {}", config.url, e)))?;
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(ws, command_rx));
+        Ok(WsApiClient {
+            commands: command_tx,
+            credentials: Arc::new(credentials),
+            context,
+            logged_on: Arc::new(AtomicBool::new(false)),
+            request_timeout: config.request_timeout,
+            recv_window: config.recv_window,
+        })
+    }
+
+    pub async fn ping(&self) -> Result<(), BraavosError> {
+        self.request(WsMethod::Ping, BTreeMap::new()).await.map(|_| ())
+    }
+
+    pub async fn server_time(&self) -> Result<UnixTimeStamp, BraavosError> {
+        let time: ServerTime = parse(self.request(WsMethod::Time, BTreeMap::new()).await?)?;
+        Ok(time.server_time)
+    }
+
+    /** 只有Ed25519的key可以logon，之后这个连接上的请求不用再带apiKey和签名
+     */
+    pub async fn session_logon(&self) -> Result<(), BraavosError> {
+        if !matches!(*self.credentials, WsApiCredentials::Ed25519 { .. }) {
+            return Err(BraavosError::Custom(String::from("session.logon needs an ed25519 api key")));
+        }
+        self.logged_on.store(false, Ordering::SeqCst);
+        self.signed_request(WsMethod::SessionLogon, BTreeMap::new()).await?;
+        self.logged_on.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub async fn session_logout(&self) -> Result<(), BraavosError> {
+        self.request(WsMethod::SessionLogout, BTreeMap::new()).await?;
+        self.logged_on.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub async fn place_order(&self, request: &SpotOrderRequest) -> Result<SpotOrderResponse, BraavosError> {
+        parse(self.signed_request(WsMethod::OrderPlace, params_of(request)).await?)
+    }
+
+    pub async fn cancel_order(&self, symbol: &str, order_id: u64) -> Result<SpotOrderResponse, BraavosError> {
+        parse(self.signed_request(WsMethod::OrderCancel, order_params(symbol, order_id)).await?)
+    }
+
+    pub async fn order_status(&self, symbol: &str, order_id: u64) -> Result<SpotOrderResponse, BraavosError> {
+        parse(self.signed_request(WsMethod::OrderStatus, order_params(symbol, order_id)).await?)
+    }
+
+    pub async fn account_status(&self) -> Result<SpotAccountStatus, BraavosError> {
+        parse(self.signed_request(WsMethod::AccountStatus, BTreeMap::new()).await?)
+    }
+
+    /** 加上timestamp和recvWindow。没有logon的话再加上apiKey，所有参数按照名字排序之后签名
+     */
+    pub async fn signed_request(&self, method: WsMethod, mut params: BTreeMap<String, Value>) -> Result<Value, BraavosError> {
+        self.context.ensure_time_synced().await;
+        params.insert(String::from("timestamp"), Value::from(self.context.clock.now()));
+        if method != WsMethod::SessionLogon {
+            params.insert(String::from("recvWindow"), Value::from(self.recv_window));
+        }
+        if !self.logged_on.load(Ordering::SeqCst) {
+            params.insert(String::from("apiKey"), Value::from(self.credentials.api_key()));
+            let signature = self.credentials.sign(&payload_of(&params));
+            params.insert(String::from("signature"), Value::from(signature));
+        }
+        self.request(method, params).await
+    }
+
+    pub async fn request(&self, method: WsMethod, params: BTreeMap<String, Value>) -> Result<Value, BraavosError> {
+        let request = if params.is_empty() {
+            WsRequest::empty_new(method)
+        } else {
+            WsRequest::with_params(method, Value::Object(params.into_iter().collect()))
+        };
+        let (reply, response) = oneshot::channel();
+        self.commands.send(ApiCommand { request, reply })
+            .map_err(|_| BraavosError::WebSocket(String::from("ws api connection is closed")))?;
+        match tokio::time::timeout(self.request_timeout, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(BraavosError::WebSocket(format!("{} failed, connection lost", method.name()))),
+            Err(_) => Err(BraavosError::WebSocket(format!("{} timeout after {:?}", method.name(), self.request_timeout))),
+        }
+    }
+}
+
+fn parse<U: DeserializeOwned>(result: Value) -> Result<U, BraavosError> {
+    serde_json::from_value(result.clone()).map_err(|source| BraavosError::Deserialize { source, body: result.to_string() })
+}
+
+/** 请求的Display是REST用的query string，拆开放到params里面
+ */
+fn params_of<T: Display>(request: &T) -> BTreeMap<String, Value> {
+    url::form_urlencoded::parse(request.to_string().as_bytes())
+        .map(|(k, v)| (k.into_owned(), Value::from(v.into_owned())))
+        .collect()
+}
+
+fn order_params(symbol: &str, order_id: u64) -> BTreeMap<String, Value> {
+    BTreeMap::from([
+        (String::from("symbol"), Value::from(symbol)),
+        (String::from("orderId"), Value::from(order_id)),
+    ])
+}
+
+/** 签名的内容，BTreeMap已经按照参数名排好序了
+ */
+fn payload_of(params: &BTreeMap<String, Value>) -> String {
+    params.iter()
+        .map(|(k, v)| match v {
+            Value::String(s) => format!("{}={}", k, s),
+            other => format!("{}={}", k, other),
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/** 返回：{"id":"1","status":200,"result":{...},"rateLimits":[...]}
+* 错误：{"id":"1","status":400,"error":{"code":-2010,"msg":"..."}}
+*/
+#[derive(Debug, Deserialize)]
+struct ApiResponse {
+    id: Option<Value>,
+    #[serde(default)]
+    status: u16,
+    #[serde(default)]
+    result: Value,
+    error: Option<ApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: i64,
+    msg: String,
+}
+
+async fn run(ws: WsStream, mut commands: mpsc::UnboundedReceiver<ApiCommand>) {
+    let (mut write, mut read) = ws.split();
+    let mut pending: HashMap<String, oneshot::Sender<Result<Value, BraavosError>>> = HashMap::new();
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                None => {
+                    let _ = write.send(Message::Close(None)).await;
+                    return;
+                }
+                Some(ApiCommand { request, reply }) => {
+                    debug!("ws api request:{} {}", request.method().name(), request.id());
+                    match write.send(Message::Text(request.to_json())).await {
+                        Ok(_) => track(&mut pending, String::from(request.id()), reply),
+                        Err(e) => {
+                            let _ = reply.send(Err(BraavosError::WebSocket(e.to_string())));
+                            warn!("ws api disconnected: {}", e);
+                            return;
+                        }
+                    }
+                }
+            },
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => complete(&text, &mut pending),
+                Some(Ok(Message::Ping(payload))) => {
+                    if let Err(e) = write.send(Message::Pong(payload)).await {
+                        warn!("ws api disconnected: {}", e);
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    warn!("ws api closed by server: {:?}", frame);
+                    return;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!("ws api disconnected: {}", e);
+                    return;
+                }
+                None => {
+                    warn!("ws api connection closed");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/** 超时的请求不会再等返回，reply的接收端已经drop了。每次加新请求的时候顺便清理掉，避免连接用得久了越积越多
+ */
+fn track(pending: &mut HashMap<String, oneshot::Sender<Result<Value, BraavosError>>>, id: String, reply: oneshot::Sender<Result<Value, BraavosError>>) {
+    pending.retain(|_, reply| !reply.is_closed());
+    pending.insert(id, reply);
+}
+
+fn complete(text: &str, pending: &mut HashMap<String, oneshot::Sender<Result<Value, BraavosError>>>) {
+    let response: ApiResponse = match serde_json::from_str(text) {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("can't parse ws api message ({}): {}", e, text);
+            return;
+        }
+    };
+    let id = match response.id {
+        Some(Value::String(id)) => id,
+        Some(other) => other.to_string(),
+        None => {
+            debug!("ws api message without id: {}", text);
+            return;
+        }
+    };
+    let reply = match pending.remove(&id) {
+        Some(reply) => reply,
+        None => {
+            debug!("response of unknown request {}: {}", id, text);
+            return;
+        }
+    };
+    let result = match response.error {
+        Some(error) => Err(BraavosError::Binance { status: response.status, code: error.code.into(), msg: error.msg }),
+        None => Ok(response.result),
+    };
+    let _ = reply.send(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::binance::bn_models::{OrderSide, OrderStatus, OrderType, TimeInForce};
+    use crate::errors::BinanceErrorCode;
+    use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+    use ed25519_dalek::pkcs8::EncodePrivateKey;
+    use ed25519_dalek::{Signature, Verifier};
+    use rust_decimal_macros::dec;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    const SECRET: &str = "secret";
+    const SEED: [u8; 32] = [7; 32];
+
+    fn test_account() -> Account {
+        Account {
+            secret: SECRET.to_string(),
+            ..crate::settings::test_account()
+        }
+    }
+
+    /** 检查签名，按照method返回。收到的params转发到channel里面
+     */
+    fn mock_response(method: &str, params: &serde_json::Map<String, Value>) -> Value {
+        let signed: BTreeMap<String, Value> = params.iter()
+            .filter(|(k, _)| k.as_str() != "signature")
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let signature = params.get("signature").and_then(|s| s.as_str());
+        match method {
+            "time" => serde_json::json!({"status": 200, "result": {"serverTime": 1723960451595u64}}),
+            "session.logon" => {
+                let verifying = SigningKey::from_bytes(&SEED).verifying_key();
+                let signature = Signature::from_slice(&STANDARD.decode(signature.unwrap()).unwrap()).unwrap();
+                assert!(verifying.verify(payload_of(&signed).as_bytes(), &signature).is_ok(), "ed25519签名不对");
+                serde_json::json!({"status": 200, "result": {"apiKey": "key", "authorizedSince": 1723960451595u64}})
+            }
+            "order.place" => {
+                assert_eq!(sign_hmac(&payload_of(&signed), SECRET).unwrap(), signature.unwrap(), "hmac签名不对");
+                serde_json::json!({"status": 200, "result": {
+                    "symbol": "BTCUSDT", "orderId": 12569099453u64, "orderListId": -1, "clientOrderId": params["newClientOrderId"],
+                    "transactTime": 1723960451595u64, "price": "58000.00000000", "origQty": "0.01000000", "executedQty": "0.00000000",
+                    "cummulativeQuoteQty": "0.00000000", "status": "NEW", "timeInForce": "GTC", "type": "LIMIT", "side": "BUY", "fills": []}})
+            }
+            "order.cancel" => serde_json::json!({"status": 400, "error": {"code": -2011, "msg": "Unknown order sent."}}),
+            "account.status" => serde_json::json!({"status": 200, "result": {
+                "canTrade": true, "canWithdraw": true, "canDeposit": true, "updateTime": 1723960451595u64, "accountType": "SPOT",
+                "balances": [{"asset": "BTC", "free": "0.5", "locked": "0.1"}]}}),
+            _ => serde_json::json!({"status": 400, "error": {"code": -1100, "msg": "unknown method"}}),
+        }
+    }
+
+    async fn mock_ws_api_server() -> (String, mpsc::UnboundedReceiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws-api/v3", listener.local_addr().unwrap());
+        let (record_tx, record_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let record = record_tx.clone();
+                let mut ws = accept_async(tcp).await.unwrap();
+                tokio::spawn(async move {
+                    while let Some(Ok(Message::Text(text))) = ws.next().await {
+                        let request: Value = serde_json::from_str(&text).unwrap();
+                        let params = request["params"].as_object().cloned().unwrap_or_default();
+                        let mut response = mock_response(request["method"].as_str().unwrap(), &params);
+                        response["id"] = request["id"].clone();
+                        let _ = record.send(request);
+                        ws.send(Message::Text(response.to_string())).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, record_rx)
+    }
+
+    fn time_server() -> MockServer {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v3/time", vec![MockResponse::json(200, "{\"serverTime\":1723960451595}")]);
+        server
+    }
+
+    #[test]
+    fn test_payload_of() {
+        let params = BTreeMap::from([
+            (String::from("timestamp"), Value::from(1723960451595u64)),
+            (String::from("symbol"), Value::from("BTCUSDT")),
+            (String::from("apiKey"), Value::from("key")),
+        ]);
+        assert_eq!("apiKey=key&symbol=BTCUSDT&timestamp=1723960451595", payload_of(&params), "参数按照名字排序");
+    }
+
+    #[test]
+    fn test_track_drops_timed_out_requests() {
+        let mut pending = HashMap::new();
+        let (timed_out, response) = oneshot::channel();
+        track(&mut pending, String::from("1"), timed_out);
+        drop(response);
+        let (waiting, _response) = oneshot::channel();
+        track(&mut pending, String::from("2"), waiting);
+        assert_eq!(vec!["2"], pending.keys().map(String::as_str).collect::<Vec<_>>(), "超时的请求不用再留着");
+    }
+
+    #[tokio::test]
+    async fn test_hmac_request_with_mock() {
+        let server = time_server();
+        let (url, mut records) = mock_ws_api_server().await;
+        let client = WsApiClient::connect(WsApiConfig::new(&url), WsApiCredentials::hmac(&test_account()), server.context()).await.unwrap();
+
+        assert_eq!(1723960451595, client.server_time().await.unwrap());
+        assert!(records.recv().await.unwrap().get("params").is_none(), "time不用参数");
+
+        let request = SpotOrderRequest {
+            symbol: String::from("BTCUSDT"),
+            side: OrderSide::Buy,
+            order_type: OrderType::Limit,
+            quantity: Some(dec!(0.010)),
+            quote_order_qty: None,
+            price: Some(dec!(58000.00)),
+            time_in_force: Some(TimeInForce::GTC),
+            new_client_order_id: String::from("abc"),
+        };
+        let order = client.place_order(&request).await.unwrap();
+        assert_eq!("abc", order.client_order_id);
+        assert_eq!(Some(OrderStatus::New), order.status);
+        let params = records.recv().await.unwrap()["params"].clone();
+        assert_eq!("0.01", params["quantity"]);
+        assert_eq!("key", params["apiKey"]);
+        assert_eq!(5000, params["recvWindow"]);
+
+        let error = client.cancel_order("BTCUSDT", 1).await.unwrap_err();
+        assert_eq!(Some(BinanceErrorCode::CancelRejected), error.binance_code());
+        assert_eq!(1, records.recv().await.unwrap()["params"]["orderId"], "orderId是数字");
+
+        assert!(client.session_logon().await.is_err(), "hmac的key不能logon");
+    }
+
+    #[tokio::test]
+    async fn test_ed25519_session_logon_with_mock() {
+        let server = time_server();
+        let (url, mut records) = mock_ws_api_server().await;
+        let pem = SigningKey::from_bytes(&SEED).to_pkcs8_pem(LineEnding::LF).unwrap();
+        let credentials = WsApiCredentials::ed25519("key", &pem).unwrap();
+        let client = WsApiClient::connect(WsApiConfig::new(&url), credentials, server.context()).await.unwrap();
+
+        client.session_logon().await.unwrap();
+        let logon = records.recv().await.unwrap();
+        assert_eq!("session.logon", logon["method"]);
+        assert!(logon["params"].get("recvWindow").is_none());
+
+        let account = client.account_status().await.unwrap();
+        assert_eq!(dec!(0.5), account.balances[0].free);
+        let params = records.recv().await.unwrap()["params"].clone();
+        assert!(params.get("apiKey").is_none() && params.get("signature").is_none(), "logon之后不用签名:{}", params);
+        assert!(params.get("timestamp").is_some());
+    }
+
+    #[test]
+    fn test_invalid_ed25519_key() {
+        assert!(WsApiCredentials::ed25519("key", "not a pem").is_err());
+    }
+}
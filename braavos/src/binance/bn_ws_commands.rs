@@ -1,19 +1,22 @@
 use crate::binance::bn_models::{deserialize_wx_method, serialize_wx_method, WsMethod};
 use crate::utils::SnowyFlakeWrapper;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::sync::LazyLock;
 
-const SF: LazyLock<SnowyFlakeWrapper> = LazyLock::new(|| {
+static SF: LazyLock<SnowyFlakeWrapper> = LazyLock::new(|| {
     SnowyFlakeWrapper::new()
 });
 
+/** 行情订阅的params是stream的数组，WS API的params是一个对象
+ */
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WsRequest {
     id: String,
     #[serde(serialize_with = "serialize_wx_method", deserialize_with = "deserialize_wx_method")]
     method: WsMethod,
     #[serde(skip_serializing_if = "Option::is_none")]
-    params: Option<Vec<String>>
+    params: Option<Value>
 }
 
 
@@ -23,7 +26,16 @@ impl WsRequest {
         WsRequest {
             id,
             method,
-            params
+            params: params.map(Value::from)
+        }
+    }
+
+    pub fn with_params(method: WsMethod, params: Value) -> WsRequest {
+        let id = SF.next_id_string();
+        WsRequest {
+            id,
+            method,
+            params: Some(params),
         }
     }
 
@@ -36,6 +48,14 @@ impl WsRequest {
         }
     }
 
+    pub fn method(&self) -> WsMethod {
+        self.method
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub fn to_json(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
@@ -43,6 +63,7 @@ impl WsRequest {
 
 #[cfg(test)]
 mod tests {
+    use crate::binance::bn_models::WsMethod;
     use crate::binance::bn_models::WsMethod::Ping;
     use crate::binance::bn_ws_commands::WsRequest;
 
@@ -52,4 +73,13 @@ mod tests {
         let expected = "{\"id\":\"abc\",\"method\":\"ping\"}";
         assert_eq!(expected, request.to_json(), "序列化出错")
     }
+
+    #[test]
+    fn test_ws_request_from_json() {
+        let request: WsRequest = serde_json::from_str(r#"{"id":"abc","method":"order.place","params":{"symbol":"BTCUSDT"}}"#).unwrap();
+        assert_eq!(WsMethod::OrderPlace, request.method());
+
+        let unknown = serde_json::from_str::<WsRequest>(r#"{"id":"abc","method":"order.unknown"}"#);
+        assert!(unknown.unwrap_err().to_string().contains("unknown ws method: order.unknown"), "不认识的method返回错误，不能panic");
+    }
 }
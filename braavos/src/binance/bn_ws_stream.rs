@@ -0,0 +1,463 @@
+use crate::binance::bn_models::{MarketEvent, WsMethod};
+use crate::binance::bn_ws_commands::WsRequest;
+use crate::errors::BraavosError;
+use crate::retry::RetryPolicy;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+pub const SPOT_STREAM_URL: &str = "wss://stream.binance.com:9443/stream";
+pub const UM_STREAM_URL: &str = "wss://fstream.binance.com/stream";
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsWriter = SplitSink<WsStream, Message>;
+type WsReader = SplitStream<WsStream>;
+
+/** 行情websocket的配置。url用/stream结尾的组合流地址，这样每条数据都带着stream的名字
+ */
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub url: String,
+    pub request_timeout: Duration, //SUBSCRIBE这些请求等待返回的时间
+    pub reconnect: RetryPolicy,    //只用里面的退避时间，断线之后会一直重连，直到client被drop
+    pub channel_size: usize,
+}
+
+impl StreamConfig {
+    pub fn new(url: &str) -> StreamConfig {
+        StreamConfig {
+            url: String::from(url),
+            request_timeout: Duration::from_secs(10),
+            reconnect: RetryPolicy {
+                max_attempts: u32::MAX,
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+            },
+            channel_size: 1024,
+        }
+    }
+}
+
+/** 从channel里面收到的事件。Connected和Disconnected用来让调用方知道中间可能丢了数据，比如需要重新拉深度快照
+ */
+// 绝大部分都是Data，不用Box，省得每条行情都分配一次
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    Connected,
+    Disconnected(String),
+    Data { stream: String, event: MarketEvent },
+}
+
+enum StreamCommand {
+    Request {
+        method: WsMethod,
+        params: Option<Vec<String>>,
+        reply: oneshot::Sender<Result<Value, BraavosError>>,
+    },
+}
+
+/** 行情websocket客户端。连接在后台的task里面维护：
+* 1. 请求和返回按照id对应起来
+* 2. 服务器的ping直接回pong
+* 3. 断线之后按照退避时间重连，重连成功之后重新订阅之前订阅成功的stream
+*
+* 所有的client都drop之后，后台task关闭连接并退出
+*/
+#[derive(Clone)]
+pub struct MarketStreamClient {
+    commands: mpsc::UnboundedSender<StreamCommand>,
+    request_timeout: Duration,
+}
+
+impl MarketStreamClient {
+    /** 需要在tokio的runtime里面调用
+     */
+    pub fn start(config: StreamConfig) -> (MarketStreamClient, mpsc::Receiver<StreamEvent>) {
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        let (event_tx, event_rx) = mpsc::channel(config.channel_size);
+        let client = MarketStreamClient {
+            commands: command_tx,
+            request_timeout: config.request_timeout,
+        };
+        tokio::spawn(run(config, command_rx, event_tx));
+        (client, event_rx)
+    }
+
+    pub async fn subscribe(&self, streams: &[&str]) -> Result<(), BraavosError> {
+        self.request(WsMethod::SUBSCRIBE, Some(to_params(streams))).await.map(|_| ())
+    }
+
+    pub async fn unsubscribe(&self, streams: &[&str]) -> Result<(), BraavosError> {
+        self.request(WsMethod::UNSUBSCRIBE, Some(to_params(streams))).await.map(|_| ())
+    }
+
+    /** 服务器上现在订阅的stream
+     */
+    pub async fn list_subscriptions(&self) -> Result<Vec<String>, BraavosError> {
+        let result = self.request(WsMethod::ListSubscriptions, None).await?;
+        serde_json::from_value(result.clone()).map_err(|source| BraavosError::Deserialize { source, body: result.to_string() })
+    }
+
+    async fn request(&self, method: WsMethod, params: Option<Vec<String>>) -> Result<Value, BraavosError> {
+        let (reply, response) = oneshot::channel();
+        self.commands.send(StreamCommand::Request { method, params, reply })
+            .map_err(|_| BraavosError::WebSocket(String::from("stream client is closed")))?;
+        match tokio::time::timeout(self.request_timeout, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(BraavosError::WebSocket(String::from("stream client is closed"))),
+            Err(_) => Err(BraavosError::WebSocket(format!("{:?} timeout after {:?}", method, self.request_timeout))),
+        }
+    }
+}
+
+fn to_params(streams: &[&str]) -> Vec<String> {
+    streams.iter().map(|s| String::from(*s)).collect()
+}
+
+/** 服务器发过来的内容，可能是请求的返回，也可能是行情数据
+* 返回：{"result":null,"id":"1"}，{"error":{"code":2,"msg":"Invalid request"},"id":"1"}
+* 组合流的数据：{"stream":"btcusdt@aggTrade","data":{...}}
+*/
+#[derive(Debug, Deserialize)]
+struct StreamFrame {
+    id: Option<Value>,
+    #[serde(default)]
+    result: Value,
+    error: Option<StreamError>,
+    stream: Option<String>,
+    data: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamError {
+    code: i64,
+    msg: String,
+}
+
+struct Pending {
+    method: WsMethod,
+    params: Option<Vec<String>>,
+    reply: Option<oneshot::Sender<Result<Value, BraavosError>>>, //重连之后自动订阅的请求没有调用方等着
+}
+
+enum SessionEnd {
+    Closed,
+    Disconnected(String),
+}
+
+async fn run(config: StreamConfig, mut commands: mpsc::UnboundedReceiver<StreamCommand>, events: mpsc::Sender<StreamEvent>) {
+    let mut subscriptions = BTreeSet::new();
+    let mut attempt = 0;
+    loop {
+        match connect_async(config.url.as_str()).await {
+            Ok((ws, _)) => {
+                info!("stream {} connected", config.url);
+                attempt = 0;
+                if events.send(StreamEvent::Connected).await.is_err() {
+                    return;
+                }
+                let reason = match session(ws, &mut subscriptions, &mut commands, &events).await {
+                    SessionEnd::Closed => return,
+                    SessionEnd::Disconnected(reason) => reason,
+                };
+                warn!("stream {} disconnected: {}", config.url, reason);
+                if events.send(StreamEvent::Disconnected(reason)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("can't connect stream {}: {}", config.url, e),
+        }
+
+        attempt += 1;
+        let wait = config.reconnect.backoff(attempt);
+        debug!("reconnect stream {} after {:?}", config.url, wait);
+        let sleep = tokio::time::sleep(wait);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                command = commands.recv() => match command {
+                    None => return,
+                    Some(StreamCommand::Request { method, reply, .. }) => {
+                        let _ = reply.send(Err(BraavosError::WebSocket(format!("{:?} failed, stream is reconnecting", method))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn session(ws: WsStream,
+                 subscriptions: &mut BTreeSet<String>,
+                 commands: &mut mpsc::UnboundedReceiver<StreamCommand>,
+                 events: &mpsc::Sender<StreamEvent>) -> SessionEnd {
+    let (mut write, mut read) = ws.split();
+    let mut pending = HashMap::new();
+
+    if !subscriptions.is_empty() {
+        let params: Vec<String> = subscriptions.iter().cloned().collect();
+        info!("resubscribe {:?}", params);
+        if let Err(e) = send_request(&mut write, &mut pending, WsMethod::SUBSCRIBE, Some(params), None).await {
+            return SessionEnd::Disconnected(e);
+        }
+    }
+
+    let end = read_loop(&mut write, &mut read, subscriptions, &mut pending, commands, events).await;
+    for (_, p) in pending.drain() {
+        if let Some(reply) = p.reply {
+            let _ = reply.send(Err(BraavosError::WebSocket(format!("{:?} failed, connection lost", p.method))));
+        }
+    }
+    end
+}
+
+async fn read_loop(write: &mut WsWriter,
+                   read: &mut WsReader,
+                   subscriptions: &mut BTreeSet<String>,
+                   pending: &mut HashMap<String, Pending>,
+                   commands: &mut mpsc::UnboundedReceiver<StreamCommand>,
+                   events: &mpsc::Sender<StreamEvent>) -> SessionEnd {
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                None => {
+                    let _ = write.send(Message::Close(None)).await;
+                    return SessionEnd::Closed;
+                }
+                Some(StreamCommand::Request { method, params, reply }) => {
+                    if let Err(e) = send_request(write, pending, method, params, Some(reply)).await {
+                        return SessionEnd::Disconnected(e);
+                    }
+                }
+            },
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if let Some(end) = handle_text(&text, subscriptions, pending, events).await {
+                        return end;
+                    }
+                }
+                Some(Ok(Message::Ping(payload))) => {
+                    if let Err(e) = write.send(Message::Pong(payload)).await {
+                        return SessionEnd::Disconnected(e.to_string());
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => return SessionEnd::Disconnected(format!("closed by server: {:?}", frame)),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return SessionEnd::Disconnected(e.to_string()),
+                None => return SessionEnd::Disconnected(String::from("connection closed")),
+            }
+        }
+    }
+}
+
+async fn send_request(write: &mut WsWriter,
+                      pending: &mut HashMap<String, Pending>,
+                      method: WsMethod,
+                      params: Option<Vec<String>>,
+                      reply: Option<oneshot::Sender<Result<Value, BraavosError>>>) -> Result<(), String> {
+    let request = WsRequest::new(method, params.clone());
+    debug!("stream request:{}", request.to_json());
+    match write.send(Message::Text(request.to_json())).await {
+        Ok(_) => {
+            pending.insert(String::from(request.id()), Pending { method, params, reply });
+            Ok(())
+        }
+        Err(e) => {
+            if let Some(reply) = reply {
+                let _ = reply.send(Err(BraavosError::WebSocket(e.to_string())));
+            }
+            Err(e.to_string())
+        }
+    }
+}
+
+/** 返回Some说明事件的接收方已经不在了，需要关闭连接
+ */
+async fn handle_text(text: &str,
+                     subscriptions: &mut BTreeSet<String>,
+                     pending: &mut HashMap<String, Pending>,
+                     events: &mpsc::Sender<StreamEvent>) -> Option<SessionEnd> {
+    let frame: StreamFrame = match serde_json::from_str(text) {
+        Ok(frame) => frame,
+        Err(e) => {
+            warn!("can't parse stream message ({}): {}", e, text);
+            return None;
+        }
+    };
+
+    if let Some(id) = frame.id {
+        let id = match id {
+            Value::String(id) => id,
+            other => other.to_string(),
+        };
+        match pending.remove(&id) {
+            Some(request) => complete(request, frame.result, frame.error, subscriptions),
+            None => debug!("response of unknown request {}: {}", id, text),
+        }
+        return None;
+    }
+
+    let (stream, data) = match (frame.stream, frame.data) {
+        (Some(stream), Some(data)) => (stream, data),
+        // 连的是/ws的地址，数据没有包一层
+        _ => (String::new(), serde_json::from_str(text).unwrap_or_default()),
+    };
+    let event = match MarketEvent::from_value(data.clone()) {
+        Ok(event) => event,
+        Err(e) => {
+            warn!("can't parse {} event ({}): {}", stream, e, data);
+            MarketEvent::Unknown(data)
+        }
+    };
+    let event = StreamEvent::Data { stream, event };
+    if events.send(event).await.is_err() {
+        return Some(SessionEnd::Closed);
+    }
+    None
+}
+
+fn complete(request: Pending, result: Value, error: Option<StreamError>, subscriptions: &mut BTreeSet<String>) {
+    let response = match error {
+        Some(error) => {
+            warn!("{:?} {:?} rejected: {} {}", request.method, request.params, error.code, error.msg);
+            Err(BraavosError::WebSocket(format!("{:?} rejected {}: {}", request.method, error.code, error.msg)))
+        }
+        None => {
+            let params = request.params.unwrap_or_default();
+            match request.method {
+                WsMethod::SUBSCRIBE => subscriptions.extend(params),
+                WsMethod::UNSUBSCRIBE => params.iter().for_each(|p| { subscriptions.remove(p); }),
+                _ => {}
+            }
+            Ok(result)
+        }
+    };
+    if let Some(reply) = request.reply {
+        let _ = reply.send(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    const AGG_TRADE: &str = r#"{"stream":"btcusdt@aggTrade","data":{"e":"aggTrade","E":1723960451595,"s":"BTCUSDT","a":26129,"p":"58000.01","q":"0.010","f":100,"l":105,"T":1723960451590,"m":true,"M":true}}"#;
+
+    /** 本地的行情服务器，收到的每条消息都转发到channel里面，格式是"连接序号:内容"。
+    * 第一个连接订阅成功之后发一个ping和一条数据，收到pong之后断开，用来测试重连
+    */
+    async fn mock_stream_server() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/stream", listener.local_addr().unwrap());
+        let (record_tx, record_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut connection = 0;
+            while let Ok((tcp, _)) = listener.accept().await {
+                connection += 1;
+                let record = record_tx.clone();
+                let mut ws = accept_async(tcp).await.unwrap();
+                tokio::spawn(async move {
+                    while let Some(Ok(message)) = ws.next().await {
+                        match message {
+                            Message::Text(text) => {
+                                let _ = record.send(format!("{}:{}", connection, text));
+                                let request: Value = serde_json::from_str(&text).unwrap();
+                                let id = &request["id"];
+                                let method = request["method"].as_str().unwrap();
+                                let invalid = request["params"].as_array().is_some_and(|p| p.iter().any(|s| s.as_str() == Some("bad")));
+                                let response = if invalid {
+                                    serde_json::json!({"error": {"code": 2, "msg": "Invalid request"}, "id": id})
+                                } else if method == "LIST_SUBSCRIPTIONS" {
+                                    serde_json::json!({"result": ["btcusdt@aggTrade"], "id": id})
+                                } else {
+                                    serde_json::json!({"result": null, "id": id})
+                                };
+                                ws.send(Message::Text(response.to_string())).await.unwrap();
+                                if connection == 1 && method == "SUBSCRIBE" {
+                                    ws.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+                                    ws.send(Message::Text(String::from(AGG_TRADE))).await.unwrap();
+                                }
+                            }
+                            Message::Pong(payload) => {
+                                let _ = record.send(format!("{}:pong {}", connection, String::from_utf8_lossy(&payload)));
+                                if connection == 1 {
+                                    let _ = ws.close(None).await;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+        (url, record_rx)
+    }
+
+    fn fast_config(url: &str) -> StreamConfig {
+        StreamConfig {
+            request_timeout: Duration::from_secs(5),
+            reconnect: RetryPolicy { max_attempts: u32::MAX, base_delay: Duration::from_millis(10), max_delay: Duration::from_millis(10) },
+            ..StreamConfig::new(url)
+        }
+    }
+
+    async fn next_record(records: &mut mpsc::UnboundedReceiver<String>) -> String {
+        tokio::time::timeout(Duration::from_secs(5), records.recv()).await.unwrap().unwrap()
+    }
+
+    async fn next_event(events: &mut mpsc::Receiver<StreamEvent>) -> StreamEvent {
+        tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stream_subscribe_and_reconnect() {
+        let (url, mut records) = mock_stream_server().await;
+        let (client, mut events) = MarketStreamClient::start(fast_config(&url));
+        assert_eq!(StreamEvent::Connected, next_event(&mut events).await);
+
+        client.subscribe(&["btcusdt@aggTrade"]).await.unwrap();
+        assert!(next_record(&mut records).await.starts_with("1:{\"id\":"));
+
+        match next_event(&mut events).await {
+            StreamEvent::Data { stream, event: MarketEvent::AggTrade(trade) } => {
+                assert_eq!("btcusdt@aggTrade", stream);
+                assert_eq!(rust_decimal_macros::dec!(58000.01), trade.price);
+            }
+            other => panic!("应该是行情数据:{:?}", other),
+        }
+        assert_eq!("1:pong hi", next_record(&mut records).await, "服务器的ping要回pong");
+
+        assert!(matches!(next_event(&mut events).await, StreamEvent::Disconnected(_)));
+        assert_eq!(StreamEvent::Connected, next_event(&mut events).await);
+        let resubscribe: Value = serde_json::from_str(next_record(&mut records).await.strip_prefix("2:").unwrap()).unwrap();
+        assert_eq!("SUBSCRIBE", resubscribe["method"]);
+        assert_eq!(serde_json::json!(["btcusdt@aggTrade"]), resubscribe["params"], "重连之后重新订阅");
+
+        assert_eq!(vec!["btcusdt@aggTrade".to_string()], client.list_subscriptions().await.unwrap());
+        let actual = client.subscribe(&["bad"]).await.unwrap_err();
+        assert!(matches!(actual, BraavosError::WebSocket(ref msg) if msg.contains("Invalid request")), "{}", actual);
+    }
+
+    #[test]
+    fn test_complete_updates_subscriptions() {
+        let mut subscriptions = BTreeSet::new();
+        let subscribe = Pending { method: WsMethod::SUBSCRIBE, params: Some(vec!["a".to_string(), "b".to_string()]), reply: None };
+        complete(subscribe, Value::Null, None, &mut subscriptions);
+        let unsubscribe = Pending { method: WsMethod::UNSUBSCRIBE, params: Some(vec!["a".to_string()]), reply: None };
+        complete(unsubscribe, Value::Null, None, &mut subscriptions);
+        let rejected = Pending { method: WsMethod::SUBSCRIBE, params: Some(vec!["c".to_string()]), reply: None };
+        complete(rejected, Value::Null, Some(StreamError { code: 2, msg: "Invalid request".to_string() }), &mut subscriptions);
+        assert_eq!(vec!["b"], subscriptions.iter().collect::<Vec<_>>(), "失败的订阅不记录");
+    }
+}
@@ -1,3 +1,17 @@
 pub mod bn_models;
 pub mod bn_commands;
 pub mod bn_ws_commands;
+pub mod bn_order_commands;
+pub mod bn_rate_limit;
+pub mod bn_time;
+pub mod bn_ws_stream;
+pub mod bn_order_book;
+pub mod bn_user_stream;
+pub mod bn_ws_api;
+pub mod bn_account_state;
+pub mod bn_symbol_rules;
+pub mod bn_exchange;
+pub mod bn_classic_account;
+
+#[cfg(test)]
+pub(crate) mod bn_mock_server;
@@ -0,0 +1,384 @@
+use crate::accounts::{AccountReader, RawDataQuery};
+use crate::bybit::bybit_models::{BybitAPI, BybitContext, BybitList, BybitPosition, BybitRawAccountData, BybitResponse, BybitWalletBalance, PositionListQuery, WalletBalanceQuery, BYBIT_PRODUCTION};
+use crate::errors::{BraavosError, BYBIT_SERVER_ERROR, BYBIT_TIMESTAMP_ERROR};
+use crate::models::{AccountSummary, SwapPosition, SwapSummary};
+use crate::retry::{RetryClassifier, RetryKind, RetryPolicy};
+use crate::settings::Account;
+use crate::utils::{init_client, sign_hmac, unix_time};
+use async_trait::async_trait;
+use log::{error, trace, warn};
+use reqwest::StatusCode;
+use rust_decimal_macros::dec;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::sync::LazyLock;
+use tokio::try_join;
+
+pub static DEFAULT_BYBIT_CONTEXT: LazyLock<BybitContext> = LazyLock::new(|| {
+    BybitContext::new(init_client(), BYBIT_PRODUCTION)
+});
+
+/** 持仓一页最多200条
+ */
+const POSITION_LIMIT: usize = 200;
+
+impl BybitContext {
+    pub fn new(client: reqwest::Client, base_url: &str) -> BybitContext {
+        BybitContext {
+            client,
+            base_url: String::from(base_url.trim_end_matches('/')),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> BybitContext {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /** 签名的GET请求，按照RetryPolicy重试，每次重试都重新签名
+     */
+    pub(crate) async fn get<Q: Display, U: DeserializeOwned>(&self, api: BybitAPI, query: &Q, account: &Account) -> Result<U, BraavosError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match self.send_get(api, query, account).await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+
+            let policy = &self.retry_policy;
+            if attempt >= policy.max_attempts || !self.retry_kind(&err).retryable(true) {
+                return Err(err);
+            }
+            let wait = policy.backoff(attempt);
+            warn!("bybit request failed, retry {} after {:?}:{}", attempt, wait, err);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn send_get<Q: Display, U: DeserializeOwned>(&self, api: BybitAPI, query: &Q, account: &Account) -> Result<U, BraavosError> {
+        let query_string = query.to_string();
+        let timestamp = unix_time().to_string();
+        let recv_window = account.recv_window.to_string();
+        let sign = sign_request(&account.secret, &timestamp, &account.api_key, &recv_window, &query_string);
+
+        let res = self.client.get(format!("{}{}?{}", self.base_url, api.path(), query_string))
+            .header("X-BAPI-API-KEY", &account.api_key)
+            .header("X-BAPI-TIMESTAMP", timestamp)
+            .header("X-BAPI-RECV-WINDOW", recv_window)
+            .header("X-BAPI-SIGN", sign)
+            .send()
+            .await?;
+        let status = res.status();
+        let body = res.text().await?;
+        trace!("body:{}", &body);
+        parse_response(status, body)
+    }
+}
+
+/** Bybit的错误码：10002时间戳错误肯定没有执行，10016结果未知，其他的看HTTP状态码
+ */
+impl RetryClassifier for BybitContext {
+    fn retry_kind(&self, error: &BraavosError) -> RetryKind {
+        match error {
+            BraavosError::Bybit { code, status, .. } => match *code {
+                BYBIT_TIMESTAMP_ERROR => RetryKind::NotProcessed,
+                BYBIT_SERVER_ERROR => RetryKind::OutcomeUnknown,
+                _ => RetryKind::of_status(*status),
+            },
+            _ => RetryKind::of_common(error),
+        }
+    }
+}
+
+/** 配置里面的币种，比如SOL，对应的U本位永续是SOLUSDT
+ */
+fn bybit_fra_symbols(account: &Account) -> Vec<String> {
+    match &account.funding_rate_arbitrage {
+        None => { vec![] }
+        Some(fra) => { fra.iter().map(|x| format!("{}USDT", x)).collect() }
+    }
+}
+
+/** 签名的原文是timestamp + api_key + recv_window + query string，结果是16进制
+ */
+pub(crate) fn sign_request(secret: &str, timestamp: &str, api_key: &str, recv_window: &str, query_string: &str) -> String {
+    sign_hmac(&format!("{}{}{}{}", timestamp, api_key, recv_window, query_string), secret).unwrap()
+}
+
+/** 先按照{retCode,retMsg,result}解析，retCode是0再解析result。IP限频的时候返回的是403
+ */
+fn parse_response<U: DeserializeOwned>(status: StatusCode, body: String) -> Result<U, BraavosError> {
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::FORBIDDEN {
+        error!("bybit rate limit,status:{}", status);
+        return Err(BraavosError::RateLimited { status: status.as_u16(), retry_after: None, body });
+    }
+
+    match serde_json::from_str::<BybitResponse<serde_json::Value>>(&body) {
+        Ok(resp) if resp.ret_code == 0 && status.is_success() => {
+            serde_json::from_value(resp.result.unwrap_or_default())
+                .map_err(|source| BraavosError::Deserialize { source, body })
+        }
+        Ok(resp) => {
+            error!("bybit error response,{},{}", status, &body);
+            Err(BraavosError::Bybit { status: status.as_u16(), code: resp.ret_code, msg: resp.ret_msg })
+        }
+        Err(source) if status.is_success() => Err(BraavosError::Deserialize { source, body }),
+        Err(_) => Err(BraavosError::HttpStatus { status: status.as_u16(), body }),
+    }
+}
+
+pub struct BybitRawDataQuery {
+    pub context: BybitContext,
+}
+
+impl BybitRawDataQuery {
+    pub fn new(context: BybitContext) -> BybitRawDataQuery {
+        BybitRawDataQuery { context }
+    }
+
+    /** U本位永续的持仓，按照cursor翻页
+     */
+    async fn linear_positions(&self, account: &Account) -> Result<Vec<BybitPosition>, BraavosError> {
+        let mut positions = vec![];
+        let mut cursor = None;
+        loop {
+            let query = PositionListQuery {
+                category: String::from("linear"),
+                settle_coin: String::from("USDT"),
+                limit: POSITION_LIMIT,
+                cursor,
+            };
+            let page: BybitList<BybitPosition> = self.context.get(BybitAPI::PositionListAPI, &query, account).await?;
+            positions.extend(page.list);
+            if page.next_page_cursor.is_empty() {
+                return Ok(positions);
+            }
+            cursor = Some(page.next_page_cursor);
+        }
+    }
+}
+
+impl RawDataQuery<BybitRawAccountData> for BybitRawDataQuery {
+    async fn query_raw_data(&self, account: &Account) -> Result<BybitRawAccountData, BraavosError> {
+        let unified = WalletBalanceQuery { account_type: String::from("UNIFIED") };
+        let (wallet, positions) = try_join!(
+            self.context.get::<WalletBalanceQuery, BybitList<BybitWalletBalance>>(BybitAPI::WalletBalanceAPI, &unified, account),
+            self.linear_positions(account)
+        )?;
+        let wallet_balance = wallet.list.into_iter().next()
+            .ok_or_else(|| BraavosError::Custom(format!("bybit account {} returns no wallet balance", account.name)))?;
+        Ok(BybitRawAccountData { wallet_balance, positions })
+    }
+}
+
+/** Bybit统一账户，资产和持仓换算成和币安统一账户一样的汇总。交易对的写法和币安一样，套利币种也是SOLUSDT这样的。
+* 资金费还没有接，fra_funding是空的
+*/
+pub struct BybitAccountReader {
+    pub account: Account,
+    pub context: BybitContext,
+}
+
+impl BybitAccountReader {
+    pub fn new(account: Account) -> BybitAccountReader {
+        BybitAccountReader::with_context(account, DEFAULT_BYBIT_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: BybitContext) -> BybitAccountReader {
+        BybitAccountReader { account, context }
+    }
+
+    pub(crate) fn summary_of(&self, data: &BybitRawAccountData) -> AccountSummary {
+        let wallet = &data.wallet_balance;
+        // 借币的币种equity是负数，和币安的negative balance一样按照正数算
+        let negative_balance = wallet.coin.iter()
+            .filter(|c| c.equity < dec!(0))
+            .map(|c| c.usd_value.abs())
+            .sum();
+        let usdt_equity = wallet.coin.iter()
+            .find(|c| c.coin == "USDT")
+            .map(|c| c.wallet_balance.max(dec!(0)))
+            .unwrap_or_default();
+
+        AccountSummary {
+            usdt_equity,
+            negative_balance,
+            account_pnl: wallet.total_perp_upl,
+            account_equity: wallet.total_equity,
+            um_swap_summary: self.linear_swap_balance(&data.positions),
+            cm_swap_summary: SwapSummary::default(),
+            margin_summary: None,
+        }
+    }
+
+    /** size是币的数量，名义价值用标记价格算，和币安的notional一致
+     */
+    fn linear_swap_balance(&self, swap_position: &[BybitPosition]) -> SwapSummary {
+        let fra_symbol = bybit_fra_symbols(&self.account);
+        let mut balance = dec!(0);
+        let mut short_balance = dec!(0);
+        let mut long_balance = dec!(0);
+        let mut pnl = dec!(0);
+        let mut long_pnl = dec!(0);
+        let mut short_pnl = dec!(0);
+        let mut fra_pnl = dec!(0);
+
+        let mut positions: Vec<SwapPosition> = vec![];
+        for swap in swap_position.iter().filter(|p| !p.size.is_zero()) {
+            if fra_symbol.contains(&swap.symbol) {
+                fra_pnl += swap.unrealised_pnl;
+                continue;
+            }
+            let notional = swap.size * swap.mark_price;
+            trace!("symbol:{}, 名义价值：{},未实现利润{}", swap.symbol, notional, swap.unrealised_pnl);
+            let direction = swap.direction();
+            pnl += swap.unrealised_pnl;
+            balance += notional;
+            if direction > dec!(0) {
+                long_balance += notional;
+                long_pnl += swap.unrealised_pnl;
+            } else {
+                short_balance += notional;
+                short_pnl += swap.unrealised_pnl;
+            }
+            positions.push(SwapPosition {
+                symbol: swap.symbol.clone(),
+                cur_price: swap.mark_price,
+                avg_price: swap.avg_price,
+                pos_u: notional * direction,
+                pnl_u: swap.unrealised_pnl,
+                position_amt: swap.size * direction,
+            });
+        }
+        SwapSummary {
+            long_balance,
+            long_pnl,
+            short_balance,
+            short_pnl,
+            balance,
+            pnl,
+            fra_pnl,
+            fra_funding_income: dec!(0),
+            fra_funding: vec![],
+            positions,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountReader for BybitAccountReader {
+    async fn account_balance(&self) -> Result<AccountSummary, BraavosError> {
+        let query = BybitRawDataQuery::new(self.context.clone());
+        match query.query_raw_data(&self.account).await {
+            Ok(data) => Ok(self.summary_of(&data)),
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::exchange::Exchange;
+    use crate::utils::parse_test_json;
+    use std::time::Duration;
+
+    fn test_account() -> Account {
+        Account {
+            name: "bybit".to_string(),
+            api_key: "XXXXXXXXXX".to_string(),
+            funding_rate_arbitrage: Some(vec!["SOL".to_string()]),
+            exchange: Exchange::Bybit,
+            ..crate::settings::test_account()
+        }
+    }
+
+    #[test]
+    fn test_retry_kind() {
+        let context = BybitContext::new(reqwest::Client::new(), BYBIT_PRODUCTION);
+        let bybit_error = |code: i64| BraavosError::Bybit { status: 200, code, msg: String::new() };
+        assert_eq!(RetryKind::NotProcessed, context.retry_kind(&bybit_error(BYBIT_TIMESTAMP_ERROR)));
+        assert_eq!(RetryKind::OutcomeUnknown, context.retry_kind(&bybit_error(BYBIT_SERVER_ERROR)));
+        assert_eq!(RetryKind::Fatal, context.retry_kind(&bybit_error(10001)), "参数错误不重试");
+    }
+
+    #[test]
+    fn test_sign_request() {
+        let actual = sign_request("secret", "1658385579423", "XXXXXXXXXX", "5000", "category=option&symbol=BTC-29JUL22-25000-C");
+        assert_eq!("346ec833b10fca76e56518fe6b643e26a949e7193e54e0b9ed9fe5c70a2d58b0", actual);
+    }
+
+    #[test]
+    fn test_summary_of() {
+        let wallet = parse_test_json::<BybitResponse<BybitList<BybitWalletBalance>>>("tests/data/bybit_wallet_balance.json");
+        let positions = parse_test_json::<BybitResponse<BybitList<BybitPosition>>>("tests/data/bybit_position_list.json");
+        let data = BybitRawAccountData {
+            wallet_balance: wallet.result.unwrap().list.remove(0),
+            positions: positions.result.unwrap().list,
+        };
+        let reader = BybitAccountReader::with_context(test_account(), BybitContext::new(reqwest::Client::new(), BYBIT_PRODUCTION));
+        let actual = reader.summary_of(&data);
+
+        assert_eq!(dec!(36094.6), actual.account_equity);
+        assert_eq!(dec!(10050), actual.usdt_equity);
+        assert_eq!(dec!(2905.4), actual.negative_balance, "借的SOL");
+        assert_eq!(dec!(-50), actual.account_pnl);
+
+        let swap = actual.um_swap_summary;
+        assert_eq!(2, swap.positions.len(), "没有仓位的和套利的不算");
+        assert_eq!(dec!(105.4), swap.fra_pnl);
+        assert_eq!(dec!(5512.43), swap.balance);
+        assert_eq!(dec!(2900), swap.short_balance);
+        assert_eq!(dec!(212.43), swap.pnl);
+
+        let btc = swap.positions.iter().find(|p| p.symbol == "BTCUSDT").unwrap();
+        assert_eq!(dec!(-0.05), btc.position_amt);
+        assert_eq!(dec!(-2900), btc.pos_u);
+    }
+
+    #[tokio::test]
+    async fn test_account_balance_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/v5/account/wallet-balance",
+                     vec![MockResponse::json(200, "{\"retCode\":10016,\"retMsg\":\"Server error\",\"result\":{}}"),
+                          MockResponse::fixture("tests/data/bybit_wallet_balance.json")])
+            .route(hyper::Method::GET, "/v5/position/list",
+                   vec![MockResponse::fixture("tests/data/bybit_position_list.json"),
+                        MockResponse::json(200, "{\"retCode\":0,\"retMsg\":\"OK\",\"result\":{\"category\":\"linear\",\"nextPageCursor\":\"\",\"list\":[]}}")]);
+        let retry = RetryPolicy { max_attempts: 2, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+        let context = BybitContext::new(reqwest::Client::new(), &server.base_url()).with_retry_policy(retry);
+        let reader = BybitAccountReader::with_context(test_account(), context);
+
+        let actual = reader.account_balance().await.unwrap();
+        assert_eq!(dec!(36094.6), actual.account_equity);
+        assert_eq!(2, actual.um_swap_summary.positions.len());
+
+        let requests = server.requests();
+        assert_eq!(2, requests.iter().filter(|r| r.path == "/v5/account/wallet-balance").count(), "10016之后重试");
+        let pages: Vec<_> = requests.iter().filter(|r| r.path == "/v5/position/list").collect();
+        assert_eq!(2, pages.len());
+        assert_eq!(Some("category=linear&settleCoin=USDT&limit=200&cursor=page2".to_string()), pages[1].query);
+
+        let headers = &pages[1].headers;
+        assert_eq!("XXXXXXXXXX", headers["X-BAPI-API-KEY"]);
+        assert_eq!("5000", headers["X-BAPI-RECV-WINDOW"]);
+        let timestamp = headers["X-BAPI-TIMESTAMP"].to_str().unwrap();
+        let sign = sign_request("secret", timestamp, "XXXXXXXXXX", "5000", pages[1].query.as_deref().unwrap());
+        assert_eq!(sign, headers["X-BAPI-SIGN"].to_str().unwrap());
+    }
+
+    #[test]
+    fn test_parse_bybit_error() {
+        let actual = parse_response::<BybitList<BybitPosition>>(StatusCode::OK, String::from("{\"retCode\":10006,\"retMsg\":\"Too many visits!\",\"result\":{}}"));
+        assert!(actual.unwrap_err().is_rate_limited());
+
+        let banned = parse_response::<BybitList<BybitPosition>>(StatusCode::FORBIDDEN, String::from("access too frequent"));
+        assert!(matches!(banned, Err(BraavosError::RateLimited { status: 403, .. })));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::models::Decimal;
+use crate::retry::RetryPolicy;
+use crate::utils::empty_as_zero;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+
+pub const BYBIT_PRODUCTION: &str = "https://api.bybit.com";
+
+/** 和OkxContext一样，一个进程共用一个，账户信息在调用的时候传
+ */
+#[derive(Debug, Clone)]
+pub struct BybitContext {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub retry_policy: RetryPolicy,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BybitAPI {
+    WalletBalanceAPI,
+    PositionListAPI,
+}
+
+impl BybitAPI {
+    pub fn path(&self) -> &'static str {
+        match self {
+            BybitAPI::WalletBalanceAPI => "/v5/account/wallet-balance",
+            BybitAPI::PositionListAPI => "/v5/position/list",
+        }
+    }
+}
+
+/** V5所有的返回都包在{retCode,retMsg,result}里面，retCode是0才是成功
+ */
+#[derive(Debug, Deserialize)]
+pub struct BybitResponse<T> {
+    #[serde(rename = "retCode")]
+    pub ret_code: i64,
+    #[serde(rename = "retMsg")]
+    pub ret_msg: String,
+    pub result: Option<T>,
+}
+
+/** 列表类的返回，翻页的接口nextPageCursor不是空的就还有下一页
+ */
+#[derive(Debug, Deserialize)]
+pub struct BybitList<T> {
+    pub list: Vec<T>,
+    #[serde(rename = "nextPageCursor", default)]
+    pub next_page_cursor: String,
+}
+
+/** 统一账户是UNIFIED
+ */
+#[derive(Debug, Clone)]
+pub struct WalletBalanceQuery {
+    pub account_type: String,
+}
+
+impl std::fmt::Display for WalletBalanceQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "accountType={}", self.account_type)
+    }
+}
+
+/** category是linear，inverse，spot，option。cursor原样带上，返回的时候已经编码过了
+ */
+#[derive(Debug, Clone)]
+pub struct PositionListQuery {
+    pub category: String,
+    pub settle_coin: String,
+    pub limit: usize,
+    pub cursor: Option<String>,
+}
+
+impl std::fmt::Display for PositionListQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "category={}&settleCoin={}&limit={}", self.category, self.settle_coin, self.limit)?;
+        match &self.cursor {
+            Some(cursor) => write!(f, "&cursor={}", cursor),
+            None => Ok(()),
+        }
+    }
+}
+
+/** 统一账户的资产，金额都是美元
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct BybitWalletBalance {
+    #[serde(rename = "totalEquity", deserialize_with = "empty_as_zero")]
+    pub total_equity: Decimal,
+
+    #[serde(rename = "totalWalletBalance", deserialize_with = "empty_as_zero")]
+    pub total_wallet_balance: Decimal,
+
+    #[serde(rename = "totalPerpUPL", deserialize_with = "empty_as_zero")]
+    pub total_perp_upl: Decimal, //永续和交割的未实现盈亏
+
+    pub coin: Vec<BybitCoinBalance>,
+}
+
+/** 每个币种的资产，equity是币的数量，usdValue是折算的美元。借币的话equity是负数
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct BybitCoinBalance {
+    pub coin: String,
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub equity: Decimal,
+
+    #[serde(rename = "usdValue", deserialize_with = "empty_as_zero")]
+    pub usd_value: Decimal,
+
+    #[serde(rename = "walletBalance", deserialize_with = "empty_as_zero")]
+    pub wallet_balance: Decimal,
+
+    #[serde(rename = "borrowAmount", deserialize_with = "empty_as_zero")]
+    pub borrow_amount: Decimal,
+
+    #[serde(rename = "unrealisedPnl", deserialize_with = "empty_as_zero")]
+    pub unrealised_pnl: Decimal,
+}
+
+/** 持仓，size是币的数量，都是正数，用side区分方向。没有仓位的时候side是空字符串
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct BybitPosition {
+    pub symbol: String,
+
+    pub side: String, //Buy，Sell
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub size: Decimal,
+
+    #[serde(rename = "avgPrice", deserialize_with = "empty_as_zero")]
+    pub avg_price: Decimal,
+
+    #[serde(rename = "markPrice", deserialize_with = "empty_as_zero")]
+    pub mark_price: Decimal,
+
+    #[serde(rename = "unrealisedPnl", deserialize_with = "empty_as_zero")]
+    pub unrealised_pnl: Decimal,
+}
+
+impl BybitPosition {
+    /** 多头是1，空头是-1
+     */
+    pub fn direction(&self) -> Decimal {
+        if self.side == "Sell" { dec!(-1) } else { dec!(1) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BybitRawAccountData {
+    pub wallet_balance: BybitWalletBalance,
+    pub positions: Vec<BybitPosition>,
+}
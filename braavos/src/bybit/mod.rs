@@ -0,0 +1,2 @@
+pub mod bybit_models;
+pub mod bybit_commands;
@@ -1,32 +1,226 @@
 use std::error::Error;
 use std::fmt;
+use std::time::Duration;
+use rust_decimal::Decimal;
+
+/** 币安返回的错误码，常用的单独列出来，方便调用方直接match
+* 完整的列表见 https://developers.binance.com/docs/binance-spot-api-docs/errors
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinanceErrorCode {
+    Disconnected,               // -1001 内部错误，无法处理请求
+    TooManyRequests,            // -1003 请求权重超限
+    Timeout,                    // -1007 等待后端超时，请求状态未知
+    TimestampOutsideRecvWindow, // -1021 时间戳不在recvWindow里面
+    InvalidSignature,           // -1022 签名错误
+    InvalidSymbol,              // -1121 交易对不存在
+    InvalidListenKey,           // -1125 listenKey不存在或者已经过期
+    NewOrderRejected,           // -2010 下单被拒绝
+    CancelRejected,             // -2011 撤单被拒绝
+    NoSuchOrder,                // -2013 订单不存在
+    BadApiKeyFormat,            // -2014 api key格式错误
+    RejectedMbxKey,             // -2015 api key，ip或者权限不对
+    MarginInsufficient,         // -2019 保证金不足
+    Other(i64),
+}
+
+impl From<i64> for BinanceErrorCode {
+    fn from(code: i64) -> Self {
+        match code {
+            -1001 => BinanceErrorCode::Disconnected,
+            -1003 => BinanceErrorCode::TooManyRequests,
+            -1007 => BinanceErrorCode::Timeout,
+            -1021 => BinanceErrorCode::TimestampOutsideRecvWindow,
+            -1022 => BinanceErrorCode::InvalidSignature,
+            -1121 => BinanceErrorCode::InvalidSymbol,
+            -1125 => BinanceErrorCode::InvalidListenKey,
+            -2010 => BinanceErrorCode::NewOrderRejected,
+            -2011 => BinanceErrorCode::CancelRejected,
+            -2013 => BinanceErrorCode::NoSuchOrder,
+            -2014 => BinanceErrorCode::BadApiKeyFormat,
+            -2015 => BinanceErrorCode::RejectedMbxKey,
+            -2019 => BinanceErrorCode::MarginInsufficient,
+            other => BinanceErrorCode::Other(other),
+        }
+    }
+}
+
+impl BinanceErrorCode {
+    pub fn code(&self) -> i64 {
+        match self {
+            BinanceErrorCode::Disconnected => -1001,
+            BinanceErrorCode::TooManyRequests => -1003,
+            BinanceErrorCode::Timeout => -1007,
+            BinanceErrorCode::TimestampOutsideRecvWindow => -1021,
+            BinanceErrorCode::InvalidSignature => -1022,
+            BinanceErrorCode::InvalidSymbol => -1121,
+            BinanceErrorCode::InvalidListenKey => -1125,
+            BinanceErrorCode::NewOrderRejected => -2010,
+            BinanceErrorCode::CancelRejected => -2011,
+            BinanceErrorCode::NoSuchOrder => -2013,
+            BinanceErrorCode::BadApiKeyFormat => -2014,
+            BinanceErrorCode::RejectedMbxKey => -2015,
+            BinanceErrorCode::MarginInsufficient => -2019,
+            BinanceErrorCode::Other(code) => *code,
+        }
+    }
+}
+
+/** 下单之前按照exchangeInfo检查出来的问题，订单不会发出去
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderRuleViolation {
+    UnknownSymbol(String),
+    SymbolNotTrading(String),
+    PriceTooLow { price: Decimal, min_price: Decimal },
+    PriceTooHigh { price: Decimal, max_price: Decimal },
+    PriceNotOnTick { price: Decimal, tick_size: Decimal },
+    QtyTooSmall { qty: Decimal, min_qty: Decimal },
+    QtyTooLarge { qty: Decimal, max_qty: Decimal },
+    QtyNotOnStep { qty: Decimal, step_size: Decimal },
+    NotionalTooSmall { notional: Decimal, min_notional: Decimal },
+    NotionalTooLarge { notional: Decimal, max_notional: Decimal },
+}
+
+impl fmt::Display for OrderRuleViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderRuleViolation::UnknownSymbol(symbol) => write!(f, "unknown symbol {}", symbol),
+            OrderRuleViolation::SymbolNotTrading(symbol) => write!(f, "{} is not trading", symbol),
+            OrderRuleViolation::PriceTooLow { price, min_price } => write!(f, "price {} < min price {}", price, min_price),
+            OrderRuleViolation::PriceTooHigh { price, max_price } => write!(f, "price {} > max price {}", price, max_price),
+            OrderRuleViolation::PriceNotOnTick { price, tick_size } => write!(f, "price {} is not a multiple of tick size {}", price, tick_size),
+            OrderRuleViolation::QtyTooSmall { qty, min_qty } => write!(f, "qty {} < min qty {}", qty, min_qty),
+            OrderRuleViolation::QtyTooLarge { qty, max_qty } => write!(f, "qty {} > max qty {}", qty, max_qty),
+            OrderRuleViolation::QtyNotOnStep { qty, step_size } => write!(f, "qty {} is not a multiple of step size {}", qty, step_size),
+            OrderRuleViolation::NotionalTooSmall { notional, min_notional } => write!(f, "notional {} < min notional {}", notional, min_notional),
+            OrderRuleViolation::NotionalTooLarge { notional, max_notional } => write!(f, "notional {} > max notional {}", notional, max_notional),
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct BraavosError {
-    message: String,
+pub enum BraavosError {
+    /// 网络层的错误，比如连接失败，超时
+    Transport(reqwest::Error),
+    /// 非2xx的返回，而且body不是币安的错误格式
+    HttpStatus { status: u16, body: String },
+    /// 币安返回的 {code,msg}
+    Binance { status: u16, code: BinanceErrorCode, msg: String },
+    /// OKX返回的 {code,msg}，code不是"0"
+    Okx { status: u16, code: String, msg: String },
+    /// Bybit返回的 {retCode,retMsg}，retCode不是0
+    Bybit { status: u16, code: i64, msg: String },
+    /// 429是限频，418是被ban了。retry_after是从Retry-After头里面读出来的
+    RateLimited { status: u16, retry_after: Option<Duration>, body: String },
+    /// 本地的限频器判断权重不够，请求没有发出去
+    RateLimitExceeded { retry_after: Duration },
+    /// 返回的内容解析不了，body是原始的返回
+    Deserialize { source: serde_json::Error, body: String },
+    /// websocket连接断开，超时，或者请求被服务器拒绝
+    WebSocket(String),
+    /// 下单之前本地检查没通过
+    InvalidOrder(OrderRuleViolation),
+    /// 交易所没有这个功能，比如某个品种没有对应的接口
+    Unsupported(String),
+    /// 其他的错误
+    Custom(String),
 }
 
 // 实现 Display trait，用于将错误信息格式化为字符串
 impl fmt::Display for BraavosError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Custom Error: {}", self.message)
+        match self {
+            BraavosError::Transport(error) => write!(f, "request Error: {}", error),
+            BraavosError::HttpStatus { status, body } => write!(f, "http status {}: {}", status, body),
+            BraavosError::Binance { status, code, msg } => write!(f, "binance error {} (http {}): {}", code.code(), status, msg),
+            BraavosError::Okx { status, code, msg } => write!(f, "okx error {} (http {}): {}", code, status, msg),
+            BraavosError::Bybit { status, code, msg } => write!(f, "bybit error {} (http {}): {}", code, status, msg),
+            BraavosError::RateLimited { status, retry_after, body } => match retry_after {
+                Some(wait) => write!(f, "rate limited (http {}), retry after {}s: {}", status, wait.as_secs(), body),
+                None => write!(f, "rate limited (http {}): {}", status, body),
+            },
+            BraavosError::RateLimitExceeded { retry_after } => write!(f, "request weight exceeded, retry after {}ms", retry_after.as_millis()),
+            BraavosError::Deserialize { source, body } => write!(f, "can't parse response ({}): {}", source, body),
+            BraavosError::WebSocket(message) => write!(f, "websocket error: {}", message),
+            BraavosError::InvalidOrder(violation) => write!(f, "invalid order: {}", violation),
+            BraavosError::Unsupported(message) => write!(f, "unsupported: {}", message),
+            BraavosError::Custom(message) => write!(f, "Custom Error: {}", message),
+        }
     }
 }
 
+/** OKX的错误码是字符串，只列出需要特别处理的
+ */
+pub const OKX_TOO_MANY_REQUESTS: &str = "50011";
+pub const OKX_SERVICE_UNAVAILABLE: &str = "50001";
+pub const OKX_REQUEST_TIMEOUT: &str = "50004";
+pub const OKX_TIMESTAMP_EXPIRED: &str = "50102";
+
+pub const BYBIT_TIMESTAMP_ERROR: i64 = 10002; //时间戳不在recv_window里面
+pub const BYBIT_TOO_MANY_REQUESTS: i64 = 10006;
+pub const BYBIT_SERVER_ERROR: i64 = 10016;
+
 impl BraavosError {
     pub fn new(message: String) -> BraavosError {
-        BraavosError { message }
+        BraavosError::Custom(message)
+    }
+
+    /** 币安的错误码，不是币安返回的错误就是None
+     */
+    pub fn binance_code(&self) -> Option<BinanceErrorCode> {
+        match self {
+            BraavosError::Binance { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, BraavosError::RateLimited { .. } | BraavosError::RateLimitExceeded { .. })
+            || self.binance_code() == Some(BinanceErrorCode::TooManyRequests)
+            || matches!(self, BraavosError::Okx { code, .. } if code == OKX_TOO_MANY_REQUESTS)
+            || matches!(self, BraavosError::Bybit { code: BYBIT_TOO_MANY_REQUESTS, .. })
+    }
+
+    pub fn is_timestamp_error(&self) -> bool {
+        self.binance_code() == Some(BinanceErrorCode::TimestampOutsideRecvWindow)
     }
 }
 
 // 实现 Error trait，用于提供错误信息
-impl Error for BraavosError {}
+impl Error for BraavosError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BraavosError::Transport(error) => Some(error),
+            BraavosError::Deserialize { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
-// 实现 From trait，将 io::Error 转换为 CustomError
+// 实现 From trait，将 reqwest::Error 转换为 BraavosError
 impl From<reqwest::Error> for BraavosError {
     fn from(error: reqwest::Error) -> Self {
-        BraavosError {
-            message: format!("request Error: {}", error),
-        }
+        BraavosError::Transport(error)
+    }
+}
+
+impl From<OrderRuleViolation> for BraavosError {
+    fn from(violation: OrderRuleViolation) -> Self {
+        BraavosError::InvalidOrder(violation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binance_error_code() {
+        assert_eq!(BinanceErrorCode::TimestampOutsideRecvWindow, BinanceErrorCode::from(-1021));
+        assert_eq!(BinanceErrorCode::MarginInsufficient, BinanceErrorCode::from(-2019));
+        assert_eq!(BinanceErrorCode::Other(-4164), BinanceErrorCode::from(-4164));
+        assert_eq!(-4164, BinanceErrorCode::from(-4164).code());
+        assert_eq!(-1021, BinanceErrorCode::TimestampOutsideRecvWindow.code());
     }
 }
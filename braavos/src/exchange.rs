@@ -0,0 +1,81 @@
+use crate::accounts::AccountReader;
+use crate::binance::bn_classic_account::{CMAccountReader, SpotAccountReader, UMAccountReader};
+use crate::binance::bn_commands::PMAccountReader;
+use crate::binance::bn_exchange::BinanceMarketData;
+use crate::binance::bn_order_commands::PMOrderExecutor;
+use crate::bybit::bybit_commands::BybitAccountReader;
+use crate::errors::BraavosError;
+use crate::market::MarketDataReader;
+use crate::okx::okx_commands::OkxAccountReader;
+use crate::settings::Account;
+use crate::trading::OrderExecutor;
+use serde::Deserialize;
+
+/** 账户在哪个交易所，配置里面用小写，不配置就是binance
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Exchange {
+    #[default]
+    Binance,
+    Okx,
+    Bybit,
+}
+
+impl std::fmt::Display for Exchange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Exchange::Binance => write!(f, "binance"),
+            Exchange::Okx => write!(f, "okx"),
+            Exchange::Bybit => write!(f, "bybit"),
+        }
+    }
+}
+
+/** 币安的账户类型，配置里面用小写，不配置就是统一账户。其他交易所只有统一账户，不看这个
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountType {
+    #[default]
+    Portfolio,
+    Spot,
+    UsdM,
+    CoinM,
+}
+
+/** 按照账户配置的交易所创建对应的实现，nightwatch和direwolf只用这里的trait，不直接用交易所的模块
+ */
+pub fn account_reader(account: &Account) -> Box<dyn AccountReader> {
+    match account.exchange {
+        Exchange::Binance => match account.account_type {
+            AccountType::Portfolio => Box::new(PMAccountReader::new(account.clone())),
+            AccountType::Spot => Box::new(SpotAccountReader::new(account.clone())),
+            AccountType::UsdM => Box::new(UMAccountReader::new(account.clone())),
+            AccountType::CoinM => Box::new(CMAccountReader::new(account.clone())),
+        },
+        Exchange::Okx => Box::new(OkxAccountReader::new(account.clone())),
+        Exchange::Bybit => Box::new(BybitAccountReader::new(account.clone())),
+    }
+}
+
+/** 有的交易所只接了账户，还不能下单和看行情，返回Unsupported。
+* 调用方按照Result处理，加交易所或者接上下单和行情只改这里，nightwatch和direwolf不用改
+*/
+pub fn order_executor(account: &Account) -> Result<Box<dyn OrderExecutor>, BraavosError> {
+    match account.exchange {
+        Exchange::Binance => Ok(Box::new(PMOrderExecutor::new(account.clone()))),
+        Exchange::Okx | Exchange::Bybit => Err(unsupported(account.exchange, "order executor")),
+    }
+}
+
+pub fn market_data(exchange: Exchange) -> Result<Box<dyn MarketDataReader>, BraavosError> {
+    match exchange {
+        Exchange::Binance => Ok(Box::new(BinanceMarketData::new())),
+        Exchange::Okx | Exchange::Bybit => Err(unsupported(exchange, "market data")),
+    }
+}
+
+fn unsupported(exchange: Exchange, what: &str) -> BraavosError {
+    BraavosError::Unsupported(format!("{} of {}", what, exchange))
+}
@@ -1,9 +1,16 @@
 pub mod binance;
+pub mod okx;
+pub mod bybit;
 
 pub mod models;
 pub mod errors;
 pub mod settings;
+pub mod valuation;
 pub mod accounts;
+pub mod market;
+pub mod trading;
+pub mod exchange;
+pub mod retry;
 
 pub mod utils;
 
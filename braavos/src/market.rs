@@ -0,0 +1,17 @@
+use crate::errors::BraavosError;
+use crate::models::{BookSnapshot, Decimal, Instrument, InstrumentKind};
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+/** 公开的行情，不需要账户
+ */
+#[async_trait]
+pub trait MarketDataReader: Send + Sync {
+    async fn ping(&self) -> Result<(), BraavosError>;
+
+    async fn order_book(&self, instrument: &Instrument, depth: u16) -> Result<BookSnapshot, BraavosError>;
+
+    /** 某一类品种所有交易对的价格，现货是最新成交价，永续是标记价格
+     */
+    async fn prices(&self, kind: InstrumentKind) -> Result<HashMap<String, Decimal>, BraavosError>;
+}
@@ -14,10 +14,36 @@ pub struct AccountSummary {
     pub account_pnl: Decimal,
     pub account_equity: Decimal,
     pub um_swap_summary: SwapSummary,
+    pub cm_swap_summary: SwapSummary, //币本位合约，按照现货价格折算成USDT
+    pub margin_summary: Option<MarginSummary>, //只有币安统一账户有，其他的是None
 }
 
+/** 统一账户的保证金情况，金额都是USDT
+ */
+#[derive(Debug)]
+pub struct MarginSummary {
+    pub uni_mmr: Decimal,                //统一账户维持保证金率
+    pub account_maint_margin: Decimal,   //维持保证金
+    pub account_status: String,          //NORMAL，MARGIN_CALL，REDUCE_ONLY这些
+    pub liquidation_distance: Decimal,   //权益再跌多少比例会被强平，0到1
+    pub borrowed: Decimal,               //全仓杠杆借的币
+    pub interest: Decimal,               //全仓杠杆还没还的利息
+    pub assets: Vec<MarginAsset>,        //有借币或者利息的币种
+}
 
+/** 每个币种的借币和利息，amount是币的数量，usdt是折算之后的
+ */
 #[derive(Debug)]
+pub struct MarginAsset {
+    pub asset: String,
+    pub borrowed: Decimal,
+    pub interest: Decimal,
+    pub borrowed_usdt: Decimal,
+    pub interest_usdt: Decimal,
+}
+
+
+#[derive(Debug, Default)]
 pub struct SwapSummary {
     pub long_balance: Decimal,
     pub long_pnl: Decimal,
@@ -26,9 +52,21 @@ pub struct SwapSummary {
     pub balance: Decimal,
     pub pnl: Decimal,
     pub fra_pnl: Decimal, //funding_rate_arbitrage
+    pub fra_funding_income: Decimal, //套利币种累计的资金费
+    pub fra_funding: Vec<FundingIncome>,
     pub positions: Vec<SwapPosition>,
 }
 
+/** 套利币种的资金费率和累计的资金费收入
+ */
+#[derive(Debug)]
+pub struct FundingIncome {
+    pub symbol: String,                   //交易对
+    pub funding_rate: Decimal,            //当前的资金费率
+    pub next_funding_time: UnixTimeStamp, //下次结算时间
+    pub income: Decimal,                  //累计的资金费，正数是收到的
+}
+
 
 #[derive(Debug)]
 pub struct SwapPosition {
@@ -40,6 +78,124 @@ pub struct SwapPosition {
     pub position_amt: Decimal,  //持仓数量
 }
 
+/** 交易品种的类型，和交易所无关。各个交易所自己对应到自己的接口
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstrumentKind {
+    Spot,
+    Margin,      //杠杆
+    LinearSwap,  //U本位永续
+    InverseSwap, //币本位永续
+}
+
+/** 交易品种，symbol用交易所自己的写法，比如币安的BTCUSDT
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Instrument {
+    pub kind: InstrumentKind,
+    pub symbol: String,
+}
+
+impl Instrument {
+    pub fn new(kind: InstrumentKind, symbol: &str) -> Instrument {
+        Instrument { kind, symbol: String::from(symbol) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/** 订单类型，限价单的有效方式也放在这里
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderKind {
+    Market,
+    Limit,    //成交为止
+    PostOnly, //只做maker
+    Ioc,      //无法立即成交的部分就撤销
+    Fok,      //无法全部立即成交就撤销
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderState {
+    Open,
+    PartiallyFilled,
+    Filled,
+    Canceled,
+    Rejected,
+    Expired,
+    Unknown,
+}
+
+/** 下单请求。市价单price是None，client_order_id不填的话由交易所的实现生成
+ */
+#[derive(Debug, Clone)]
+pub struct OrderRequest {
+    pub instrument: Instrument,
+    pub side: Side,
+    pub kind: OrderKind,
+    pub quantity: Decimal,
+    pub price: Option<Decimal>,
+    pub reduce_only: bool,
+    pub client_order_id: Option<String>,
+}
+
+impl OrderRequest {
+    pub fn limit(instrument: Instrument, side: Side, quantity: Decimal, price: Decimal) -> OrderRequest {
+        OrderRequest { instrument, side, kind: OrderKind::Limit, quantity, price: Some(price), reduce_only: false, client_order_id: None }
+    }
+
+    pub fn market(instrument: Instrument, side: Side, quantity: Decimal) -> OrderRequest {
+        OrderRequest { instrument, side, kind: OrderKind::Market, quantity, price: None, reduce_only: false, client_order_id: None }
+    }
+
+    /** 限价单换成PostOnly，Ioc或者Fok
+     */
+    pub fn with_kind(mut self, kind: OrderKind) -> OrderRequest {
+        self.kind = kind;
+        self
+    }
+
+    pub fn reduce_only(mut self) -> OrderRequest {
+        self.reduce_only = true;
+        self
+    }
+}
+
+/** 订单的状态，order_id是交易所的id，统一用字符串
+ */
+#[derive(Debug, Clone)]
+pub struct OrderInfo {
+    pub instrument: Instrument,
+    pub order_id: String,
+    pub client_order_id: String,
+    pub side: Side,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    pub filled_qty: Decimal,
+    pub avg_price: Decimal, //没有成交是0
+    pub state: OrderState,
+    pub update_time: Option<UnixTimeStamp>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BookLevel {
+    pub price: Decimal,
+    pub qty: Decimal,
+}
+
+/** 订单簿快照，bids从高到低，asks从低到高
+ */
+#[derive(Debug, Clone)]
+pub struct BookSnapshot {
+    pub instrument: Instrument,
+    pub bids: Vec<BookLevel>,
+    pub asks: Vec<BookLevel>,
+}
+
 
 #[derive(Debug, PartialEq, Default)]
 pub struct EmptyObject;
@@ -0,0 +1,2 @@
+pub mod okx_models;
+pub mod okx_commands;
@@ -0,0 +1,382 @@
+use crate::accounts::{AccountReader, RawDataQuery};
+use crate::errors::{BraavosError, OKX_REQUEST_TIMEOUT, OKX_SERVICE_UNAVAILABLE, OKX_TIMESTAMP_EXPIRED};
+use crate::models::{AccountSummary, Decimal, EmptyObject, SwapPosition, SwapSummary};
+use crate::okx::okx_models::{InstTypeQuery, OkxAPI, OkxBalance, OkxContext, OkxInstrument, OkxPosition, OkxRawAccountData, OkxResponse, OKX_PRODUCTION};
+use crate::retry::{RetryClassifier, RetryKind, RetryPolicy};
+use crate::settings::Account;
+use crate::utils::{init_client, sign_hmac_base64};
+use async_trait::async_trait;
+use log::{error, trace, warn};
+use reqwest::StatusCode;
+use rust_decimal_macros::dec;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+use tokio::try_join;
+
+pub static DEFAULT_OKX_CONTEXT: LazyLock<OkxContext> = LazyLock::new(|| {
+    OkxContext::new(init_client(), OKX_PRODUCTION)
+});
+
+/** 统一账户下面U本位永续的instType
+ */
+const SWAP: &str = "SWAP";
+
+impl OkxContext {
+    pub fn new(client: reqwest::Client, base_url: &str) -> OkxContext {
+        OkxContext {
+            client,
+            base_url: String::from(base_url.trim_end_matches('/')),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> OkxContext {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /** GET都是幂等的，按照RetryPolicy重试。私有接口需要传账户，每次重试都重新签名
+     */
+    pub(crate) async fn get<Q: Display, U: DeserializeOwned>(&self, api: OkxAPI, query: Option<&Q>, account: Option<&Account>) -> Result<Vec<U>, BraavosError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match self.send_get(api, query, account).await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+
+            let policy = &self.retry_policy;
+            if attempt >= policy.max_attempts || !self.retry_kind(&err).retryable(true) {
+                return Err(err);
+            }
+            let wait = policy.backoff(attempt);
+            warn!("okx request failed, retry {} after {:?}:{}", attempt, wait, err);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn send_get<Q: Display, U: DeserializeOwned>(&self, api: OkxAPI, query: Option<&Q>, account: Option<&Account>) -> Result<Vec<U>, BraavosError> {
+        let request_path = match query {
+            Some(q) => format!("{}?{}", api.path(), q),
+            None => String::from(api.path()),
+        };
+        let mut request = self.client.get(format!("{}{}", self.base_url, request_path));
+        if api.is_private() {
+            let account = account.ok_or_else(|| BraavosError::Custom(format!("okx {:?} needs an account", api)))?;
+            let passphrase = account.passphrase.as_deref()
+                .ok_or_else(|| BraavosError::Custom(format!("okx account {} has no passphrase", account.name)))?;
+            let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+            request = request
+                .header("OK-ACCESS-KEY", &account.api_key)
+                .header("OK-ACCESS-SIGN", sign_request(&account.secret, &timestamp, "GET", &request_path, ""))
+                .header("OK-ACCESS-TIMESTAMP", timestamp)
+                .header("OK-ACCESS-PASSPHRASE", passphrase);
+        }
+
+        let res = request.send().await?;
+        let status = res.status();
+        let body = res.text().await?;
+        trace!("body:{}", &body);
+        parse_response(status, body)
+    }
+}
+
+/** OKX的错误码：50102时间戳过期肯定没有执行，50001和50004结果未知，其他的看HTTP状态码
+ */
+impl RetryClassifier for OkxContext {
+    fn retry_kind(&self, error: &BraavosError) -> RetryKind {
+        match error {
+            BraavosError::Okx { code, status, .. } => match code.as_str() {
+                OKX_TIMESTAMP_EXPIRED => RetryKind::NotProcessed,
+                OKX_SERVICE_UNAVAILABLE | OKX_REQUEST_TIMEOUT => RetryKind::OutcomeUnknown,
+                _ => RetryKind::of_status(*status),
+            },
+            _ => RetryKind::of_common(error),
+        }
+    }
+}
+
+/** 签名的原文是timestamp + method + requestPath + body，GET的query string算在requestPath里面
+ */
+pub(crate) fn sign_request(secret: &str, timestamp: &str, method: &str, request_path: &str, body: &str) -> String {
+    sign_hmac_base64(&format!("{}{}{}{}", timestamp, method, request_path, body), secret).unwrap()
+}
+
+/** 先按照{code,msg,data}解析，code是"0"再解析data。出错的时候data的格式不一定对
+ */
+fn parse_response<U: DeserializeOwned>(status: StatusCode, body: String) -> Result<Vec<U>, BraavosError> {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        error!("okx rate limit,status:{}", status);
+        return Err(BraavosError::RateLimited { status: status.as_u16(), retry_after: None, body });
+    }
+
+    match serde_json::from_str::<OkxResponse<serde_json::Value>>(&body) {
+        Ok(resp) if resp.code == "0" && status.is_success() => {
+            serde_json::from_value(serde_json::Value::Array(resp.data))
+                .map_err(|source| BraavosError::Deserialize { source, body })
+        }
+        Ok(resp) => {
+            error!("okx error response,{},{}", status, &body);
+            Err(BraavosError::Okx { status: status.as_u16(), code: resp.code, msg: resp.msg })
+        }
+        Err(source) if status.is_success() => Err(BraavosError::Deserialize { source, body }),
+        Err(_) => Err(BraavosError::HttpStatus { status: status.as_u16(), body }),
+    }
+}
+
+pub struct OkxRawDataQuery {
+    pub context: OkxContext,
+}
+
+impl OkxRawDataQuery {
+    pub fn new(context: OkxContext) -> OkxRawDataQuery {
+        OkxRawDataQuery { context }
+    }
+}
+
+impl RawDataQuery<OkxRawAccountData> for OkxRawDataQuery {
+    async fn query_raw_data(&self, account: &Account) -> Result<OkxRawAccountData, BraavosError> {
+        let swap = InstTypeQuery { inst_type: String::from(SWAP) };
+        let (balance, positions, instruments) = try_join!(
+            self.context.get::<EmptyObject, OkxBalance>(OkxAPI::BalanceAPI, None, Some(account)),
+            self.context.get::<InstTypeQuery, OkxPosition>(OkxAPI::PositionsAPI, Some(&swap), Some(account)),
+            self.context.get::<InstTypeQuery, OkxInstrument>(OkxAPI::InstrumentsAPI, Some(&swap), None)
+        )?;
+        let balance = balance.into_iter().next()
+            .ok_or_else(|| BraavosError::Custom(format!("okx account {} returns no balance", account.name)))?;
+        Ok(OkxRawAccountData { balance, positions, instruments })
+    }
+}
+
+/** 配置里面的币种，比如SOL，对应的U本位永续是SOL-USDT-SWAP
+ */
+fn okx_fra_symbols(account: &Account) -> Vec<String> {
+    match &account.funding_rate_arbitrage {
+        None => { vec![] }
+        Some(fra) => { fra.iter().map(|x| format!("{}-USDT-SWAP", x)).collect() }
+    }
+}
+
+/** OKX统一账户，资产和持仓换算成和币安统一账户一样的汇总。资金费还没有接，fra_funding是空的
+ */
+pub struct OkxAccountReader {
+    pub account: Account,
+    pub context: OkxContext,
+}
+
+impl OkxAccountReader {
+    pub fn new(account: Account) -> OkxAccountReader {
+        OkxAccountReader::with_context(account, DEFAULT_OKX_CONTEXT.clone())
+    }
+
+    pub fn with_context(account: Account, context: OkxContext) -> OkxAccountReader {
+        OkxAccountReader { account, context }
+    }
+
+    pub(crate) fn summary_of(&self, data: &OkxRawAccountData) -> AccountSummary {
+        let balance = &data.balance;
+        // 借币的币种eq是负数，和币安的negative balance一样按照正数算
+        let negative_balance = balance.details.iter()
+            .filter(|d| d.eq < dec!(0))
+            .map(|d| d.eq_usd.abs())
+            .sum();
+        let usdt_equity = balance.details.iter()
+            .find(|d| d.ccy == "USDT")
+            .map(|d| d.cash_bal.max(dec!(0)))
+            .unwrap_or_default();
+
+        AccountSummary {
+            usdt_equity,
+            negative_balance,
+            account_pnl: balance.upl,
+            account_equity: balance.total_eq,
+            um_swap_summary: self.linear_swap_balance(&data.positions, &data.instruments),
+            cm_swap_summary: SwapSummary::default(),
+            margin_summary: None,
+        }
+    }
+
+    /** 只算U本位永续，张数乘以ctVal换算成币的数量
+     */
+    fn linear_swap_balance(&self, swap_position: &[OkxPosition], instruments: &[OkxInstrument]) -> SwapSummary {
+        let fra_symbol = okx_fra_symbols(&self.account);
+        let mut balance = dec!(0);
+        let mut short_balance = dec!(0);
+        let mut long_balance = dec!(0);
+        let mut pnl = dec!(0);
+        let mut long_pnl = dec!(0);
+        let mut short_pnl = dec!(0);
+        let mut fra_pnl = dec!(0);
+
+        let mut positions: Vec<SwapPosition> = vec![];
+        for swap in swap_position.iter().filter(|p| !p.pos.is_zero()) {
+            let ct_val = match instruments.iter().find(|i| i.inst_id == swap.inst_id) {
+                Some(instrument) if instrument.is_linear() => instrument.ct_val,
+                Some(_) => continue,
+                None => {
+                    error!("instrument {} not exists!!!", swap.inst_id);
+                    continue;
+                }
+            };
+            if fra_symbol.contains(&swap.inst_id) {
+                fra_pnl += swap.upl;
+                continue;
+            }
+            trace!("symbol:{}, 名义价值：{},未实现利润{}", swap.inst_id, swap.notional_usd, swap.upl);
+            let direction = swap.direction();
+            pnl += swap.upl;
+            balance += swap.notional_usd;
+            if direction > dec!(0) {
+                long_balance += swap.notional_usd;
+                long_pnl += swap.upl;
+            } else {
+                short_balance += swap.notional_usd;
+                short_pnl += swap.upl;
+            }
+            let position_amt: Decimal = swap.pos.abs() * ct_val * direction;
+            positions.push(SwapPosition {
+                symbol: swap.inst_id.clone(),
+                cur_price: swap.mark_px,
+                avg_price: swap.avg_px,
+                pos_u: swap.notional_usd * direction,
+                pnl_u: swap.upl,
+                position_amt,
+            });
+        }
+        SwapSummary {
+            long_balance,
+            long_pnl,
+            short_balance,
+            short_pnl,
+            balance,
+            pnl,
+            fra_pnl,
+            fra_funding_income: dec!(0),
+            fra_funding: vec![],
+            positions,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountReader for OkxAccountReader {
+    async fn account_balance(&self) -> Result<AccountSummary, BraavosError> {
+        let query = OkxRawDataQuery::new(self.context.clone());
+        match query.query_raw_data(&self.account).await {
+            Ok(data) => Ok(self.summary_of(&data)),
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binance::bn_mock_server::{MockResponse, MockServer};
+    use crate::exchange::Exchange;
+    use crate::utils::parse_test_json;
+    use std::time::Duration;
+
+    fn test_account() -> Account {
+        Account {
+            name: "okx".to_string(),
+            funding_rate_arbitrage: Some(vec!["SOL".to_string()]),
+            exchange: Exchange::Okx,
+            passphrase: Some("passphrase".to_string()),
+            ..crate::settings::test_account()
+        }
+    }
+
+    #[test]
+    fn test_retry_kind() {
+        let context = OkxContext::new(reqwest::Client::new(), OKX_PRODUCTION);
+        let okx_error = |code: &str, status: u16| BraavosError::Okx { status, code: String::from(code), msg: String::new() };
+        assert_eq!(RetryKind::NotProcessed, context.retry_kind(&okx_error(OKX_TIMESTAMP_EXPIRED, 401)));
+        assert_eq!(RetryKind::OutcomeUnknown, context.retry_kind(&okx_error(OKX_SERVICE_UNAVAILABLE, 503)));
+        assert_eq!(RetryKind::Fatal, context.retry_kind(&okx_error("51008", 200)), "余额不足这种业务错误不重试");
+    }
+
+    #[test]
+    fn test_sign_request() {
+        let actual = sign_request("22582BD0CFF14C41EDBF1AB98506286D", "2020-12-08T09:08:57.715Z", "GET", "/api/v5/account/balance?ccy=BTC", "");
+        assert_eq!("HiZhvSfMtWJA3uUIVXV3a/bSXNPCWvYFXoGCVS8V4zY=", actual);
+    }
+
+    #[test]
+    fn test_summary_of() {
+        let data = OkxRawAccountData {
+            balance: parse_test_json::<OkxResponse<OkxBalance>>("tests/data/okx_account_balance.json").data.remove(0),
+            positions: parse_test_json::<OkxResponse<OkxPosition>>("tests/data/okx_account_positions.json").data,
+            instruments: parse_test_json::<OkxResponse<OkxInstrument>>("tests/data/okx_public_instruments.json").data,
+        };
+        let reader = OkxAccountReader::with_context(test_account(), OkxContext::new(reqwest::Client::new(), OKX_PRODUCTION));
+        let actual = reader.summary_of(&data);
+
+        assert_eq!(dec!(36094.6), actual.account_equity);
+        assert_eq!(dec!(10050), actual.usdt_equity);
+        assert_eq!(dec!(2905.4), actual.negative_balance, "借的SOL");
+        assert_eq!(dec!(-50), actual.account_pnl);
+
+        let swap = actual.um_swap_summary;
+        assert_eq!(2, swap.positions.len(), "币本位和套利的不算");
+        assert_eq!(dec!(105.4), swap.fra_pnl);
+        assert_eq!(dec!(5512.43), swap.balance);
+        assert_eq!(dec!(2612.43), swap.long_balance);
+        assert_eq!(dec!(2900), swap.short_balance);
+        assert_eq!(dec!(212.43), swap.pnl);
+
+        let btc = swap.positions.iter().find(|p| p.symbol == "BTC-USDT-SWAP").unwrap();
+        assert_eq!(dec!(-0.05), btc.position_amt, "5张，一张0.01个BTC");
+        assert_eq!(dec!(-2900), btc.pos_u);
+        let eth = swap.positions.iter().find(|p| p.symbol == "ETH-USDT-SWAP").unwrap();
+        assert_eq!(dec!(1), eth.position_amt, "双向持仓的多头");
+    }
+
+    #[tokio::test]
+    async fn test_account_balance_with_mock() {
+        let server = MockServer::start();
+        server.route(hyper::Method::GET, "/api/v5/account/balance",
+                     vec![MockResponse::json(503, "<html>unavailable</html>"),
+                          MockResponse::fixture("tests/data/okx_account_balance.json")])
+            .route(hyper::Method::GET, "/api/v5/account/positions", vec![MockResponse::fixture("tests/data/okx_account_positions.json")])
+            .route(hyper::Method::GET, "/api/v5/public/instruments", vec![MockResponse::fixture("tests/data/okx_public_instruments.json")]);
+        let retry = RetryPolicy { max_attempts: 2, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1) };
+        let context = OkxContext::new(reqwest::Client::new(), &server.base_url()).with_retry_policy(retry);
+        let reader = OkxAccountReader::with_context(test_account(), context.clone());
+
+        let actual = reader.account_balance().await.unwrap();
+        assert_eq!(dec!(36094.6), actual.account_equity);
+
+        let requests = server.requests();
+        let positions = requests.iter().find(|r| r.path == "/api/v5/account/positions").unwrap();
+        assert_eq!(Some("instType=SWAP".to_string()), positions.query);
+        assert_eq!("key", positions.headers["OK-ACCESS-KEY"]);
+        assert_eq!("passphrase", positions.headers["OK-ACCESS-PASSPHRASE"]);
+        let timestamp = positions.headers["OK-ACCESS-TIMESTAMP"].to_str().unwrap();
+        let sign = sign_request("secret", timestamp, "GET", "/api/v5/account/positions?instType=SWAP", "");
+        assert_eq!(sign, positions.headers["OK-ACCESS-SIGN"].to_str().unwrap());
+
+        let instruments = requests.iter().find(|r| r.path == "/api/v5/public/instruments").unwrap();
+        assert!(!instruments.headers.contains_key("OK-ACCESS-SIGN"), "公开接口不签名");
+
+        let mut no_passphrase = test_account();
+        no_passphrase.passphrase = None;
+        let err = OkxAccountReader::with_context(no_passphrase, context).account_balance().await.unwrap_err();
+        assert!(err.to_string().contains("passphrase"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_okx_error() {
+        let actual = parse_response::<OkxBalance>(StatusCode::UNAUTHORIZED, String::from("{\"code\":\"50113\",\"msg\":\"Invalid Sign\",\"data\":[]}"));
+        assert!(matches!(actual, Err(BraavosError::Okx { status: 401, ref code, .. }) if code == "50113"));
+
+        let limited = parse_response::<OkxBalance>(StatusCode::OK, String::from("{\"code\":\"50011\",\"msg\":\"Too Many Requests\",\"data\":[]}"));
+        assert!(limited.unwrap_err().is_rate_limited());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::models::{Decimal, UnixTimeStamp};
+use crate::retry::RetryPolicy;
+use crate::utils::empty_as_zero;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Deserializer};
+
+pub const OKX_PRODUCTION: &str = "https://www.okx.com";
+
+/** 和BinanceContext一样，一个进程共用一个，账户信息在调用的时候传
+ */
+#[derive(Debug, Clone)]
+pub struct OkxContext {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub retry_policy: RetryPolicy,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OkxAPI {
+    BalanceAPI,
+    PositionsAPI,
+    InstrumentsAPI,
+}
+
+impl OkxAPI {
+    pub fn path(&self) -> &'static str {
+        match self {
+            OkxAPI::BalanceAPI => "/api/v5/account/balance",
+            OkxAPI::PositionsAPI => "/api/v5/account/positions",
+            OkxAPI::InstrumentsAPI => "/api/v5/public/instruments",
+        }
+    }
+
+    /** 公开接口不用签名
+     */
+    pub fn is_private(&self) -> bool {
+        !matches!(self, OkxAPI::InstrumentsAPI)
+    }
+}
+
+/** OKX所有的返回都包在{code,msg,data}里面，code是"0"才是成功
+ */
+#[derive(Debug, Deserialize)]
+pub struct OkxResponse<T> {
+    pub code: String,
+    pub msg: String,
+    #[serde(default = "Vec::new")]
+    pub data: Vec<T>,
+}
+
+/** 查询参数，instType是SPOT，MARGIN，SWAP，FUTURES，OPTION
+ */
+#[derive(Debug, Clone)]
+pub struct InstTypeQuery {
+    pub inst_type: String,
+}
+
+impl std::fmt::Display for InstTypeQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "instType={}", self.inst_type)
+    }
+}
+
+/** 统一账户的资产，金额都是美元
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct OkxBalance {
+    #[serde(rename = "totalEq", deserialize_with = "empty_as_zero")]
+    pub total_eq: Decimal, //总权益
+
+    #[serde(rename = "adjEq", deserialize_with = "empty_as_zero")]
+    pub adj_eq: Decimal, //有效保证金，跨币种和组合保证金模式才有
+
+    #[serde(deserialize_with = "empty_as_zero", default)]
+    pub upl: Decimal, //账户层面的未实现盈亏
+
+    #[serde(rename = "uTime", deserialize_with = "str_to_timestamp")]
+    pub update_time: UnixTimeStamp,
+
+    pub details: Vec<OkxBalanceDetail>,
+}
+
+/** 每个币种的资产，eq是币的数量，eqUsd是折算的美元。借币的话eq是负数
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct OkxBalanceDetail {
+    pub ccy: String,
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub eq: Decimal,
+
+    #[serde(rename = "eqUsd", deserialize_with = "empty_as_zero")]
+    pub eq_usd: Decimal,
+
+    #[serde(rename = "cashBal", deserialize_with = "empty_as_zero")]
+    pub cash_bal: Decimal,
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub liab: Decimal, //负债，正数
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub upl: Decimal,
+}
+
+/** 持仓。pos是张数，单向持仓的时候空头是负数，双向持仓的时候用posSide区分
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct OkxPosition {
+    #[serde(rename = "instType")]
+    pub inst_type: String,
+
+    #[serde(rename = "instId")]
+    pub inst_id: String,
+
+    #[serde(rename = "posSide")]
+    pub pos_side: String, //net，long，short
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub pos: Decimal,
+
+    #[serde(rename = "avgPx", deserialize_with = "empty_as_zero")]
+    pub avg_px: Decimal,
+
+    #[serde(rename = "markPx", deserialize_with = "empty_as_zero")]
+    pub mark_px: Decimal,
+
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub upl: Decimal, //未实现盈亏，单位是保证金币种
+
+    #[serde(rename = "notionalUsd", deserialize_with = "empty_as_zero")]
+    pub notional_usd: Decimal, //名义价值，都是正数
+}
+
+impl OkxPosition {
+    /** 多头是1，空头是-1
+     */
+    pub fn direction(&self) -> Decimal {
+        match self.pos_side.as_str() {
+            "long" => dec!(1),
+            "short" => dec!(-1),
+            _ if self.pos < dec!(0) => dec!(-1),
+            _ => dec!(1),
+        }
+    }
+}
+
+/** 合约的信息，一张合约等于ctVal个ctValCcy
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct OkxInstrument {
+    #[serde(rename = "instId")]
+    pub inst_id: String,
+
+    #[serde(rename = "settleCcy")]
+    pub settle_ccy: String,
+
+    #[serde(rename = "ctVal", deserialize_with = "empty_as_zero")]
+    pub ct_val: Decimal,
+
+    #[serde(rename = "ctType")]
+    pub ct_type: String, //linear是U本位，inverse是币本位
+}
+
+impl OkxInstrument {
+    pub fn is_linear(&self) -> bool {
+        self.ct_type == "linear"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OkxRawAccountData {
+    pub balance: OkxBalance,
+    pub positions: Vec<OkxPosition>,
+    pub instruments: Vec<OkxInstrument>,
+}
+
+fn str_to_timestamp<'de, D>(deserializer: D) -> Result<UnixTimeStamp, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    s.parse::<UnixTimeStamp>().map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,133 @@
+use crate::errors::BraavosError;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/** 失败的请求能不能重试。只有确定安全的错误才会重试：
+* 1. 请求肯定没有被处理的(连不上，时间戳错误)，什么请求都可以重试
+* 2. 结果未知的(超时，5xx)，只有幂等的请求才重试。下单不是幂等的，币安只在订单还挂着的时候
+*    拒绝重复的clientOrderId，已经成交的市价单或者IOC重复提交会再下一次。下单的结果未知时先按clientOrderId查单，
+*    见PMOrderExecutor
+* 3. 限频，业务错误，解析错误都不重试
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryKind {
+    NotProcessed,
+    OutcomeUnknown,
+    Fatal,
+}
+
+impl RetryKind {
+    pub fn retryable(&self, idempotent: bool) -> bool {
+        match self {
+            RetryKind::NotProcessed => true,
+            RetryKind::OutcomeUnknown => idempotent,
+            RetryKind::Fatal => false,
+        }
+    }
+
+    /** 连接和HTTP状态码这些和交易所无关的错误。交易所返回的错误码不认识的话，按照HTTP状态码算
+     */
+    pub fn of_common(error: &BraavosError) -> RetryKind {
+        match error {
+            BraavosError::Transport(e) if e.is_connect() => RetryKind::NotProcessed,
+            BraavosError::Transport(_) => RetryKind::OutcomeUnknown,
+            BraavosError::HttpStatus { status, .. } => RetryKind::of_status(*status),
+            _ => RetryKind::Fatal,
+        }
+    }
+
+    pub fn of_status(status: u16) -> RetryKind {
+        if status >= 500 { RetryKind::OutcomeUnknown } else { RetryKind::Fatal }
+    }
+}
+
+/** 每个交易所按照自己的错误码分类，一般在各自的context上面实现
+ */
+pub trait RetryClassifier {
+    fn retry_kind(&self, error: &BraavosError) -> RetryKind;
+}
+
+/** 重试的策略。指数退避加随机抖动，避免很多账户同时失败之后又同时重试。
+* 哪些错误可以重试见RetryKind
+*/
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, //包括第一次请求
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /** attempt是已经失败的次数，从1开始
+     */
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.base_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = exp.min(self.max_delay).as_millis() as u64;
+        let half = delay / 2;
+        Duration::from_millis(half + random_u64() % (delay - half + 1))
+    }
+}
+
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(crate::utils::unix_time());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+        for _ in 0..20 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100), "{:?}", first);
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400), "{:?}", third);
+            let tenth = policy.backoff(10);
+            assert!(tenth >= Duration::from_millis(500) && tenth <= Duration::from_millis(1000), "不能超过max_delay:{:?}", tenth);
+        }
+    }
+
+    #[test]
+    fn test_common_retry_kind() {
+        let bad_gateway = RetryKind::of_common(&BraavosError::HttpStatus { status: 502, body: String::new() });
+        assert!(bad_gateway.retryable(true));
+        assert!(!bad_gateway.retryable(false), "不幂等的请求5xx不知道有没有成功，不能重试");
+
+        let banned = RetryKind::of_common(&BraavosError::RateLimited { status: 418, retry_after: None, body: String::new() });
+        assert!(!banned.retryable(true), "限频不重试");
+        assert_eq!(RetryKind::Fatal, RetryKind::of_status(400));
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_not_processed() {
+        let error = reqwest::Client::new().get("http://127.0.0.1:1/").send().await.unwrap_err();
+        assert!(error.is_connect());
+        assert_eq!(RetryKind::NotProcessed, RetryKind::of_common(&BraavosError::from(error)), "连不上的请求肯定没有发出去");
+    }
+}
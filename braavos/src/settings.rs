@@ -1,3 +1,6 @@
+use crate::exchange::{AccountType, Exchange};
+use crate::models::UnixTimeStamp;
+use crate::valuation::ValuationPolicy;
 use config::{Config, ConfigError, File};
 use log::info;
 use serde::Deserialize;
@@ -13,6 +16,20 @@ pub struct Account {
     pub funding_rate_arbitrage: Option<Vec<String>>,
     #[serde(default)]
     pub burning_free: bool, //是否燃烧降低手续费
+    #[serde(default = "default_recv_window")]
+    pub recv_window: u16, //签名请求的recvWindow，单位毫秒
+    #[serde(default)]
+    pub funding_since: Option<UnixTimeStamp>, //资金费从什么时候开始累计，毫秒时间戳，不配置就是最近7天
+    #[serde(default)]
+    pub exchange: Exchange, //哪个交易所，不配置就是binance
+    #[serde(default)]
+    pub passphrase: Option<String>, //OKX创建API的时候设置的passphrase，币安不用
+    #[serde(default)]
+    pub account_type: AccountType, //币安的账户类型，portfolio，spot，usdm，coinm，不配置是统一账户
+}
+
+fn default_recv_window() -> u16 {
+    5000
 }
 
 pub static BRAAVOS_SETTING: LazyLock<Settings> = LazyLock::new(|| {
@@ -33,7 +50,9 @@ fn init_setting() -> Settings {
 #[allow(unused)]
 pub struct Settings {
     pub proxy: Option<String>,
+    pub endpoint: Option<String>, //production，testnet或者自定义的地址，不配置就是production
     pub accounts: Vec<Account>,
+    pub valuation: ValuationPolicy, //余额怎么折算成USDT，不配置用默认的
 }
 
 impl Settings {
@@ -44,14 +63,23 @@ impl Settings {
             .build()?;
 
         let proxy = s.get_string("proxy").map(Some).unwrap_or(None);
+        let endpoint = s.get_string("endpoint").map(Some).unwrap_or(None);
 
         // 获取所有 person 配置信息
         let accounts: Vec<Account> = s.get("account").unwrap();
 
+        let valuation = match s.get::<ValuationPolicy>("valuation") {
+            Ok(valuation) => valuation,
+            Err(ConfigError::NotFound(_)) => ValuationPolicy::default(),
+            Err(err) => return Err(err),
+        };
+
 
         Ok(Settings {
             proxy,
+            endpoint,
             accounts,
+            valuation,
         })
     }
 
@@ -60,6 +88,24 @@ impl Settings {
     }
 }
 
+/** 单元测试用的币安统一账户，别的交易所或者字段不一样的用..test_account()改
+ */
+#[cfg(test)]
+pub(crate) fn test_account() -> Account {
+    Account {
+        name: "test".to_string(),
+        api_key: "key".to_string(),
+        secret: "secret".to_string(),
+        funding_rate_arbitrage: None,
+        burning_free: false,
+        recv_window: 5000,
+        funding_since: None,
+        exchange: Exchange::Binance,
+        passphrase: None,
+        account_type: Default::default(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,20 +113,40 @@ mod tests {
     #[test]
     fn test_load_setting() {
         let setting = Settings::new("tests/Settings.toml").unwrap();
-        assert_eq!(setting.accounts.len(), 2);
+        assert_eq!(setting.accounts.len(), 4);
 
-        let actual = setting.accounts.get(0).unwrap();
+        let actual = setting.accounts.first().unwrap();
         assert_eq!(actual.name, "abc");
         assert_eq!(actual.api_key, "189rjfadoisfj8923fjio");
         assert_eq!(actual.secret, "bfsabfsbsfbsfbsfa31bw");
-        assert_eq!(actual.burning_free, true);
+        assert!(actual.burning_free);
         let coins = &actual.funding_rate_arbitrage;
         match coins {
-            None => { assert!(false, "数组为空"); }
+            None => { panic!("数组为空"); }
             Some(v) => { assert_eq!(v.len(), 3, "载入数量不对"); }
         }
 
 
+        assert_eq!(actual.recv_window, 10000);
+        assert_eq!(setting.accounts[1].recv_window, 5000, "默认的recvWindow");
+        assert_eq!(actual.funding_since, Some(1722470400000));
+        assert_eq!(setting.accounts[1].funding_since, None);
+        assert_eq!(actual.exchange, Exchange::Binance);
+        assert_eq!(setting.accounts[1].exchange, Exchange::Binance, "默认是binance");
+        assert_eq!(actual.passphrase, None);
+        assert_eq!(setting.accounts[2].exchange, Exchange::Okx);
+        assert_eq!(setting.accounts[2].passphrase, Some(String::from("okx-passphrase")));
+        assert_eq!(actual.account_type, AccountType::Portfolio, "默认是统一账户");
+        assert_eq!(setting.accounts[3].account_type, AccountType::UsdM);
+
         assert_eq!(setting.proxy, Some(String::from("http://localhost:7890")));
+        assert_eq!(setting.endpoint, Some(String::from("production")));
+
+        let valuation = &setting.valuation;
+        assert_eq!(valuation.dust_threshold, rust_decimal_macros::dec!(1));
+        assert_eq!(valuation.bridges, vec!["BTC", "BNB"]);
+        assert_eq!(valuation.pegs.get("USDC"), Some(&rust_decimal_macros::dec!(1)));
+        assert_eq!(valuation.exclude, vec!["LDBTC"]);
+        assert!(valuation.include.is_empty(), "没配置的用默认值");
     }
 }
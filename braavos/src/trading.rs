@@ -0,0 +1,16 @@
+use crate::errors::BraavosError;
+use crate::models::{Instrument, OrderInfo, OrderRequest};
+use async_trait::async_trait;
+
+/** 下单和撤单，每个实现对应一个账户
+ */
+#[async_trait]
+pub trait OrderExecutor: Send + Sync {
+    async fn place_order(&self, request: &OrderRequest) -> Result<OrderInfo, BraavosError>;
+
+    async fn cancel_order(&self, instrument: &Instrument, order_id: &str) -> Result<OrderInfo, BraavosError>;
+
+    /** instrument是None的话查所有品种的挂单
+     */
+    async fn open_orders(&self, instrument: Option<&Instrument>) -> Result<Vec<OrderInfo>, BraavosError>;
+}
@@ -1,4 +1,7 @@
-use crate::models::UnixTimeStamp;
+use crate::models::{Decimal, UnixTimeStamp};
+use crate::settings::BRAAVOS_SETTING;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use hmac::digest::InvalidLength;
 use hmac::{Hmac, Mac};
 use log::LevelFilter;
@@ -27,6 +30,19 @@ where
     s.parse::<u16>().map_err(serde::de::Error::custom)
 }
 
+/** OKX和Bybit没有值的时候返回空字符串，当成0
+ */
+pub(crate) fn empty_as_zero<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    if s.is_empty() {
+        return Ok(Decimal::ZERO);
+    }
+    s.parse::<Decimal>().map_err(serde::de::Error::custom)
+}
+
 
 // 签名方法从官方项目copy https://github.com/binance/binance-spot-connector-rust/blob/main/src/utils.rs#L9
 pub(crate) fn sign_hmac(payload: &str, key: &str) -> Result<String, InvalidLength> {
@@ -37,6 +53,27 @@ pub(crate) fn sign_hmac(payload: &str, key: &str) -> Result<String, InvalidLengt
     Ok(format!("{:x}", result.into_bytes()))
 }
 
+/** OKX的签名是base64，不是16进制
+ */
+pub(crate) fn sign_hmac_base64(payload: &str, key: &str) -> Result<String, InvalidLength> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())?;
+
+    mac.update(payload.as_bytes());
+    Ok(STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+/** 所有交易所共用的http client，配置了代理就走代理
+ */
+pub(crate) fn init_client() -> reqwest::Client {
+    let builder = reqwest::Client::builder();
+    let proxy_builder = match &BRAAVOS_SETTING.proxy {
+        Some(val) => { builder.proxy(reqwest::Proxy::https(val).unwrap()) }
+        None => { builder }
+    };
+
+    proxy_builder.build().unwrap()
+}
+
 
 pub fn setup_logger(level: Option<LevelFilter>) -> Result<(), fern::InitError> {
     let filter = match level {
@@ -64,9 +101,19 @@ pub struct SnowyFlakeWrapper {
     sf: Mutex<Sonyflake>,
 }
 
+impl Default for SnowyFlakeWrapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SnowyFlakeWrapper {
+    /** 默认用内网IP做machine id，没有内网IP的机器(比如直接绑公网IP的)用进程号代替
+     */
     pub fn new() -> SnowyFlakeWrapper {
-        let sf = Sonyflake::new().unwrap();
+        let sf = Sonyflake::new()
+            .or_else(|_| Sonyflake::builder().machine_id(&|| Ok(std::process::id() as u16)).finalize())
+            .unwrap();
         SnowyFlakeWrapper {
             sf: Mutex::new(sf),
         }
@@ -0,0 +1,163 @@
+use crate::models::Decimal;
+use log::warn;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+
+/** 计价货币，不能配置。U本位合约的名义价值和未实现盈亏，usdt_equity，借币的borrowed_usdt这些都是按USDT算的，
+* 换成别的计价货币的话汇总里面会混着两种单位
+*/
+pub const QUOTE: &str = "USDT";
+
+/** 资产怎么折算成USDT，配置在Settings的[valuation]下面，不配置的话和原来一样：
+* 只用{asset}USDT的现货价格，价值小于5U的当成0
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ValuationPolicy {
+    pub dust_threshold: Decimal,          //小于这个价值的余额当成0
+    pub bridges: Vec<String>,             //没有直接的交易对的时候，按顺序试X->bridge->USDT
+    #[serde(deserialize_with = "uppercase_keys")]
+    pub pegs: HashMap<String, Decimal>,   //稳定币直接用固定的价格，比如USDC = 1
+    pub include: Vec<String>,             //不是空的话只算这些币
+    pub exclude: Vec<String>,             //这些币不算
+    pub mark_price: bool,                 //有合约标记价格的币优先用标记价格，和交易所保证金的算法一致
+}
+
+impl Default for ValuationPolicy {
+    fn default() -> Self {
+        ValuationPolicy {
+            dust_threshold: dec!(5),
+            bridges: vec![],
+            pegs: HashMap::new(),
+            include: vec![],
+            exclude: vec![],
+            mark_price: false,
+        }
+    }
+}
+
+impl ValuationPolicy {
+    pub fn is_quote(&self, asset: &str) -> bool {
+        QUOTE == asset
+    }
+
+    /** 计价货币一直都算，其他的先看include再看exclude
+     */
+    pub fn includes(&self, asset: &str) -> bool {
+        if self.is_quote(asset) {
+            return true;
+        }
+        let included = self.include.is_empty() || self.include.iter().any(|a| a == asset);
+        included && !self.exclude.iter().any(|a| a == asset)
+    }
+
+    pub fn is_dust(&self, value: Decimal) -> bool {
+        value.abs() < self.dust_threshold
+    }
+
+    /** 给资产估值可能用到的交易对，只查这些交易对的价格就够了
+     */
+    pub fn pairs_for(&self, asset: &str) -> Vec<String> {
+        let needs_pair = |asset: &str| !self.is_quote(asset) && !self.pegs.contains_key(asset);
+        if !needs_pair(asset) {
+            return vec![];
+        }
+        let mut pairs = vec![format!("{}{}", asset, QUOTE)];
+        for bridge in self.bridges.iter().filter(|bridge| bridge.as_str() != asset) {
+            pairs.push(format!("{}{}", asset, bridge));
+            if needs_pair(bridge) {
+                pairs.push(format!("{}{}", bridge, QUOTE));
+            }
+        }
+        pairs
+    }
+
+    /** 资产用计价货币表示的价格。pair_price是交易所的交易对价格，比如BTCUSDT。
+    * 顺序是：计价货币本身，固定价格，直接的交易对，通过bridges中转。都没有的话返回None
+    */
+    pub fn price<F>(&self, asset: &str, pair_price: F) -> Option<Decimal>
+    where
+        F: Fn(&str) -> Option<Decimal>,
+    {
+        let direct = |asset: &str| -> Option<Decimal> {
+            if self.is_quote(asset) {
+                return Some(dec!(1));
+            }
+            self.pegs.get(asset).copied().or_else(|| pair_price(&format!("{}{}", asset, QUOTE)))
+        };
+
+        let price = direct(asset).or_else(|| {
+            self.bridges.iter()
+                .filter(|bridge| bridge.as_str() != asset)
+                .find_map(|bridge| {
+                    let to_bridge = pair_price(&format!("{}{}", asset, bridge))?;
+                    Some(to_bridge * direct(bridge)?)
+                })
+        });
+        if price.is_none() {
+            warn!("can't value {} in {}", asset, QUOTE);
+        }
+        price
+    }
+}
+
+/** config读出来的key都变成了小写，币种统一用大写
+ */
+fn uppercase_keys<'de, D>(deserializer: D) -> Result<HashMap<String, Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pegs: HashMap<String, Decimal> = Deserialize::deserialize(deserializer)?;
+    Ok(pegs.into_iter().map(|(asset, price)| (asset.to_uppercase(), price)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prices(pair: &str) -> Option<Decimal> {
+        match pair {
+            "BTCUSDT" => Some(dec!(60000)),
+            "ETHUSDT" => Some(dec!(2600)),
+            "XYZBTC" => Some(dec!(0.0001)),
+            "USDCUSDT" => Some(dec!(0.9998)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_price_routing() {
+        let mut policy = ValuationPolicy::default();
+        assert_eq!(Some(dec!(1)), policy.price("USDT", prices));
+        assert_eq!(Some(dec!(2600)), policy.price("ETH", prices));
+        assert_eq!(Some(dec!(0.9998)), policy.price("USDC", prices));
+        assert_eq!(None, policy.price("XYZ", prices), "默认没有中转");
+
+        policy.bridges = vec![String::from("ETH"), String::from("BTC")];
+        policy.pegs.insert(String::from("USDC"), dec!(1));
+        assert_eq!(Some(dec!(6)), policy.price("XYZ", prices), "XYZ->BTC->USDT");
+        assert_eq!(Some(dec!(1)), policy.price("USDC", prices), "固定价格优先");
+
+        assert_eq!(vec!["XYZUSDT", "XYZETH", "ETHUSDT", "XYZBTC", "BTCUSDT"], policy.pairs_for("XYZ"));
+        assert!(policy.pairs_for("USDC").is_empty(), "固定价格不用查");
+        assert!(policy.pairs_for("USDT").is_empty());
+    }
+
+    #[test]
+    fn test_includes() {
+        let mut policy = ValuationPolicy {
+            exclude: vec![String::from("BNB"), String::from("USDT")],
+            ..Default::default()
+        };
+        assert!(policy.includes("BTC"));
+        assert!(!policy.includes("BNB"));
+        assert!(policy.includes("USDT"), "计价货币不能排除");
+
+        policy.include = vec![String::from("BTC")];
+        assert!(policy.includes("BTC"));
+        assert!(!policy.includes("ETH"));
+        assert!(policy.is_dust(dec!(4.99)));
+        assert!(!policy.is_dust(dec!(5)));
+    }
+}
@@ -1,28 +1,47 @@
 use crate::errors::NightWatchError;
-use log::error;
+use log::{error, warn};
 
 use crate::prometheus_gauge;
 use crate::prometheus_server::ToGauge;
-use braavos::accounts::AccountReader;
-use braavos::binance::bn_commands::{execute_ping, PMAccountReader};
-use braavos::models::{AccountSummary, Decimal, SwapPosition, SwapSummary};
+use braavos::exchange::{account_reader, market_data, Exchange};
+use braavos::models::{AccountSummary, Decimal, FundingIncome, MarginAsset, MarginSummary, SwapPosition, SwapSummary};
 use braavos::settings::{Account, BRAAVOS_SETTING};
 use prometheus::Gauge;
 use rust_decimal_macros::dec;
+use std::collections::HashSet;
+use tokio::task::JoinSet;
 
+/** 配置里面用到的交易所都ping一次
+ */
 pub(crate) async fn ping_exchange() -> Result<(), NightWatchError> {
-    execute_ping().await.expect("can't connect to binance");
-    println!("binance access success");
+    let exchanges: HashSet<Exchange> = BRAAVOS_SETTING.accounts.iter().map(|acc| acc.exchange).collect();
+    for exchange in exchanges {
+        match market_data(exchange) {
+            Ok(market) => {
+                market.ping().await.unwrap_or_else(|e| panic!("can't connect to {}: {}", exchange, e));
+                println!("{} access success", exchange);
+            }
+            Err(e) => warn!("skip ping {}: {}", exchange, e),
+        }
+    }
     Ok(())
 }
 
 
+/** 所有账户并发的查，一个账户慢或者失败不影响其他账户
+ */
 pub(crate) async fn cal_gauge_according_setting() -> Result<Vec<Gauge>, NightWatchError> {
-    let mut res = vec![];
+    let mut tasks = JoinSet::new();
     for acc in &BRAAVOS_SETTING.accounts {
-        let positions_gauge = cal_one_account_gauge(&acc).await;
+        tasks.spawn(cal_one_account_gauge(acc));
+    }
 
-        res.extend(positions_gauge)
+    let mut res = vec![];
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(positions_gauge) => res.extend(positions_gauge),
+            Err(e) => error!("account scrape task failed: {}", e),
+        }
     }
     Ok(res)
 }
@@ -48,7 +67,46 @@ impl ToGauge for SwapSummary {
         let acc_short = prometheus_gauge!(format!("{}_acc_short", strategy),self.short_balance);
         let acc_short_pnl = prometheus_gauge!(format!("{}_acc_short_pnl", strategy),self.short_pnl);
         let fra_pnl = prometheus_gauge!(format!("{}_fra_pnl", strategy),self.fra_pnl);
-        vec![acc, acc_long, acc_long_pnl, acc_short, acc_short_pnl, pnl, fra_pnl]
+        let fra_funding = prometheus_gauge!(format!("{}_fra_funding", strategy),self.fra_funding_income);
+        vec![acc, acc_long, acc_long_pnl, acc_short, acc_short_pnl, pnl, fra_pnl, fra_funding]
+    }
+}
+
+impl ToGauge for MarginSummary {
+    fn to_prometheus_gauge(&self, strategy: &str) -> Vec<Gauge> {
+        let side_name = format!("{}_margin", strategy);
+        let uni_mmr = prometheus_gauge!(side_name,self.uni_mmr,("field" => "uni_mmr"));
+        let maint_margin = prometheus_gauge!(side_name,self.account_maint_margin,("field" => "maint_margin"));
+        let liquidation_distance = prometheus_gauge!(side_name,self.liquidation_distance,("field" => "liquidation_distance"));
+        let borrowed = prometheus_gauge!(side_name,self.borrowed,("field" => "borrowed"));
+        let interest = prometheus_gauge!(side_name,self.interest,("field" => "interest"));
+        // 状态是字符串，放在label里面，值一直是1。label和上面的不一样，同一个名字注册会失败，所以单独一个名字
+        let status = prometheus_gauge!(format!("{}_margin_status", strategy),dec!(1),("status" => &self.account_status));
+        let mut res = vec![uni_mmr, maint_margin, liquidation_distance, borrowed, interest, status];
+        for a in &self.assets {
+            res.extend(a.to_prometheus_gauge(strategy));
+        }
+        res
+    }
+}
+
+impl ToGauge for MarginAsset {
+    fn to_prometheus_gauge(&self, strategy: &str) -> Vec<Gauge> {
+        let side_name = format!("{strategy}_borrow");
+        let borrowed = prometheus_gauge!(side_name,self.borrowed,("field" => "borrowed"),("asset" => &self.asset));
+        let interest = prometheus_gauge!(side_name,self.interest,("field" => "interest"),("asset" => &self.asset));
+        let borrowed_usdt = prometheus_gauge!(side_name,self.borrowed_usdt,("field" => "borrowed_usdt"),("asset" => &self.asset));
+        let interest_usdt = prometheus_gauge!(side_name,self.interest_usdt,("field" => "interest_usdt"),("asset" => &self.asset));
+        vec![borrowed, interest, borrowed_usdt, interest_usdt]
+    }
+}
+
+impl ToGauge for FundingIncome {
+    fn to_prometheus_gauge(&self, strategy: &str) -> Vec<Gauge> {
+        let side_name = format!("{strategy}_funding");
+        let funding_rate = prometheus_gauge!(side_name,self.funding_rate,("field" => "funding_rate"),("symbol" => &self.symbol));
+        let income = prometheus_gauge!(side_name,self.income,("field" => "income"),("symbol" => &self.symbol));
+        vec![funding_rate, income]
     }
 }
 
@@ -72,19 +130,14 @@ impl ToGauge for SwapPosition {
 
 
 async fn cal_one_account_gauge(account: &Account) -> Vec<Gauge> {
-    let calculator = PMAccountReader::new(account.clone());
-    match calculator.account_balance() {
-        Ok(data) => {
-            let mut res = vec![];
-            res.extend(data.to_prometheus_gauge(&account.name));
-            let um_swap = data.um_swap_summary;
-            res.extend(um_swap.to_prometheus_gauge(&account.name));
-            let um_swap_position = um_swap.positions;
-            for p in &um_swap_position {
-                res.extend(p.to_prometheus_gauge(&account.name));
-            }
-            res
-        },
+    let calculator = account_reader(account);
+    match calculator.account_balance().await {
+        Ok(data) => account_gauges(&account.name, data),
+
+        Err(e) if e.is_rate_limited() => {
+            warn!("account {} is rate limited, skip this scrape: {}", account.name, e);
+            vec![]
+        }
 
         Err(e) => {
             error!("Error getting account balance: {}", e);
@@ -93,10 +146,39 @@ async fn cal_one_account_gauge(account: &Account) -> Vec<Gauge> {
     }
 }
 
+/** 一个账户所有的指标。所有账户的指标注册在同一个Registry里面，同一个名字的label必须一样
+ */
+fn account_gauges(name: &str, data: AccountSummary) -> Vec<Gauge> {
+    let mut res = vec![];
+    res.extend(data.to_prometheus_gauge(name));
+    let um_swap = data.um_swap_summary;
+    res.extend(um_swap.to_prometheus_gauge(name));
+    let um_swap_position = um_swap.positions;
+    for p in &um_swap_position {
+        res.extend(p.to_prometheus_gauge(name));
+    }
+    for f in &um_swap.fra_funding {
+        res.extend(f.to_prometheus_gauge(name));
+    }
+    // 币本位的指标名字加上_cm，和U本位的区分开
+    let cm_strategy = format!("{}_cm", name);
+    if let Some(margin) = &data.margin_summary {
+        res.extend(margin.to_prometheus_gauge(name));
+    }
+    let cm_swap = data.cm_swap_summary;
+    res.extend(cm_swap.to_prometheus_gauge(&cm_strategy));
+    for p in &cm_swap.positions {
+        res.extend(p.to_prometheus_gauge(&cm_strategy));
+    }
+    res
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::clients::account_gauges;
     use crate::prometheus_server::ToGauge;
-    use braavos::models::{AccountSummary, SwapPosition, SwapSummary};
+    use braavos::models::{AccountSummary, FundingIncome, MarginAsset, MarginSummary, SwapPosition, SwapSummary};
+    use prometheus::Registry;
     use rust_decimal_macros::dec;
 
     #[test]
@@ -124,11 +206,48 @@ mod tests {
             balance: Default::default(),
             pnl: Default::default(),
             fra_pnl: Default::default(),
+            fra_funding_income: Default::default(),
+            fra_funding: vec![],
             positions: vec![],
         };
         let actual = swap_position.to_prometheus_gauge("test");
 
-        assert_eq!(7, actual.len());
+        assert_eq!(8, actual.len());
+    }
+
+    #[test]
+    fn test_to_funding_income_prometheus() {
+        let funding = FundingIncome {
+            symbol: "SOLUSDT".to_string(),
+            funding_rate: dec!(0.0001),
+            next_funding_time: 1723968000000,
+            income: dec!(1.5),
+        };
+        let actual = funding.to_prometheus_gauge("test");
+
+        assert_eq!(2, actual.len());
+    }
+
+    #[test]
+    fn test_to_margin_summary_prometheus() {
+        let margin = MarginSummary {
+            uni_mmr: dec!(5.25),
+            account_maint_margin: dec!(193.6),
+            account_status: "NORMAL".to_string(),
+            liquidation_distance: dec!(0.8),
+            borrowed: dec!(269.45),
+            interest: dec!(0.12),
+            assets: vec![MarginAsset {
+                asset: "BNB".to_string(),
+                borrowed: dec!(0.5),
+                interest: dec!(0),
+                borrowed_usdt: dec!(269.45),
+                interest_usdt: dec!(0),
+            }],
+        };
+        let actual = margin.to_prometheus_gauge("test");
+
+        assert_eq!(6 + 4, actual.len());
     }
 
     #[test]
@@ -146,14 +265,69 @@ mod tests {
                 balance: Default::default(),
                 pnl: Default::default(),
                 fra_pnl: Default::default(),
+                fra_funding_income: Default::default(),
+                fra_funding: vec![],
                 positions: vec![],
             },
+            cm_swap_summary: Default::default(),
+            margin_summary: None,
         };
         let actual = swap_position.to_prometheus_gauge("test");
 
         assert_eq!(4, actual.len());
     }
-}
-
 
+    #[test]
+    fn test_register_account_gauges() {
+        let position = |symbol: &str, position_amt| SwapPosition {
+            symbol: symbol.to_string(),
+            cur_price: dec!(100),
+            avg_price: dec!(90),
+            pos_u: dec!(1000),
+            pnl_u: dec!(100),
+            position_amt,
+        };
+        let data = AccountSummary {
+            usdt_equity: dec!(1000),
+            negative_balance: dec!(0),
+            account_pnl: dec!(10),
+            account_equity: dec!(2000),
+            um_swap_summary: SwapSummary {
+                fra_funding: vec![FundingIncome {
+                    symbol: "SOLUSDT".to_string(),
+                    funding_rate: dec!(0.0001),
+                    next_funding_time: 1723968000000,
+                    income: dec!(1.5),
+                }],
+                positions: vec![position("SOLUSDT", dec!(-10)), position("BTCUSDT", dec!(0.1))],
+                ..Default::default()
+            },
+            cm_swap_summary: SwapSummary {
+                positions: vec![position("BTCUSD_PERP", dec!(1))],
+                ..Default::default()
+            },
+            margin_summary: Some(MarginSummary {
+                uni_mmr: dec!(5.25),
+                account_maint_margin: dec!(193.6),
+                account_status: "NORMAL".to_string(),
+                liquidation_distance: dec!(0.8),
+                borrowed: dec!(269.45),
+                interest: dec!(0.12),
+                assets: vec![MarginAsset {
+                    asset: "BNB".to_string(),
+                    borrowed: dec!(0.5),
+                    interest: dec!(0),
+                    borrowed_usdt: dec!(269.45),
+                    interest_usdt: dec!(0),
+                }],
+            }),
+        };
 
+        // 和PrometheusServer::print_metric一样注册，名字一样label不一样的话会失败
+        let registry = Registry::new();
+        for g in account_gauges("test", data) {
+            registry.register(Box::new(g)).unwrap();
+        }
+        assert!(registry.gather().iter().any(|f| f.get_name() == "test_margin_status"));
+    }
+}